@@ -1,27 +1,32 @@
-use anyhow::{Context, Result};
-use std::fs;
-use std::path::Path;
-use wal::wal::{DEFAULT_WAL_FILE_PREFIX, DEFAULT_WAL_FOLDER};
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+use wal::lanes::MultiLaneWal;
+use wal::wal::{DEFAULT_WAL_FILE_PREFIX, WriteAheadLog};
 
 pub enum CheckPoint {
     SequenceWindowMemtableFlushed,
 }
 
-struct CheckPointManager {}
+/// Releases the WAL segment of a sequence window once the memtable holding it is flushed.
+pub struct CheckPointManager {
+    wal: Arc<WriteAheadLog>,
+}
 
 impl CheckPointManager {
-    fn sequence_window_memtable_flushed(seq_beginning: u64, seq_end: u64) -> anyhow::Result<()> {
+    pub fn new(wal: Arc<WriteAheadLog>) -> Self {
+        CheckPointManager { wal }
+    }
+
+    pub fn sequence_window_memtable_flushed(&self, seq_beginning: u64, seq_end: u64) -> Result<()> {
         let filename = format!(
             "{}-{:020}-{:020}.log",
             DEFAULT_WAL_FILE_PREFIX, seq_beginning, seq_end
         );
-        let wal_path = Path::new(DEFAULT_WAL_FOLDER).join(&filename);
 
-        if wal_path.exists() {
-            fs::remove_file(&wal_path)
-                .context(format!("Failed to delete WAL file: {:?}", wal_path))?;
+        // the segment is recycled when the wal keeps a recycle pool and deleted otherwise
+        if self.wal.release_wal_file_by_seq(seq_beginning, seq_end)? {
             println!(
-                "Deleted WAL file for sequence range [{}, {}]: {}",
+                "Released WAL file for sequence range [{}, {}]: {}",
                 seq_beginning, seq_end, filename
             );
         } else {
@@ -34,3 +39,57 @@ impl CheckPointManager {
         Ok(())
     }
 }
+
+/// Where one lane of a multi-lane WAL stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaneCheckPoint {
+    pub lane: usize,
+    /// highest sequence number written and synced to the lane
+    pub durable_seq: Option<u64>,
+    /// highest sequence number whose segment was released after its memtable was flushed
+    pub flushed_seq: Option<u64>,
+}
+
+/// Releases flushed segments of a multi-lane WAL and tracks per lane how far it is durable and
+/// flushed.
+pub struct LaneCheckPointManager {
+    wal: Arc<MultiLaneWal>,
+    flushed: Mutex<Vec<Option<u64>>>,
+}
+
+impl LaneCheckPointManager {
+    pub fn new(wal: Arc<MultiLaneWal>) -> Self {
+        let flushed = Mutex::new(vec![None; wal.lanes()]);
+        LaneCheckPointManager { wal, flushed }
+    }
+
+    pub fn sequence_window_memtable_flushed(&self, seq_beginning: u64, seq_end: u64) -> Result<()> {
+        match self.wal.release_wal_file_by_seq(seq_beginning, seq_end)? {
+            Some(lane) => {
+                let mut flushed = self.flushed.lock().unwrap();
+                flushed[lane] = Some(flushed[lane].map_or(seq_end, |seq| seq.max(seq_end)));
+                println!(
+                    "Released WAL file for sequence range [{}, {}] on lane {}",
+                    seq_beginning, seq_end, lane
+                );
+            }
+            None => println!(
+                "WAL file not found on any lane for sequence range [{}, {}]",
+                seq_beginning, seq_end
+            ),
+        }
+
+        Ok(())
+    }
+
+    pub fn lane_checkpoints(&self) -> Vec<LaneCheckPoint> {
+        let flushed = self.flushed.lock().unwrap();
+        (0..self.wal.lanes())
+            .map(|lane| LaneCheckPoint {
+                lane,
+                durable_seq: self.wal.durable_seq(lane),
+                flushed_seq: flushed[lane],
+            })
+            .collect()
+    }
+}
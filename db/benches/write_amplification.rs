@@ -0,0 +1,134 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use db::compaction::{CompactionJob, CompactionOptions, CompactionStyle, new_picker};
+use db::filename;
+use db::universal::UniversalOptions;
+use db::version_edit::{TableMeta, VersionEdit};
+use db::version_set::VersionSet;
+use memtable::key::{InternalKey, ValueType};
+use sstable::builder::TableBuilder;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use transaction::{Instruction, Signer, Transaction};
+
+const FLUSHES: u64 = 128;
+const FLUSH_SIZE: u64 = 16;
+// share of writes that overwrite an earlier key instead of adding a new one
+const OVERWRITE_PERCENT: u64 = 20;
+
+#[derive(Debug, Default)]
+struct Amplification {
+    flushed: u64,
+    compacted: u64,
+}
+
+// transaction ingest: transactions keyed by hash, so spread over the whole key space, with some
+// of them rewritten later
+fn workload() -> Vec<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut keys = Vec::new();
+    (0..FLUSHES)
+        .map(|_| {
+            (0..FLUSH_SIZE)
+                .map(|_| {
+                    let key = if !keys.is_empty() && random() % 100 < OVERWRITE_PERCENT {
+                        keys[random() as usize % keys.len()]
+                    } else {
+                        keys.push(random());
+                        keys[keys.len() - 1]
+                    };
+                    let mut itx = Instruction {
+                        contract: [key as u8; 32],
+                        ..Default::default()
+                    };
+                    itx.data[..64].copy_from_slice(&[0xab; 64]);
+                    let mut itxs = [Instruction::default(); 5];
+                    itxs[0] = itx;
+                    let tx = Transaction::new_with_timestamp(Signer::new([7; 32]), itxs, key);
+                    (key.to_be_bytes().to_vec(), tx.to_bytes().unwrap())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// writes each batch as a level 0 table the way a flush would, compacting whatever becomes due
+fn run(options: &CompactionOptions, batches: &[Vec<(Vec<u8>, Vec<u8>)>]) -> Amplification {
+    let dir = tempfile::tempdir().unwrap();
+    let versions = Arc::new(VersionSet::open(dir.path()).unwrap());
+    let picker = new_picker(options.clone(), dir.path());
+    let job = CompactionJob::new(options.clone(), versions.clone());
+    let mut amplification = Amplification::default();
+    let mut seq = 0;
+    for batch in batches {
+        let mut memtable = BTreeMap::new();
+        for (key, value) in batch {
+            seq += 1;
+            memtable.insert(key.clone(), (seq, value.clone()));
+        }
+        let file_number = versions.new_file_number();
+        let path = filename::table_file_name(versions.folder(), file_number);
+        let mut builder = TableBuilder::create(&path, options.table.clone()).unwrap();
+        for (key, (seq, value)) in &memtable {
+            let key = InternalKey::new(key.clone(), *seq, ValueType::Value);
+            builder.add(&key, value).unwrap();
+        }
+        let info = builder.finish().unwrap();
+        amplification.flushed += info.file_size;
+        let mut edit = VersionEdit::default();
+        edit.add_file(TableMeta {
+            file_number,
+            level: 0,
+            file_size: info.file_size,
+            smallest: info.smallest.unwrap(),
+            largest: info.largest.unwrap(),
+            smallest_seq: info.properties.smallest_seq,
+            largest_seq: info.properties.largest_seq,
+        });
+        versions.log_and_apply(edit).unwrap();
+
+        while let Some(compaction) = picker.pick(&versions.current()) {
+            amplification.compacted += job.run(&compaction, &[]).unwrap().bytes_written;
+        }
+    }
+    amplification
+}
+
+fn bench_write_amplification(c: &mut Criterion) {
+    let batches = workload();
+    let leveled = CompactionOptions {
+        max_bytes_for_level_base: 512 * 1024,
+        target_file_size: 128 * 1024,
+        ..Default::default()
+    };
+    // universal keeps more sorted runs around in exchange for rewriting less, with only 4 of
+    // them most flushes would be merged straight into the newest run
+    let universal = CompactionOptions {
+        level0_file_num_compaction_trigger: 8,
+        style: CompactionStyle::Universal(UniversalOptions::default()),
+        ..leveled.clone()
+    };
+    let mut group = c.benchmark_group("write_amplification");
+    group.sample_size(10);
+    for (name, options) in [("leveled", leveled), ("universal", universal)] {
+        let amplification = run(&options, &batches);
+        println!(
+            "{}: {} bytes flushed, {} bytes compacted, write amplification {:.2}",
+            name,
+            amplification.flushed,
+            amplification.compacted,
+            (amplification.flushed + amplification.compacted) as f64
+                / amplification.flushed as f64
+        );
+        group.bench_function(name, |b| b.iter(|| run(&options, &batches)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_write_amplification);
+criterion_main!(benches);
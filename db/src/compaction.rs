@@ -0,0 +1,639 @@
+use anyhow::{Context, Result};
+use memtable::key::{self, InternalKey, ValueType};
+use sstable::builder::{TableBuilder, TableOptions};
+use sstable::table::{Table, TableIterator};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use wal::backpressure::WriteController;
+
+use crate::compaction_filter::{CompactionDecision, CompactionFilter};
+use crate::fifo::{FifoOptions, FifoPicker};
+use crate::filename;
+use crate::leveled::LeveledPicker;
+use crate::universal::{UniversalOptions, UniversalPicker};
+use crate::version_edit::{TableMeta, VersionEdit};
+use crate::version_set::{NUM_LEVELS, Version, VersionSet};
+
+const DEFAULT_LEVEL0_FILE_NUM_COMPACTION_TRIGGER: usize = 4;
+const DEFAULT_MAX_BYTES_FOR_LEVEL_BASE: u64 = 256 * 1024 * 1024;
+const DEFAULT_MAX_BYTES_FOR_LEVEL_MULTIPLIER: f64 = 10.0;
+const DEFAULT_TARGET_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct CompactionOptions {
+    /// number of level 0 tables that makes level 0 due for compaction
+    pub level0_file_num_compaction_trigger: usize,
+    /// target size of level 1
+    pub max_bytes_for_level_base: u64,
+    /// every level past 1 targets this many times the size of the level above
+    pub max_bytes_for_level_multiplier: f64,
+    /// compaction output is split into tables of about this size
+    pub target_file_size: u64,
+    /// options of the tables compaction writes
+    pub table: TableOptions,
+    pub style: CompactionStyle,
+    /// consulted about every value compaction rewrites, `None` keeps them all
+    pub filter: Option<Arc<dyn CompactionFilter>>,
+}
+
+/// How the tables of a database are arranged, and so which picker decides what to compact.
+#[derive(Debug, Clone, Default)]
+pub enum CompactionStyle {
+    /// every level past 0 is one sorted run with a size target, see `LeveledPicker`
+    #[default]
+    Leveled,
+    /// sorted runs of similar size are merged together, see `UniversalPicker`
+    Universal(UniversalOptions),
+    /// level 0 tables are never merged, the oldest ones are dropped, see `FifoPicker`
+    Fifo(FifoOptions),
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        CompactionOptions {
+            level0_file_num_compaction_trigger: DEFAULT_LEVEL0_FILE_NUM_COMPACTION_TRIGGER,
+            max_bytes_for_level_base: DEFAULT_MAX_BYTES_FOR_LEVEL_BASE,
+            max_bytes_for_level_multiplier: DEFAULT_MAX_BYTES_FOR_LEVEL_MULTIPLIER,
+            target_file_size: DEFAULT_TARGET_FILE_SIZE,
+            table: TableOptions::default(),
+            style: CompactionStyle::default(),
+            filter: None,
+        }
+    }
+}
+
+impl CompactionOptions {
+    /// Target size of `level`, 1 and up.
+    pub fn max_bytes_for_level(&self, level: usize) -> u64 {
+        let multiplier = self
+            .max_bytes_for_level_multiplier
+            .powi(level.saturating_sub(1) as i32);
+        (self.max_bytes_for_level_base as f64 * multiplier) as u64
+    }
+}
+
+/// Decides which tables to compact next.
+pub trait CompactionPicker: Send + Sync {
+    /// The most urgent compaction of `version`, `None` when nothing is due.
+    fn pick(&self, version: &Version) -> Option<Compaction>;
+}
+
+/// The picker for the style `options` selects, for the database in `folder`.
+pub fn new_picker(options: CompactionOptions, folder: &Path) -> Box<dyn CompactionPicker> {
+    match &options.style {
+        CompactionStyle::Leveled => Box::new(LeveledPicker::new(options)),
+        CompactionStyle::Universal(universal) => {
+            Box::new(UniversalPicker::new(universal.clone(), options))
+        }
+        CompactionStyle::Fifo(fifo) => Box::new(FifoPicker::new(fifo.clone(), folder)),
+    }
+}
+
+/// Tables picked to be merged into `output_level`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compaction {
+    /// level the compaction was picked for
+    pub level: usize,
+    pub output_level: usize,
+    /// tables of `level` and the overlapping ones of `output_level`
+    pub inputs: Vec<TableMeta>,
+    /// how far past its trigger the picker found the database, at least 1
+    pub score: f64,
+    /// outputs are cut once they reach this size
+    pub target_file_size: u64,
+    /// no level below `output_level` holds keys in the input range, so tombstones have nothing
+    /// left to shadow
+    pub bottommost: bool,
+    /// the inputs are dropped whole instead of merged
+    pub deletion: bool,
+}
+
+impl Compaction {
+    /// A single table with nothing to merge with is moved by an edit instead of rewritten.
+    pub fn is_trivial_move(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].level as usize != self.output_level
+    }
+
+    /// Smallest and largest user key of the inputs.
+    pub fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        let smallest = self
+            .inputs
+            .iter()
+            .map(|t| t.smallest.user_key.as_slice())
+            .min()?;
+        let largest = self
+            .inputs
+            .iter()
+            .map(|t| t.largest.user_key.as_slice())
+            .max()?;
+        Some((smallest, largest))
+    }
+}
+
+/// Whether any level past `output_level` has tables overlapping `[smallest, largest]`.
+pub(crate) fn is_bottommost(
+    version: &Version,
+    output_level: usize,
+    smallest: &[u8],
+    largest: &[u8],
+) -> bool {
+    (output_level + 1..NUM_LEVELS).all(|level| {
+        version
+            .overlapping_files(level, smallest, largest)
+            .is_empty()
+    })
+}
+
+/// What running a compaction did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub input_files: usize,
+    pub output_files: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// entries dropped because a newer version, a tombstone or a range deletion shadowed them in
+    /// every snapshot, or because they were tombstones at the bottommost level
+    pub entries_dropped: u64,
+    pub range_deletions_dropped: u64,
+    /// decisions of the `CompactionFilter`
+    pub filter_kept: u64,
+    pub filter_removed: u64,
+    pub filter_changed: u64,
+}
+
+/// Merges the inputs of a `Compaction` into new tables of the output level and logs the swap.
+///
+/// Several versions of a key are kept as long as a snapshot can tell them apart: sequence
+/// numbers are grouped into stripes by the snapshots, and within a stripe only the newest
+/// version survives. Deletions and range deletions are only dropped at the bottommost level, and
+/// only once they are older than every snapshot.
+pub struct CompactionJob {
+    options: CompactionOptions,
+    versions: Arc<VersionSet>,
+    controller: Option<Arc<WriteController>>,
+}
+
+impl CompactionJob {
+    pub fn new(options: CompactionOptions, versions: Arc<VersionSet>) -> Self {
+        CompactionJob {
+            options,
+            versions,
+            controller: None,
+        }
+    }
+
+    /// Reports the level 0 table count to `controller` after every compaction, so writers speed
+    /// back up once L0 drains.
+    pub fn with_controller(mut self, controller: Arc<WriteController>) -> Self {
+        self.controller = Some(controller);
+        self
+    }
+
+    /// Runs `compaction`. `snapshots` are the sequence numbers of the snapshots readers hold.
+    pub fn run(&self, compaction: &Compaction, snapshots: &[u64]) -> Result<CompactionStats> {
+        let mut stats = CompactionStats {
+            input_files: compaction.inputs.len(),
+            ..Default::default()
+        };
+        let mut edit = VersionEdit::default();
+        for table in &compaction.inputs {
+            edit.delete_file(table.level, table.file_number);
+        }
+        if compaction.deletion {
+            self.log_and_apply(edit)?;
+            self.versions.delete_obsolete_files()?;
+            println!(
+                "Dropped {} tables ({} bytes) of level {}",
+                stats.input_files,
+                compaction.inputs.iter().map(|t| t.file_size).sum::<u64>(),
+                compaction.level
+            );
+            return Ok(stats);
+        }
+        if compaction.is_trivial_move() {
+            let mut table = compaction.inputs[0].clone();
+            table.level = compaction.output_level as u32;
+            edit.add_file(table);
+            self.log_and_apply(edit)?;
+            return Ok(stats);
+        }
+
+        let mut snapshots = snapshots.to_vec();
+        snapshots.sort_unstable();
+        let mut outputs = CompactionOutputs {
+            options: &self.options,
+            versions: &self.versions,
+            level: compaction.output_level as u32,
+            target_file_size: compaction.target_file_size,
+            current: None,
+            pending: Vec::new(),
+            finished: Vec::new(),
+        };
+        let merged = self.merge(compaction, &snapshots, &mut outputs, &mut stats);
+        let tables = match merged.and_then(|range_deletions| outputs.finish(&range_deletions)) {
+            Ok(tables) => tables,
+            Err(e) => {
+                outputs.abandon();
+                return Err(e);
+            }
+        };
+        stats.bytes_read = compaction.inputs.iter().map(|t| t.file_size).sum();
+        stats.output_files = tables.len();
+        stats.bytes_written = tables.iter().map(|t| t.file_size).sum();
+        for table in tables {
+            edit.add_file(table);
+        }
+        self.log_and_apply(edit)?;
+        self.versions.delete_obsolete_files()?;
+        println!(
+            "Compacted {} tables ({} bytes) of level {} into {} tables ({} bytes) of level {}",
+            stats.input_files,
+            stats.bytes_read,
+            compaction.level,
+            stats.output_files,
+            stats.bytes_written,
+            compaction.output_level
+        );
+        Ok(stats)
+    }
+
+    fn log_and_apply(&self, edit: VersionEdit) -> Result<()> {
+        let version = self.versions.log_and_apply(edit)?;
+        if let Some(controller) = &self.controller {
+            controller.set_l0_files(version.num_files(0));
+        }
+        Ok(())
+    }
+
+    /// Feeds the surviving entries to `outputs` and returns the surviving range deletions.
+    fn merge(
+        &self,
+        compaction: &Compaction,
+        snapshots: &[u64],
+        outputs: &mut CompactionOutputs,
+        stats: &mut CompactionStats,
+    ) -> Result<Vec<(InternalKey, Vec<u8>)>> {
+        let folder = self.versions.folder();
+        let tables = compaction
+            .inputs
+            .iter()
+            .map(|table| Table::open(filename::table_file_name(folder, table.file_number)))
+            .collect::<Result<Vec<_>>>()?;
+        // index of the first snapshot that sees `seq`, versions with the same stripe are only
+        // ever read together
+        let stripe = |seq: u64| snapshots.partition_point(|snapshot| *snapshot < seq);
+
+        let mut range_deletions: Vec<_> = tables
+            .iter()
+            .flat_map(|table| table.range_deletions().iter().cloned())
+            .collect();
+        range_deletions.sort();
+        range_deletions.dedup();
+
+        let mut inputs = MergingIterator::new(tables.iter().map(Table::iter).collect())?;
+        let mut current_user_key: Option<Vec<u8>> = None;
+        let mut last_stripe = None;
+        while let Some((mut key, mut value)) = inputs.next()? {
+            if current_user_key.as_deref() != Some(key.user_key.as_slice()) {
+                current_user_key = Some(key.user_key.clone());
+                last_stripe = None;
+            }
+            let key_stripe = stripe(key.seq);
+            let shadowed = last_stripe == Some(key_stripe);
+            let range_deleted = range_deletions.iter().any(|(start, end)| {
+                start.user_key <= key.user_key
+                    && key.user_key.as_slice() < end.as_slice()
+                    && start.seq > key.seq
+                    && stripe(start.seq) == key_stripe
+            });
+            let obsolete_tombstone =
+                key.value_type == ValueType::Deletion && compaction.bottommost && key_stripe == 0;
+            last_stripe = Some(key_stripe);
+            if shadowed || range_deleted || obsolete_tombstone {
+                stats.entries_dropped += 1;
+                continue;
+            }
+            // only values no snapshot reads, newer than all of them
+            let filter =
+                self.options.filter.as_ref().filter(|_| {
+                    key.value_type == ValueType::Value && key_stripe == snapshots.len()
+                });
+            if let Some(filter) = filter {
+                match filter.filter(compaction.level, &key.user_key, &value) {
+                    CompactionDecision::Keep => stats.filter_kept += 1,
+                    CompactionDecision::Remove => {
+                        stats.filter_removed += 1;
+                        if compaction.bottommost && key_stripe == 0 {
+                            continue;
+                        }
+                        key.value_type = ValueType::Deletion;
+                        value.clear();
+                    }
+                    CompactionDecision::ChangeValue(changed) => {
+                        stats.filter_changed += 1;
+                        value = changed;
+                    }
+                }
+            }
+            outputs.add(&key, &value)?;
+        }
+
+        if compaction.bottommost {
+            let before = range_deletions.len();
+            range_deletions.retain(|(start, _)| stripe(start.seq) != 0);
+            stats.range_deletions_dropped = (before - range_deletions.len()) as u64;
+        }
+        Ok(range_deletions)
+    }
+}
+
+/// Merges table iterators into one stream in internal key order.
+pub(crate) struct MergingIterator<'a> {
+    inputs: Vec<TableIterator<'a>>,
+    last_key: Option<Vec<u8>>,
+}
+
+impl<'a> MergingIterator<'a> {
+    pub(crate) fn new(mut inputs: Vec<TableIterator<'a>>) -> Result<Self> {
+        for input in &mut inputs {
+            input.seek_to_first()?;
+        }
+        Ok(MergingIterator {
+            inputs,
+            last_key: None,
+        })
+    }
+
+    /// Next entry across all inputs. The same entry in two inputs is only returned once.
+    #[allow(clippy::should_implement_trait)]
+    pub(crate) fn next(&mut self) -> Result<Option<(InternalKey, Vec<u8>)>> {
+        loop {
+            let smallest = (0..self.inputs.len())
+                .filter(|i| self.inputs[*i].valid())
+                .min_by(|a, b| key::compare_encoded(self.inputs[*a].key(), self.inputs[*b].key()));
+            let Some(i) = smallest else {
+                return Ok(None);
+            };
+            let input = &mut self.inputs[i];
+            let encoded = input.key().to_vec();
+            let value = input.value().to_vec();
+            input.next()?;
+            if self.last_key.as_deref() == Some(encoded.as_slice()) {
+                continue;
+            }
+            let key = InternalKey::decode(&encoded)
+                .with_context(|| format!("undecodable key {:?} in compaction input", encoded))?;
+            self.last_key = Some(encoded);
+            return Ok(Some((key, value)));
+        }
+    }
+}
+
+struct OutputTable {
+    builder: TableBuilder,
+    file_number: u64,
+    // user keys the table starts at and the next one starts at, `None` at either end
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+    last_user_key: Vec<u8>,
+}
+
+/// The tables a compaction writes, cut at about the target file size. All versions of a user key
+/// go to the same table, so tables of a level never share a user key.
+struct CompactionOutputs<'a> {
+    options: &'a CompactionOptions,
+    versions: &'a VersionSet,
+    level: u32,
+    target_file_size: u64,
+    current: Option<OutputTable>,
+    // cut tables waiting for their range deletions
+    pending: Vec<OutputTable>,
+    finished: Vec<TableMeta>,
+}
+
+impl CompactionOutputs<'_> {
+    fn add(&mut self, key: &InternalKey, value: &[u8]) -> Result<()> {
+        let cut = self.current.as_ref().is_some_and(|current| {
+            current.last_user_key != key.user_key
+                && current.builder.file_size_estimate() >= self.target_file_size
+        });
+        if cut {
+            let mut current = self.current.take().expect("checked above");
+            current.upper_bound = Some(key.user_key.clone());
+            self.pending.push(current);
+            self.open(Some(key.user_key.clone()))?;
+        } else if self.current.is_none() {
+            self.open(None)?;
+        }
+        let current = self.current.as_mut().expect("opened above");
+        current.builder.add(key, value)?;
+        current.last_user_key.clone_from(&key.user_key);
+        Ok(())
+    }
+
+    fn open(&mut self, lower_bound: Option<Vec<u8>>) -> Result<()> {
+        let file_number = self.versions.new_file_number();
+        let path = filename::table_file_name(self.versions.folder(), file_number);
+        self.current = Some(OutputTable {
+            builder: TableBuilder::create(path, self.options.table.clone())?,
+            file_number,
+            lower_bound,
+            upper_bound: None,
+            last_user_key: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Adds each range deletion to the tables whose key range it overlaps, clipped to it, and
+    /// finishes them all.
+    fn finish(&mut self, range_deletions: &[(InternalKey, Vec<u8>)]) -> Result<Vec<TableMeta>> {
+        if self.current.is_none() && self.pending.is_empty() && !range_deletions.is_empty() {
+            self.open(None)?;
+        }
+        self.pending.extend(self.current.take());
+        self.pending.reverse();
+        while let Some(table) = self.pending.pop() {
+            let mut builder = table.builder;
+            let mut clipped: Vec<_> = range_deletions
+                .iter()
+                .filter_map(|(start, end)| {
+                    let start_key = match &table.lower_bound {
+                        Some(lower) if *lower > start.user_key => lower.clone(),
+                        _ => start.user_key.clone(),
+                    };
+                    let end_key = match &table.upper_bound {
+                        Some(upper) if upper < end => upper.clone(),
+                        _ => end.clone(),
+                    };
+                    (start_key < end_key).then(|| {
+                        let start =
+                            InternalKey::new(start_key, start.seq, ValueType::RangeDeletion);
+                        (start, end_key)
+                    })
+                })
+                .collect();
+            clipped.sort();
+            let added = clipped
+                .iter()
+                .try_for_each(|(start, end)| builder.add_range_deletion(start, end));
+            if let Err(e) = added {
+                builder.abandon()?;
+                return Err(e);
+            }
+            if builder.num_entries() == 0 {
+                builder.abandon()?;
+                continue;
+            }
+            let info = builder.finish()?;
+            let (Some(smallest), Some(largest)) = (info.smallest, info.largest) else {
+                unreachable!("table with entries has bounds");
+            };
+            self.finished.push(TableMeta {
+                file_number: table.file_number,
+                level: self.level,
+                file_size: info.file_size,
+                smallest,
+                largest,
+                smallest_seq: info.properties.smallest_seq,
+                largest_seq: info.properties.largest_seq,
+            });
+        }
+        fs::File::open(self.versions.folder())?.sync_all()?;
+        Ok(std::mem::take(&mut self.finished))
+    }
+
+    /// Removes every table written so far after a failed compaction.
+    fn abandon(&mut self) {
+        let open = self
+            .current
+            .take()
+            .into_iter()
+            .chain(self.pending.drain(..));
+        for table in open {
+            if let Err(e) = table.builder.abandon() {
+                eprintln!("Warning: Failed to remove compaction output: {}", e);
+            }
+        }
+        for table in self.finished.drain(..) {
+            let path = filename::table_file_name(self.versions.folder(), table.file_number);
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!(
+                    "Warning: Failed to remove compaction output {:?}: {}",
+                    path, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use memtable::key::{InternalKey, ValueType};
+    use memtable::memtable::Lookup;
+    use std::sync::Arc;
+    use wal::backpressure::{BackpressureOptions, StallReason, WriteState};
+
+    pub(crate) type Entry<'a> = (&'a str, u64, ValueType, &'a str);
+
+    /// Writes `entries` to a new table and logs it to `level`.
+    pub(crate) fn add_table(versions: &VersionSet, level: u32, entries: &[Entry]) -> TableMeta {
+        let file_number = versions.new_file_number();
+        let path = filename::table_file_name(versions.folder(), file_number);
+        let mut builder = TableBuilder::create(&path, Default::default()).unwrap();
+        for (key, seq, value_type, value) in entries {
+            let key = InternalKey::new(*key, *seq, *value_type);
+            if key.value_type == ValueType::RangeDeletion {
+                builder.add_range_deletion(&key, value.as_bytes()).unwrap();
+            } else {
+                builder.add(&key, value.as_bytes()).unwrap();
+            }
+        }
+        let info = builder.finish().unwrap();
+        let table = TableMeta {
+            file_number,
+            level,
+            file_size: info.file_size,
+            smallest: info.smallest.unwrap(),
+            largest: info.largest.unwrap(),
+            smallest_seq: info.properties.smallest_seq,
+            largest_seq: info.properties.largest_seq,
+        };
+        let mut edit = VersionEdit::default();
+        edit.add_file(table.clone());
+        versions.log_and_apply(edit).unwrap();
+        table
+    }
+
+    #[test]
+    fn test_output_of_only_range_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = Arc::new(VersionSet::open(dir.path()).unwrap());
+        let older = add_table(
+            &versions,
+            0,
+            &[
+                ("a", 1, ValueType::Value, "a1"),
+                ("b", 2, ValueType::Value, "b2"),
+            ],
+        );
+        let newer = add_table(&versions, 0, &[("a", 10, ValueType::RangeDeletion, "c")]);
+        let job = CompactionJob::new(CompactionOptions::default(), versions.clone());
+        let compaction = Compaction {
+            level: 0,
+            output_level: 1,
+            inputs: vec![older, newer],
+            score: 1.0,
+            bottommost: false,
+            target_file_size: u64::MAX,
+            deletion: false,
+        };
+        // every value is under the range deletion, which has to stay for the levels below
+        let stats = job.run(&compaction, &[]).unwrap();
+        assert_eq!((stats.entries_dropped, stats.output_files), (2, 1));
+        let version = versions.current();
+        let table = &version.files(1)[0];
+        let reader = Table::open(filename::table_file_name(dir.path(), table.file_number)).unwrap();
+        assert_eq!(reader.properties().num_data_blocks, 0);
+        assert_eq!(reader.range_deletions().len(), 1);
+        assert_eq!(
+            reader.get(b"b", u64::MAX >> 8).unwrap(),
+            Some(Lookup::Deleted)
+        );
+    }
+
+    #[test]
+    fn test_compaction_reports_l0_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = Arc::new(VersionSet::open(dir.path()).unwrap());
+        let inputs = vec![
+            add_table(&versions, 0, &[("a", 1, ValueType::Value, "a1")]),
+            add_table(&versions, 0, &[("b", 2, ValueType::Value, "b2")]),
+        ];
+        let controller = Arc::new(WriteController::new(BackpressureOptions {
+            soft_l0_files: 2,
+            ..Default::default()
+        }));
+        controller.set_l0_files(versions.current().num_files(0));
+        assert_eq!(
+            controller.state(),
+            WriteState::Delayed(StallReason::L0Files)
+        );
+
+        let job = CompactionJob::new(CompactionOptions::default(), versions.clone())
+            .with_controller(controller.clone());
+        let compaction = Compaction {
+            level: 0,
+            output_level: 1,
+            inputs,
+            score: 1.0,
+            bottommost: false,
+            target_file_size: u64::MAX,
+            deletion: false,
+        };
+        job.run(&compaction, &[]).unwrap();
+        assert_eq!(versions.current().num_files(0), 0);
+        assert_eq!(controller.state(), WriteState::Normal);
+    }
+}
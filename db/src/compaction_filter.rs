@@ -0,0 +1,163 @@
+use std::fmt;
+
+/// What a `CompactionFilter` wants done with a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactionDecision {
+    Keep,
+    /// the key reads as deleted from then on
+    Remove,
+    /// the value is replaced by this one
+    ChangeValue(Vec<u8>),
+}
+
+/// Application logic deciding, value by value, what compaction keeps. Set through
+/// `CompactionOptions::filter`.
+///
+/// The filter sees the newest value of every key that no snapshot can read, so readers holding a
+/// snapshot never see a value change under them. Deletions, range deletions and values a snapshot
+/// still needs are passed through without asking, and tables that are moved or dropped whole are
+/// not looked at. A removed value becomes a deletion, so older versions of the key in deeper
+/// levels stay hidden, unless the compaction is bottommost and it can be dropped outright.
+pub trait CompactionFilter: Send + Sync {
+    /// Name shown in logs and debug output.
+    fn name(&self) -> &str;
+
+    /// Decides about `value` of the user key `key`. `level` is the level the compaction was
+    /// picked for.
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> CompactionDecision;
+}
+
+impl fmt::Debug for dyn CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CompactionFilter({})", self.name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compaction::test::add_table;
+    use crate::compaction::{Compaction, CompactionJob, CompactionOptions};
+    use crate::filename;
+    use crate::version_set::VersionSet;
+    use memtable::key::ValueType;
+    use memtable::memtable::Lookup;
+    use parking_lot::Mutex;
+    use sstable::table::Table;
+    use std::sync::Arc;
+
+    // prunes settled transactions and confirms pending ones
+    #[derive(Default)]
+    struct SettledSigners {
+        levels: Mutex<Vec<usize>>,
+    }
+
+    impl CompactionFilter for SettledSigners {
+        fn name(&self) -> &str {
+            "settled-signers"
+        }
+
+        fn filter(&self, level: usize, _key: &[u8], value: &[u8]) -> CompactionDecision {
+            self.levels.lock().push(level);
+            match value {
+                b"settled" => CompactionDecision::Remove,
+                b"pending" => CompactionDecision::ChangeValue(b"confirmed".to_vec()),
+                _ => CompactionDecision::Keep,
+            }
+        }
+    }
+
+    fn get(versions: &VersionSet, level: usize, key: &str) -> Option<Lookup> {
+        let version = versions.current();
+        let tables = version.overlapping_files(level, key.as_bytes(), key.as_bytes());
+        tables.iter().find_map(|table| {
+            Table::open(filename::table_file_name(
+                versions.folder(),
+                table.file_number,
+            ))
+            .unwrap()
+            .get(key.as_bytes(), u64::MAX >> 8)
+            .unwrap()
+        })
+    }
+
+    #[test]
+    fn test_filter_decisions_are_applied_and_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = Arc::new(VersionSet::open(dir.path()).unwrap());
+        let deepest = add_table(&versions, 2, &[("a", 1, ValueType::Value, "pending")]);
+        let first = add_table(
+            &versions,
+            0,
+            &[
+                ("a", 5, ValueType::Value, "settled"),
+                ("b", 6, ValueType::Value, "open"),
+                ("c", 7, ValueType::Value, "pending"),
+                ("d", 3, ValueType::Value, "settled"),
+            ],
+        );
+        let second = add_table(&versions, 0, &[("e", 12, ValueType::Value, "settled")]);
+        let filter = Arc::new(SettledSigners::default());
+        let options = CompactionOptions {
+            filter: Some(filter.clone()),
+            ..Default::default()
+        };
+        let job = CompactionJob::new(options, versions.clone());
+        let compaction = Compaction {
+            level: 0,
+            output_level: 1,
+            inputs: vec![first, second],
+            score: 1.0,
+            bottommost: false,
+            target_file_size: u64::MAX,
+            deletion: false,
+        };
+        // d is still read by the snapshot at 4
+        let stats = job.run(&compaction, &[4]).unwrap();
+        assert_eq!(
+            (
+                stats.filter_kept,
+                stats.filter_removed,
+                stats.filter_changed
+            ),
+            (1, 2, 1)
+        );
+        // the removed value turns into a deletion hiding the older one further down
+        assert_eq!(get(&versions, 1, "a"), Some(Lookup::Deleted));
+        assert_eq!(get(&versions, 1, "e"), Some(Lookup::Deleted));
+        let found = |v: &str| Some(Lookup::Found(v.as_bytes().to_vec()));
+        assert_eq!(get(&versions, 1, "b"), found("open"));
+        assert_eq!(get(&versions, 1, "c"), found("confirmed"));
+        assert_eq!(get(&versions, 1, "d"), found("settled"));
+
+        // at the bottom with no snapshot left, removed values are dropped outright
+        let mut inputs = versions.current().files(1).to_vec();
+        inputs.push(deepest);
+        let compaction = Compaction {
+            level: 1,
+            output_level: 2,
+            inputs,
+            score: 1.0,
+            bottommost: true,
+            target_file_size: u64::MAX,
+            deletion: false,
+        };
+        let stats = job.run(&compaction, &[]).unwrap();
+        assert_eq!(
+            (
+                stats.filter_kept,
+                stats.filter_removed,
+                stats.filter_changed
+            ),
+            (2, 1, 0)
+        );
+        assert_eq!(get(&versions, 2, "a"), None);
+        assert_eq!(get(&versions, 2, "d"), None);
+        assert_eq!(get(&versions, 2, "c"), found("confirmed"));
+        let version = versions.current();
+        let table = &version.files(2)[0];
+        let reader = Table::open(filename::table_file_name(dir.path(), table.file_number)).unwrap();
+        assert_eq!(reader.properties().num_entries, 2);
+        assert_eq!(*filter.levels.lock(), vec![0, 0, 0, 0, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,233 @@
+use parking_lot::Mutex;
+use sstable::table::Table;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use transaction::Transaction;
+
+use crate::compaction::{Compaction, CompactionPicker};
+use crate::filename;
+use crate::version_edit::TableMeta;
+use crate::version_set::Version;
+
+const DEFAULT_MAX_TABLE_FILES_SIZE: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct FifoOptions {
+    /// seconds after its newest timestamp a table is dropped, `None` keeps tables regardless of
+    /// age
+    pub ttl: Option<u64>,
+    /// the oldest tables are dropped while all of them together are larger than this
+    pub max_table_files_size: u64,
+}
+
+impl Default for FifoOptions {
+    fn default() -> Self {
+        FifoOptions {
+            ttl: None,
+            max_table_files_size: DEFAULT_MAX_TABLE_FILES_SIZE,
+        }
+    }
+}
+
+/// Timestamp of a value holding a serialized `Transaction`, to be set as
+/// `TableOptions::timestamp_extractor` so tables record the age of the transactions they hold.
+pub fn transaction_timestamp(value: &[u8]) -> Option<u64> {
+    Transaction::from_bytes(value)
+        .ok()
+        .map(|tx| tx.get_timestamp())
+}
+
+/// Picks FIFO compactions, which never merge anything: flushed tables stay in level 0 and are
+/// dropped whole, oldest first, once the database grows past `max_table_files_size`. With a TTL,
+/// tables whose newest transaction is older than it are dropped as well.
+///
+/// Ages come from the timestamp range in the table properties, so the TTL only applies to tables
+/// written with a `timestamp_extractor`. Tables without timestamps are only dropped for size.
+pub struct FifoPicker {
+    options: FifoOptions,
+    folder: PathBuf,
+    // timestamp range per table file number, read once from the table properties
+    timestamps: Mutex<HashMap<u64, Option<(u64, u64)>>>,
+}
+
+impl FifoPicker {
+    pub fn new(options: FifoOptions, folder: impl Into<PathBuf>) -> Self {
+        FifoPicker {
+            options,
+            folder: folder.into(),
+            timestamps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_expired(&self, table: &TableMeta) -> bool {
+        let Some(ttl) = self.options.ttl else {
+            return false;
+        };
+        let mut timestamps = self.timestamps.lock();
+        let range = match timestamps.get(&table.file_number) {
+            Some(range) => *range,
+            None => {
+                let path = filename::table_file_name(&self.folder, table.file_number);
+                match Table::open(&path) {
+                    Ok(reader) => {
+                        let range = reader.properties().timestamp_range();
+                        timestamps.insert(table.file_number, range);
+                        range
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Warning: Failed to read the properties of table {:?}: {}",
+                            path, e
+                        );
+                        None
+                    }
+                }
+            }
+        };
+        range.is_some_and(|(_, newest)| Transaction::timestamp_is_older_than(newest, ttl))
+    }
+}
+
+impl CompactionPicker for FifoPicker {
+    fn pick(&self, version: &Version) -> Option<Compaction> {
+        let mut tables: Vec<_> = version.all_files().cloned().collect();
+        tables.sort_by_key(|table| table.largest_seq);
+        self.timestamps
+            .lock()
+            .retain(|file_number, _| tables.iter().any(|t| t.file_number == *file_number));
+
+        let total_size: u64 = tables.iter().map(|table| table.file_size).sum();
+        let mut size = total_size;
+        let mut inputs = Vec::new();
+        for table in tables {
+            if size > self.options.max_table_files_size || self.is_expired(&table) {
+                size -= table.file_size;
+                inputs.push(table);
+            }
+        }
+        if inputs.is_empty() {
+            return None;
+        }
+        Some(Compaction {
+            level: 0,
+            output_level: 0,
+            inputs,
+            score: (total_size as f64 / self.options.max_table_files_size as f64).max(1.0),
+            bottommost: false,
+            target_file_size: u64::MAX,
+            deletion: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compaction::{CompactionJob, CompactionOptions};
+    use crate::version_edit::VersionEdit;
+    use crate::version_set::VersionSet;
+    use memtable::key::{InternalKey, ValueType};
+    use sstable::builder::{TableBuilder, TableOptions};
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use transaction::{Instruction, Signer};
+
+    // writes a level 0 table holding one transaction per timestamp
+    fn add_transactions(versions: &VersionSet, seq: u64, timestamps: &[u64]) -> TableMeta {
+        let file_number = versions.new_file_number();
+        let path = filename::table_file_name(versions.folder(), file_number);
+        let options = TableOptions {
+            timestamp_extractor: Some(transaction_timestamp),
+            ..Default::default()
+        };
+        let mut builder = TableBuilder::create(&path, options).unwrap();
+        for (i, timestamp) in timestamps.iter().enumerate() {
+            let tx = Transaction::new_with_timestamp(
+                Signer::new([i as u8; 32]),
+                [Instruction::default(); 5],
+                *timestamp,
+            );
+            let key = InternalKey::new(format!("tx{:03}", i), seq, ValueType::Value);
+            builder.add(&key, &tx.to_bytes().unwrap()).unwrap();
+        }
+        let info = builder.finish().unwrap();
+        let table = TableMeta {
+            file_number,
+            level: 0,
+            file_size: info.file_size,
+            smallest: info.smallest.unwrap(),
+            largest: info.largest.unwrap(),
+            smallest_seq: info.properties.smallest_seq,
+            largest_seq: info.properties.largest_seq,
+        };
+        let mut edit = VersionEdit::default();
+        edit.add_file(table.clone());
+        versions.log_and_apply(edit).unwrap();
+        table
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn test_drops_tables_past_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = Arc::new(VersionSet::open(dir.path()).unwrap());
+        let now = now();
+        let expired = add_transactions(&versions, 1, &[now - 7200, now - 3700]);
+        // its oldest transaction has expired, but not its newest
+        let straddling = add_transactions(&versions, 2, &[now - 7200, now - 60]);
+        let fresh = add_transactions(&versions, 3, &[now]);
+        let reader = Table::open(filename::table_file_name(
+            dir.path(),
+            straddling.file_number,
+        ))
+        .unwrap();
+        assert_eq!(
+            reader.properties().timestamp_range(),
+            Some((now - 7200, now - 60))
+        );
+
+        let picker = FifoPicker::new(
+            FifoOptions {
+                ttl: Some(3600),
+                ..Default::default()
+            },
+            dir.path(),
+        );
+        let compaction = picker.pick(&versions.current()).unwrap();
+        assert!(compaction.deletion);
+        assert_eq!(compaction.inputs, vec![expired.clone()]);
+        let job = CompactionJob::new(CompactionOptions::default(), versions.clone());
+        let stats = job.run(&compaction, &[]).unwrap();
+        assert_eq!((stats.input_files, stats.output_files), (1, 0));
+        assert_eq!(versions.current().files(0), &[straddling, fresh][..]);
+        assert!(!filename::table_file_name(dir.path(), expired.file_number).exists());
+        assert!(picker.pick(&versions.current()).is_none());
+    }
+
+    #[test]
+    fn test_drops_oldest_tables_past_size_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = VersionSet::open(dir.path()).unwrap();
+        let now = now();
+        let tables: Vec<_> = (1..=4)
+            .map(|seq| add_transactions(&versions, seq, &[now, now]))
+            .collect();
+        let size: u64 = tables.iter().map(|t| t.file_size).sum();
+        let picker = FifoPicker::new(
+            FifoOptions {
+                ttl: Some(3600),
+                max_table_files_size: size - tables[0].file_size - 1,
+            },
+            dir.path(),
+        );
+        let compaction = picker.pick(&versions.current()).unwrap();
+        assert_eq!(compaction.inputs, tables[..2]);
+        assert!(compaction.score > 1.0);
+    }
+}
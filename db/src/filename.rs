@@ -0,0 +1,32 @@
+use std::path::{Path, PathBuf};
+
+pub const CURRENT_FILE: &str = "CURRENT";
+pub const MANIFEST_FILE_PREFIX: &str = "MANIFEST-";
+pub const TABLE_FILE_EXTENSION: &str = "sst";
+
+/// `<folder>/<number>.sst`
+pub fn table_file_name(folder: &Path, file_number: u64) -> PathBuf {
+    folder.join(format!("{:06}.{}", file_number, TABLE_FILE_EXTENSION))
+}
+
+/// File number of a table file name, `None` for anything else.
+pub fn parse_table_file_name(path: &Path) -> Option<u64> {
+    if path.extension()? != TABLE_FILE_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// `MANIFEST-<number>`, relative to the database folder as `CURRENT` stores it.
+pub fn manifest_file_name(file_number: u64) -> String {
+    format!("{}{:06}", MANIFEST_FILE_PREFIX, file_number)
+}
+
+/// File number of a manifest file name, `None` for anything else.
+pub fn parse_manifest_file_name(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(MANIFEST_FILE_PREFIX)?
+        .parse()
+        .ok()
+}
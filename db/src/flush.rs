@@ -0,0 +1,372 @@
+use anyhow::{Context, Result};
+use checkpoint::CheckPointManager;
+use memtable::list::MemtableList;
+use memtable::memtable::Memtable;
+use sstable::builder::{TableBuilder, TableOptions};
+use std::fs::{self, File};
+use std::sync::Arc;
+use wal::backpressure::WriteController;
+use wal::wal::RecoveredWindow;
+
+use crate::filename;
+use crate::version_edit::{TableMeta, VersionEdit};
+use crate::version_set::VersionSet;
+
+/// What one run of the flush job did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushResult {
+    pub memtable_id: u64,
+    /// `None` when the memtable was already in a table before a crash and only its WAL windows
+    /// were left to release
+    pub table: Option<TableMeta>,
+    /// WAL windows whose segments were released
+    pub released: Vec<(u64, u64)>,
+}
+
+/// Turns immutable memtables into level 0 tables, oldest first.
+///
+/// A flush goes through three steps, each durable before the next starts:
+///
+/// 1. the table is written and synced, along with the folder entry for it
+/// 2. a `VersionEdit` adding the table is logged to the MANIFEST, from here on it is part of the
+///    database
+/// 3. the WAL segments of the memtable's windows are released
+///
+/// A crash before 2 leaves a table file nothing refers to, and the WAL still has every window,
+/// so replay rebuilds the memtable and `recover` deletes the file. A crash between 2 and 3 leaves
+/// WAL windows that are already in a table, the edit's log number tells `recover` to release them
+/// instead of replaying them.
+/// Either way no acknowledged write is lost.
+pub struct FlushJob {
+    options: TableOptions,
+    memtables: Arc<MemtableList>,
+    versions: Arc<VersionSet>,
+    checkpoint: Arc<CheckPointManager>,
+    controller: Option<Arc<WriteController>>,
+}
+
+impl FlushJob {
+    pub fn new(
+        options: TableOptions,
+        memtables: Arc<MemtableList>,
+        versions: Arc<VersionSet>,
+        checkpoint: Arc<CheckPointManager>,
+    ) -> Self {
+        FlushJob {
+            options,
+            memtables,
+            versions,
+            checkpoint,
+            controller: None,
+        }
+    }
+
+    /// Reports the level 0 table count to `controller` after every flush, so writers slow down
+    /// while L0 piles up.
+    pub fn with_controller(mut self, controller: Arc<WriteController>) -> Self {
+        self.controller = Some(controller);
+        self
+    }
+
+    /// Flushes the oldest immutable memtable. Returns `None` when none is waiting.
+    pub fn run(&self) -> Result<Option<FlushResult>> {
+        let Some(memtable) = self.memtables.oldest_immutable() else {
+            return Ok(None);
+        };
+        let Some((_, seq_end)) = memtable.seq_range() else {
+            // freezing skips empty memtables, nothing to write or release
+            self.memtables.remove_flushed(memtable.id());
+            return Ok(None);
+        };
+
+        let table = if self.versions.log_number().is_some_and(|seq| seq >= seq_end) {
+            None
+        } else {
+            let table = self.write_table(&memtable)?;
+            let mut edit = VersionEdit {
+                log_number: Some(seq_end),
+                last_sequence: Some(table.largest_seq),
+                ..Default::default()
+            };
+            edit.add_file(table.clone());
+            let version = self.versions.log_and_apply(edit)?;
+            if let Some(controller) = &self.controller {
+                controller.set_l0_files(version.num_files(0));
+            }
+            Some(table)
+        };
+        self.memtables.remove_flushed(memtable.id());
+
+        let released = memtable.windows();
+        for (seq_start, seq_end) in &released {
+            self.checkpoint
+                .sequence_window_memtable_flushed(*seq_start, *seq_end)?;
+        }
+        if let Some(table) = &table {
+            println!(
+                "Flushed memtable {} to table {} ({} bytes) covering sequence numbers up to {}",
+                memtable.id(),
+                table.file_number,
+                table.file_size,
+                seq_end
+            );
+        }
+        Ok(Some(FlushResult {
+            memtable_id: memtable.id(),
+            table,
+            released,
+        }))
+    }
+
+    /// Runs the job until no immutable memtable is left.
+    pub fn run_all(&self) -> Result<Vec<FlushResult>> {
+        let mut results = Vec::new();
+        while let Some(result) = self.run()? {
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    fn write_table(&self, memtable: &Memtable) -> Result<TableMeta> {
+        let folder = self.versions.folder();
+        let file_number = self.versions.new_file_number();
+        let path = filename::table_file_name(folder, file_number);
+        let mut builder = TableBuilder::create(&path, self.options.clone())?;
+        let added = memtable
+            .iter()
+            .try_for_each(|(key, value)| builder.add(&key, &value))
+            .and_then(|()| {
+                memtable
+                    .range_deletions()
+                    .try_for_each(|(start, end)| builder.add_range_deletion(&start, &end))
+            });
+        if let Err(e) = added {
+            builder.abandon()?;
+            return Err(e);
+        }
+        let info = builder.finish()?;
+        File::open(folder)?.sync_all()?;
+        let (Some(smallest), Some(largest)) = (info.smallest, info.largest) else {
+            unreachable!("memtable with a sequence range has entries");
+        };
+        Ok(TableMeta {
+            file_number,
+            level: 0,
+            file_size: info.file_size,
+            smallest,
+            largest,
+            smallest_seq: info.properties.smallest_seq,
+            largest_seq: info.properties.largest_seq,
+        })
+    }
+}
+
+/// Brings the table folder and the recovered WAL windows back in line after a crash. Deletes
+/// table files no version lists and releases windows that are already in a table. Returns the
+/// windows left to replay into memtables.
+pub fn recover(
+    versions: &VersionSet,
+    checkpoint: &CheckPointManager,
+    windows: Vec<RecoveredWindow>,
+) -> Result<Vec<RecoveredWindow>> {
+    let live = versions.live_files();
+    for entry in fs::read_dir(versions.folder())? {
+        let path = entry?.path();
+        let Some(file_number) = filename::parse_table_file_name(&path) else {
+            continue;
+        };
+        if !live.contains(&file_number) {
+            eprintln!(
+                "Warning: Deleting table {:?} missing from the MANIFEST",
+                path
+            );
+            fs::remove_file(&path)
+                .with_context(|| format!("failed to delete orphaned table: {:?}", path))?;
+        }
+    }
+
+    let flushed_seq = versions.log_number();
+    let mut replay = Vec::with_capacity(windows.len());
+    for window in windows {
+        let (seq_start, seq_end) = window.seq_range();
+        if flushed_seq.is_some_and(|seq| seq_end <= seq) {
+            checkpoint.sequence_window_memtable_flushed(seq_start, seq_end)?;
+        } else {
+            replay.push(window);
+        }
+    }
+    Ok(replay)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memtable::list::MemtableOptions;
+    use memtable::memtable::Lookup;
+    use sstable::table::Table;
+    use std::path::{Path, PathBuf};
+    use wal::backpressure::{BackpressureOptions, StallReason, WriteState};
+    use wal::record::WalRecord;
+    use wal::wal::WriteAheadLog;
+
+    fn write_windows(wal: &WriteAheadLog, windows: &[(u64, u64)]) {
+        for (seq_start, seq_end) in windows {
+            let mut file = wal.new_wal_file(*seq_start, *seq_end).unwrap();
+            let records: Vec<_> = (*seq_start..=*seq_end)
+                .map(|seq| WalRecord::Put {
+                    key: format!("key{:03}", seq).into_bytes(),
+                    value: seq.to_be_bytes().to_vec(),
+                })
+                .collect();
+            wal.put_records(&mut file, &records).unwrap();
+        }
+    }
+
+    struct Db {
+        wal_dir: PathBuf,
+        db_dir: PathBuf,
+        memtables: Arc<MemtableList>,
+        versions: Arc<VersionSet>,
+        checkpoint: Arc<CheckPointManager>,
+    }
+
+    impl Db {
+        fn open(dir: &Path) -> Self {
+            let wal_dir = dir.join("wal");
+            let db_dir = dir.join("db");
+            let (wal, windows, _) = WriteAheadLog::recover(&wal_dir).unwrap();
+            let versions = Arc::new(VersionSet::open(&db_dir).unwrap());
+            let checkpoint = Arc::new(CheckPointManager::new(Arc::new(wal)));
+            let windows = recover(&versions, &checkpoint, windows.unwrap_or_default()).unwrap();
+            let memtables = MemtableList::recover(MemtableOptions::default(), &windows).unwrap();
+            Db {
+                wal_dir,
+                db_dir,
+                memtables: Arc::new(memtables),
+                versions,
+                checkpoint,
+            }
+        }
+
+        fn flush_job(&self) -> FlushJob {
+            FlushJob::new(
+                TableOptions::default(),
+                self.memtables.clone(),
+                self.versions.clone(),
+                self.checkpoint.clone(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_flush_writes_l0_table_and_releases_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (wal, _, _) = WriteAheadLog::recover(dir.path().join("wal")).unwrap();
+            write_windows(&wal, &[(0, 4), (5, 9)]);
+        }
+        let db = Db::open(dir.path());
+        let controller = Arc::new(WriteController::new(BackpressureOptions {
+            soft_l0_files: 1,
+            ..Default::default()
+        }));
+        assert!(db.memtables.freeze().is_some());
+        let result = db
+            .flush_job()
+            .with_controller(controller.clone())
+            .run()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            controller.state(),
+            WriteState::Delayed(StallReason::L0Files)
+        );
+        assert_eq!(result.released, vec![(0, 4), (5, 9)]);
+        let table = result.table.unwrap();
+        assert_eq!(
+            (table.level, table.smallest_seq, table.largest_seq),
+            (0, 0, 9)
+        );
+        assert_eq!(db.versions.log_number(), Some(9));
+        assert_eq!(db.versions.current().files(0), std::slice::from_ref(&table));
+        assert_eq!(db.memtables.immutable_count(), 0);
+        assert!(db.flush_job().run().unwrap().is_none());
+
+        let reader = Table::open(filename::table_file_name(&db.db_dir, table.file_number)).unwrap();
+        assert_eq!(
+            reader.get(b"key007", u64::MAX >> 8).unwrap(),
+            Some(Lookup::Found(7u64.to_be_bytes().to_vec()))
+        );
+        assert!(
+            WriteAheadLog::find_wal_files(&db.wal_dir)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_flush_memtable_of_only_range_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (wal, _, _) = WriteAheadLog::recover(dir.path().join("wal")).unwrap();
+            let mut file = wal.new_wal_file(0, 1).unwrap();
+            let records = [
+                WalRecord::DeleteRange {
+                    start: b"key000".to_vec(),
+                    end: b"key005".to_vec(),
+                },
+                WalRecord::DeleteRange {
+                    start: b"key003".to_vec(),
+                    end: b"key009".to_vec(),
+                },
+            ];
+            wal.put_records(&mut file, &records).unwrap();
+        }
+        let db = Db::open(dir.path());
+        assert!(db.memtables.freeze().is_some());
+        let table = db.flush_job().run().unwrap().unwrap().table.unwrap();
+        assert_eq!((table.smallest_seq, table.largest_seq), (0, 1));
+
+        let reader = Table::open(filename::table_file_name(&db.db_dir, table.file_number)).unwrap();
+        assert_eq!(reader.range_deletions().len(), 2);
+        assert_eq!(
+            reader.get(b"key007", u64::MAX >> 8).unwrap(),
+            Some(Lookup::Deleted)
+        );
+        assert_eq!(reader.get(b"key009", u64::MAX >> 8).unwrap(), None);
+    }
+
+    #[test]
+    fn test_recover_after_crash_between_manifest_and_release() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (wal, _, _) = WriteAheadLog::recover(dir.path().join("wal")).unwrap();
+            write_windows(&wal, &[(0, 4), (5, 9), (10, 14)]);
+        }
+        let db = Db::open(dir.path());
+        let memtable = db.memtables.active();
+        // the first two windows made it into a logged table, then the process died
+        let job = db.flush_job();
+        let mut edit = VersionEdit {
+            log_number: Some(9),
+            ..Default::default()
+        };
+        edit.add_file(job.write_table(&memtable).unwrap());
+        db.versions.log_and_apply(edit).unwrap();
+        // and a later table was written but never recorded
+        let orphan = job.write_table(&memtable).unwrap();
+        let orphan = filename::table_file_name(&db.db_dir, orphan.file_number);
+        drop(job);
+        drop(db);
+
+        let db = Db::open(dir.path());
+        assert!(!orphan.exists());
+        assert_eq!(db.versions.current().num_files(0), 1);
+        let wal_files = WriteAheadLog::find_wal_files(&db.wal_dir).unwrap().unwrap();
+        assert_eq!(wal_files.len(), 1);
+        let memtable = db.memtables.active();
+        assert_eq!(memtable.windows(), vec![(10, 14)]);
+        assert_eq!(memtable.get(b"key003", u64::MAX >> 8), None);
+        assert!(memtable.get(b"key012", u64::MAX >> 8).is_some());
+    }
+}
@@ -0,0 +1,283 @@
+use parking_lot::Mutex;
+
+use crate::compaction::{self, Compaction, CompactionOptions, CompactionPicker};
+use crate::version_edit::TableMeta;
+use crate::version_set::{NUM_LEVELS, Version};
+
+/// Picks leveled compactions: every level past 0 holds tables with disjoint key ranges and
+/// targets `max_bytes_for_level_base * multiplier^(level - 1)` bytes, level 0 is bounded by its
+/// table count instead. The level furthest past its target is compacted into the next one.
+pub struct LeveledPicker {
+    options: CompactionOptions,
+    // largest user key compacted last per level, the next pick starts after it so every key
+    // range of a level gets its turn
+    compact_pointers: Mutex<Vec<Option<Vec<u8>>>>,
+}
+
+impl LeveledPicker {
+    pub fn new(options: CompactionOptions) -> Self {
+        LeveledPicker {
+            options,
+            compact_pointers: Mutex::new(vec![None; NUM_LEVELS]),
+        }
+    }
+
+    /// How far each level but the last is past its target, compaction is due from 1 on.
+    pub fn level_scores(&self, version: &Version) -> Vec<f64> {
+        (0..NUM_LEVELS - 1)
+            .map(|level| match level {
+                0 => {
+                    version.num_files(0) as f64
+                        / self.options.level0_file_num_compaction_trigger as f64
+                }
+                _ => {
+                    version.level_size(level) as f64
+                        / self.options.max_bytes_for_level(level) as f64
+                }
+            })
+            .collect()
+    }
+
+    /// The oldest level 0 table plus every other one overlapping it, directly or through
+    /// another, since newer versions of a key may sit in any of them.
+    fn level0_inputs(version: &Version) -> Vec<TableMeta> {
+        let files = version.files(0);
+        let Some(oldest) = files.first() else {
+            return Vec::new();
+        };
+        let mut inputs = vec![oldest.clone()];
+        loop {
+            let Some((smallest, largest)) = user_key_range(&inputs) else {
+                return inputs;
+            };
+            let overlapping = version.overlapping_files(0, &smallest, &largest);
+            if overlapping.len() == inputs.len() {
+                return overlapping;
+            }
+            inputs = overlapping;
+        }
+    }
+
+    /// First table of `level` past the compact pointer, wrapping around to the first one.
+    fn next_file(&self, version: &Version, level: usize) -> Option<TableMeta> {
+        let pointers = self.compact_pointers.lock();
+        let files = version.files(level);
+        let after_pointer = pointers[level].as_ref().and_then(|pointer| {
+            files
+                .iter()
+                .find(|table| table.smallest.user_key > *pointer)
+        });
+        after_pointer.or(files.first()).cloned()
+    }
+}
+
+impl CompactionPicker for LeveledPicker {
+    /// The compaction of the level with the highest score.
+    fn pick(&self, version: &Version) -> Option<Compaction> {
+        let (level, score) = self
+            .level_scores(version)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, score)| *score >= 1.0)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        let mut inputs = match level {
+            0 => Self::level0_inputs(version),
+            _ => vec![self.next_file(version, level)?],
+        };
+        let (smallest, largest) = user_key_range(&inputs)?;
+        if level > 0 {
+            self.compact_pointers.lock()[level] = Some(largest.clone());
+        }
+        let output_level = level + 1;
+        inputs.extend(version.overlapping_files(output_level, &smallest, &largest));
+        let (smallest, largest) = user_key_range(&inputs)?;
+        Some(Compaction {
+            level,
+            output_level,
+            bottommost: compaction::is_bottommost(version, output_level, &smallest, &largest),
+            inputs,
+            score,
+            target_file_size: self.options.target_file_size,
+            deletion: false,
+        })
+    }
+}
+
+fn user_key_range(tables: &[TableMeta]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let smallest = tables.iter().map(|t| &t.smallest.user_key).min()?;
+    let largest = tables.iter().map(|t| &t.largest.user_key).max()?;
+    Some((smallest.clone(), largest.clone()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compaction::CompactionJob;
+    use crate::compaction::test::add_table;
+    use crate::filename;
+    use crate::version_set::VersionSet;
+    use memtable::key::ValueType;
+    use memtable::memtable::Lookup;
+    use sstable::table::Table;
+    use std::sync::Arc;
+
+    fn get(versions: &VersionSet, level: usize, key: &str, snapshot: u64) -> Option<Lookup> {
+        let version = versions.current();
+        let tables = version.overlapping_files(level, key.as_bytes(), key.as_bytes());
+        tables.iter().find_map(|table| {
+            Table::open(filename::table_file_name(
+                versions.folder(),
+                table.file_number,
+            ))
+            .unwrap()
+            .get(key.as_bytes(), snapshot)
+            .unwrap()
+        })
+    }
+
+    #[test]
+    fn test_picks_by_score_and_merges_overlapping_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = VersionSet::open(dir.path()).unwrap();
+        let options = CompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_bytes_for_level_base: 1 << 20,
+            ..Default::default()
+        };
+        let picker = LeveledPicker::new(options);
+        add_table(&versions, 1, &[("a", 1, ValueType::Value, "1")]);
+        let l1 = add_table(&versions, 1, &[("m", 1, ValueType::Value, "1")]);
+        let l0_old = add_table(&versions, 0, &[("k", 2, ValueType::Value, "2")]);
+        assert!(picker.pick(&versions.current()).is_none());
+        // overlaps nothing, picked anyway since it is older than the one that triggers
+        let l0_new = add_table(&versions, 0, &[("n", 3, ValueType::Value, "3")]);
+        let l0_bridge = add_table(
+            &versions,
+            0,
+            &[
+                ("j", 4, ValueType::Value, "4"),
+                ("o", 4, ValueType::Value, "4"),
+            ],
+        );
+        let version = versions.current();
+        assert_eq!(picker.level_scores(&version)[0], 1.5);
+        let compaction = picker.pick(&version).unwrap();
+        assert_eq!((compaction.level, compaction.output_level), (0, 1));
+        let mut numbers: Vec<_> = compaction.inputs.iter().map(|t| t.file_number).collect();
+        numbers.sort();
+        assert_eq!(
+            numbers,
+            vec![
+                l1.file_number,
+                l0_old.file_number,
+                l0_new.file_number,
+                l0_bridge.file_number
+            ]
+        );
+        assert!(compaction.bottommost);
+
+        // a lone table with nothing to merge with is moved, not rewritten
+        drop(version);
+        let job = CompactionJob::new(CompactionOptions::default(), Arc::new(versions));
+        let compaction = Compaction {
+            level: 1,
+            output_level: 2,
+            inputs: vec![l1.clone()],
+            score: 2.0,
+            bottommost: true,
+            target_file_size: u64::MAX,
+            deletion: false,
+        };
+        assert!(compaction.is_trivial_move());
+        let stats = job.run(&compaction, &[]).unwrap();
+        assert_eq!((stats.input_files, stats.output_files), (1, 0));
+    }
+
+    #[test]
+    fn test_compaction_keeps_versions_snapshots_need() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = Arc::new(VersionSet::open(dir.path()).unwrap());
+        let older = add_table(
+            &versions,
+            0,
+            &[
+                ("a", 1, ValueType::Value, "a1"),
+                ("b", 2, ValueType::Value, "b2"),
+                ("c", 3, ValueType::Value, "c3"),
+                ("d", 4, ValueType::Value, "d4"),
+                ("e", 5, ValueType::Value, "e5"),
+            ],
+        );
+        let newer = add_table(
+            &versions,
+            0,
+            &[
+                ("a", 10, ValueType::Value, "a10"),
+                ("b", 11, ValueType::Deletion, ""),
+                ("c", 12, ValueType::Value, "c12"),
+                // deletes d and e
+                ("d", 13, ValueType::RangeDeletion, "f"),
+            ],
+        );
+        let job = CompactionJob::new(CompactionOptions::default(), versions.clone());
+        let compaction = Compaction {
+            level: 0,
+            output_level: 1,
+            inputs: vec![older, newer],
+            score: 1.0,
+            bottommost: true,
+            target_file_size: 1,
+            deletion: false,
+        };
+        // a reader still holds a snapshot at 4
+        let stats = job.run(&compaction, &[4]).unwrap();
+        assert_eq!(stats.input_files, 2);
+        assert_eq!(versions.current().num_files(0), 0);
+        let outputs = versions.current().files(1).to_vec();
+        // one table per user key at this target size, with disjoint key ranges
+        assert!(outputs.len() >= 4);
+        assert!(
+            outputs
+                .windows(2)
+                .all(|w| w[0].largest.user_key <= w[1].smallest.user_key)
+        );
+
+        // both versions visible to some snapshot survive
+        let found = |v: &str| Some(Lookup::Found(v.as_bytes().to_vec()));
+        assert_eq!(get(&versions, 1, "a", 4), found("a1"));
+        assert_eq!(get(&versions, 1, "a", 100), found("a10"));
+        assert_eq!(get(&versions, 1, "b", 4), found("b2"));
+        assert_eq!(get(&versions, 1, "b", 100), Some(Lookup::Deleted));
+        assert_eq!(get(&versions, 1, "d", 4), found("d4"));
+        assert_eq!(get(&versions, 1, "d", 100), Some(Lookup::Deleted));
+        // no snapshot sees e before the range deletion
+        assert_eq!(get(&versions, 1, "e", 4), None);
+        assert_eq!(stats.entries_dropped, 1);
+
+        // without the snapshot the old versions and, at the bottom, the tombstones go
+        let compaction = Compaction {
+            level: 1,
+            output_level: 2,
+            inputs: outputs,
+            score: 1.0,
+            bottommost: true,
+            target_file_size: 1,
+            deletion: false,
+        };
+        let stats = job.run(&compaction, &[]).unwrap();
+        assert_eq!(stats.entries_dropped, 5);
+        assert_eq!(stats.range_deletions_dropped, 1);
+        assert_eq!(get(&versions, 2, "a", 100), found("a10"));
+        assert_eq!(get(&versions, 2, "b", 100), None);
+        assert_eq!(get(&versions, 2, "d", 100), None);
+        assert_eq!(get(&versions, 2, "e", 100), None);
+        let files: Vec<_> = versions.current().all_files().cloned().collect();
+        assert_eq!(files.len(), 2);
+        // the inputs are gone from disk as well
+        let tables = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|e| filename::parse_table_file_name(&e.as_ref().unwrap().path()).is_some())
+            .count();
+        assert_eq!(tables, 2);
+    }
+}
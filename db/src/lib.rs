@@ -0,0 +1,9 @@
+pub mod compaction;
+pub mod compaction_filter;
+pub mod fifo;
+pub mod filename;
+pub mod flush;
+pub mod leveled;
+pub mod universal;
+pub mod version_edit;
+pub mod version_set;
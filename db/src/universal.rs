@@ -0,0 +1,254 @@
+use crate::compaction::{Compaction, CompactionOptions, CompactionPicker};
+use crate::version_edit::TableMeta;
+use crate::version_set::{NUM_LEVELS, Version};
+
+const DEFAULT_SIZE_RATIO: u64 = 1;
+const DEFAULT_MIN_MERGE_WIDTH: usize = 2;
+const DEFAULT_MAX_SIZE_AMPLIFICATION_PERCENT: u64 = 200;
+
+#[derive(Debug, Clone)]
+pub struct UniversalOptions {
+    /// percentage a run may be larger than the runs picked before it and still join them
+    pub size_ratio: u64,
+    /// fewest runs merged by a size ratio compaction, at least 2
+    pub min_merge_width: usize,
+    /// size of all runs but the oldest, in percent of the oldest, that triggers a full compaction
+    pub max_size_amplification_percent: u64,
+}
+
+impl Default for UniversalOptions {
+    fn default() -> Self {
+        UniversalOptions {
+            size_ratio: DEFAULT_SIZE_RATIO,
+            min_merge_width: DEFAULT_MIN_MERGE_WIDTH,
+            max_size_amplification_percent: DEFAULT_MAX_SIZE_AMPLIFICATION_PERCENT,
+        }
+    }
+}
+
+/// A table or level whose keys are disjoint and sorted, newer runs hold newer versions.
+#[derive(Debug)]
+struct SortedRun {
+    level: usize,
+    tables: Vec<TableMeta>,
+    size: u64,
+}
+
+/// Picks universal (size-tiered) compactions: every level 0 table and every non-empty deeper level
+/// is a sorted run, and runs of similar size are merged into one. Every byte is rewritten about
+/// once per size tier instead of once per level, trading write amplification for more runs to
+/// read and more space held by obsolete versions.
+///
+/// Nothing is done until there are `level0_file_num_compaction_trigger` runs. Then, in order:
+///
+/// 1. when the runs newer than the oldest add up to `max_size_amplification_percent` of it, all
+///    runs are merged into the last level
+/// 2. the newest stretch of runs where each is at most `size_ratio` percent larger than the ones
+///    before it together is merged, if it is at least `min_merge_width` runs long
+/// 3. otherwise, once there are more runs than the trigger, the newest runs are merged, enough
+///    of them to get back under it
+pub struct UniversalPicker {
+    universal: UniversalOptions,
+    options: CompactionOptions,
+}
+
+impl UniversalPicker {
+    pub fn new(universal: UniversalOptions, options: CompactionOptions) -> Self {
+        UniversalPicker { universal, options }
+    }
+
+    /// Whether the runs newer than the oldest one hold too much space next to it.
+    fn size_amplified(&self, runs: &[SortedRun]) -> bool {
+        let Some((oldest, newer)) = runs.split_last() else {
+            return false;
+        };
+        let newer_size: u64 = newer.iter().map(|run| run.size).sum();
+        let max_size = self
+            .universal
+            .max_size_amplification_percent
+            .saturating_mul(oldest.size);
+        !newer.is_empty() && newer_size.saturating_mul(100) >= max_size
+    }
+
+    /// Start of the newest stretch of similarly sized runs and its length.
+    fn size_ratio_pick(&self, runs: &[SortedRun]) -> Option<(usize, usize)> {
+        let min_width = self.universal.min_merge_width.max(2);
+        (0..runs.len()).find_map(|start| {
+            let mut candidate_size = runs[start].size;
+            let mut end = start + 1;
+            while let Some(run) = runs.get(end) {
+                let limit = candidate_size.saturating_mul(100 + self.universal.size_ratio) / 100;
+                if run.size > limit {
+                    break;
+                }
+                candidate_size += run.size;
+                end += 1;
+            }
+            (end - start >= min_width).then_some((start, end - start))
+        })
+    }
+
+    fn compaction(&self, runs: &[SortedRun], start: usize, width: usize, score: f64) -> Compaction {
+        let picked = &runs[start..start + width];
+        let bottommost = start + width == runs.len();
+        // just above the next older run, whose versions must stay below the merged ones
+        let output_level = match runs.get(start + width) {
+            None => NUM_LEVELS - 1,
+            Some(next) => next.level.saturating_sub(1),
+        };
+        Compaction {
+            level: picked[0].level,
+            output_level,
+            inputs: picked.iter().flat_map(|run| run.tables.clone()).collect(),
+            score,
+            bottommost,
+            // a level 0 table is a run of its own and cannot be split
+            target_file_size: match output_level {
+                0 => u64::MAX,
+                _ => self.options.target_file_size,
+            },
+            deletion: false,
+        }
+    }
+}
+
+impl CompactionPicker for UniversalPicker {
+    fn pick(&self, version: &Version) -> Option<Compaction> {
+        let runs = sorted_runs(version);
+        let trigger = self.options.level0_file_num_compaction_trigger.max(1);
+        if runs.len() < trigger || runs.len() < 2 {
+            return None;
+        }
+        let score = runs.len() as f64 / trigger as f64;
+        if self.size_amplified(&runs) {
+            return Some(self.compaction(&runs, 0, runs.len(), score));
+        }
+        if let Some((start, width)) = self.size_ratio_pick(&runs) {
+            return Some(self.compaction(&runs, start, width, score));
+        }
+        if runs.len() <= trigger {
+            return None;
+        }
+        let width = (runs.len() + 1 - trigger)
+            .max(self.universal.min_merge_width.max(2))
+            .min(runs.len());
+        Some(self.compaction(&runs, 0, width, score))
+    }
+}
+
+/// Sorted runs of `version`, newest first: level 0 tables by their newest entry, then the levels.
+fn sorted_runs(version: &Version) -> Vec<SortedRun> {
+    let mut level0 = version.files(0).to_vec();
+    level0.sort_by_key(|table| std::cmp::Reverse(table.largest_seq));
+    let level0 = level0.into_iter().map(|table| SortedRun {
+        level: 0,
+        size: table.file_size,
+        tables: vec![table],
+    });
+    let levels = (1..NUM_LEVELS)
+        .filter(|level| version.num_files(*level) > 0)
+        .map(|level| SortedRun {
+            level,
+            tables: version.files(level).to_vec(),
+            size: version.level_size(level),
+        });
+    level0.chain(levels).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compaction::test::add_table;
+    use crate::compaction::{CompactionJob, CompactionStyle, new_picker};
+    use crate::version_set::VersionSet;
+    use memtable::key::ValueType;
+    use std::sync::Arc;
+
+    fn universal_options(trigger: usize) -> CompactionOptions {
+        CompactionOptions {
+            level0_file_num_compaction_trigger: trigger,
+            style: CompactionStyle::Universal(UniversalOptions::default()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merges_similar_runs_then_everything_once_space_amplifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = Arc::new(VersionSet::open(dir.path()).unwrap());
+        let options = universal_options(4);
+        let picker = new_picker(options.clone(), dir.path());
+        let job = CompactionJob::new(options, versions.clone());
+        let value = "v".repeat(4096);
+        let big = add_table(
+            &versions,
+            6,
+            &[
+                ("a", 1, ValueType::Value, &value),
+                ("b", 2, ValueType::Value, &value),
+                ("c", 3, ValueType::Value, &value),
+                ("d", 4, ValueType::Value, &value),
+            ],
+        );
+        let small = |key, seq| add_table(&versions, 0, &[(key, seq, ValueType::Value, "v")]);
+        small("a", 10);
+        small("b", 11);
+        assert!(picker.pick(&versions.current()).is_none());
+        small("c", 12);
+
+        // the three similar tables merge into one run right above the big one
+        let compaction = picker.pick(&versions.current()).unwrap();
+        assert_eq!(compaction.inputs.len(), 3);
+        assert_eq!((compaction.level, compaction.output_level), (0, 5));
+        assert!(!compaction.bottommost);
+        job.run(&compaction, &[]).unwrap();
+        assert_eq!(versions.current().num_files(5), 1);
+
+        // the newer runs now hold more than twice the oldest, everything goes to the bottom
+        let value = "v".repeat(20 * 1024);
+        add_table(&versions, 0, &[("x", 20, ValueType::Value, &value)]);
+        add_table(&versions, 0, &[("y", 21, ValueType::Value, &value)]);
+        let compaction = picker.pick(&versions.current()).unwrap();
+        assert_eq!(compaction.inputs.len(), 4);
+        assert!(compaction.inputs.contains(&big));
+        assert_eq!(compaction.output_level, NUM_LEVELS - 1);
+        assert!(compaction.bottommost);
+        job.run(&compaction, &[]).unwrap();
+        let version = versions.current();
+        assert_eq!(version.all_files().count(), 1);
+        assert_eq!(version.num_files(NUM_LEVELS - 1), 1);
+    }
+
+    #[test]
+    fn test_merges_newest_runs_when_sizes_differ() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = Arc::new(VersionSet::open(dir.path()).unwrap());
+        let options = universal_options(3);
+        let picker = new_picker(options.clone(), dir.path());
+        let job = CompactionJob::new(options, versions.clone());
+        for (key, seq, size) in [("a", 1, 64 * 1024), ("b", 2, 16 * 1024), ("c", 3, 4096)] {
+            add_table(
+                &versions,
+                0,
+                &[(key, seq, ValueType::Value, &"v".repeat(size))],
+            );
+        }
+        // at the trigger but nothing to merge by size, so more runs are allowed
+        assert!(picker.pick(&versions.current()).is_none());
+        add_table(&versions, 0, &[("d", 4, ValueType::Value, "v")]);
+
+        // past the trigger, the two newest runs merge into one level 0 table
+        let compaction = picker.pick(&versions.current()).unwrap();
+        let mut seqs: Vec<_> = compaction.inputs.iter().map(|t| t.largest_seq).collect();
+        seqs.sort();
+        assert_eq!(seqs, vec![3, 4]);
+        assert_eq!(
+            (compaction.output_level, compaction.target_file_size),
+            (0, u64::MAX)
+        );
+        assert!(!compaction.bottommost);
+        job.run(&compaction, &[]).unwrap();
+        assert_eq!(versions.current().num_files(0), 3);
+        assert!(picker.pick(&versions.current()).is_none());
+    }
+}
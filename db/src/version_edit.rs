@@ -0,0 +1,189 @@
+use bytes::{Buf, BufMut};
+use memtable::key::InternalKey;
+
+const TAG_LOG_NUMBER: u8 = 1;
+const TAG_NEXT_FILE_NUMBER: u8 = 2;
+const TAG_LAST_SEQUENCE: u8 = 3;
+const TAG_DELETED_FILE: u8 = 4;
+const TAG_NEW_FILE: u8 = 5;
+
+/// A table that is part of the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableMeta {
+    pub file_number: u64,
+    pub level: u32,
+    pub file_size: u64,
+    pub smallest: InternalKey,
+    pub largest: InternalKey,
+    pub smallest_seq: u64,
+    pub largest_seq: u64,
+}
+
+impl TableMeta {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.file_number);
+        buf.put_u32(self.level);
+        buf.put_u64(self.file_size);
+        buf.put_u64(self.smallest_seq);
+        buf.put_u64(self.largest_seq);
+        for key in [&self.smallest, &self.largest] {
+            buf.put_u32(key.encoded_len() as u32);
+            key.encode_to(buf);
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Self> {
+        if buf.remaining() < 8 * 4 + 4 {
+            return None;
+        }
+        let file_number = buf.get_u64();
+        let level = buf.get_u32();
+        let file_size = buf.get_u64();
+        let smallest_seq = buf.get_u64();
+        let largest_seq = buf.get_u64();
+        let mut key = || {
+            if buf.remaining() < 4 {
+                return None;
+            }
+            let len = buf.get_u32() as usize;
+            let key = InternalKey::decode(buf.get(..len)?)?;
+            buf.advance(len);
+            Some(key)
+        };
+        let smallest = key()?;
+        let largest = key()?;
+        Some(TableMeta {
+            file_number,
+            level,
+            file_size,
+            smallest,
+            largest,
+            smallest_seq,
+            largest_seq,
+        })
+    }
+}
+
+/// A change to the set of tables and the counters kept with it, one record of the MANIFEST.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionEdit {
+    /// last WAL sequence number whose window is in a table, older windows need no replay
+    pub log_number: Option<u64>,
+    pub next_file_number: Option<u64>,
+    /// highest sequence number written to any table
+    pub last_sequence: Option<u64>,
+    /// `(level, file_number)` of tables that are no longer part of the database
+    pub deleted_files: Vec<(u32, u64)>,
+    pub new_files: Vec<TableMeta>,
+}
+
+impl VersionEdit {
+    pub fn add_file(&mut self, table: TableMeta) {
+        self.new_files.push(table);
+    }
+
+    pub fn delete_file(&mut self, level: u32, file_number: u64) {
+        self.deleted_files.push((level, file_number));
+    }
+
+    /// `len: u32 | (tag: u8 | field)* | crc32c: u32`, the checksum covering the tagged fields.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let counters = [
+            (TAG_LOG_NUMBER, self.log_number),
+            (TAG_NEXT_FILE_NUMBER, self.next_file_number),
+            (TAG_LAST_SEQUENCE, self.last_sequence),
+        ];
+        for (tag, value) in counters {
+            if let Some(value) = value {
+                payload.put_u8(tag);
+                payload.put_u64(value);
+            }
+        }
+        for (level, file_number) in &self.deleted_files {
+            payload.put_u8(TAG_DELETED_FILE);
+            payload.put_u32(*level);
+            payload.put_u64(*file_number);
+        }
+        for table in &self.new_files {
+            payload.put_u8(TAG_NEW_FILE);
+            table.encode_to(&mut payload);
+        }
+        let mut buf = Vec::with_capacity(payload.len() + 8);
+        buf.put_u32(payload.len() as u32);
+        buf.put_slice(&payload);
+        buf.put_u32(crc32c::crc32c(&payload));
+        buf
+    }
+
+    /// Decodes the edit at the start of `buf` and returns it with its encoded length. `None`
+    /// when the edit is incomplete, fails its checksum or has an unknown tag.
+    pub fn decode(buf: &[u8]) -> Option<(Self, usize)> {
+        let len = u32::from_be_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+        let payload = buf.get(4..4 + len)?;
+        let crc = u32::from_be_bytes(buf.get(4 + len..8 + len)?.try_into().ok()?);
+        if crc32c::crc32c(payload) != crc {
+            return None;
+        }
+        let mut edit = VersionEdit::default();
+        let mut payload = payload;
+        while payload.has_remaining() {
+            let tag = payload.get_u8();
+            let counter = match tag {
+                TAG_LOG_NUMBER => &mut edit.log_number,
+                TAG_NEXT_FILE_NUMBER => &mut edit.next_file_number,
+                TAG_LAST_SEQUENCE => &mut edit.last_sequence,
+                TAG_DELETED_FILE => {
+                    if payload.remaining() < 4 + 8 {
+                        return None;
+                    }
+                    let level = payload.get_u32();
+                    edit.deleted_files.push((level, payload.get_u64()));
+                    continue;
+                }
+                TAG_NEW_FILE => {
+                    edit.new_files.push(TableMeta::decode(&mut payload)?);
+                    continue;
+                }
+                _ => return None,
+            };
+            if payload.remaining() < 8 {
+                return None;
+            }
+            *counter = Some(payload.get_u64());
+        }
+        Some((edit, len + 8))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memtable::key::ValueType;
+
+    #[test]
+    fn test_edit_roundtrip() {
+        let mut edit = VersionEdit {
+            log_number: Some(41),
+            last_sequence: Some(40),
+            ..Default::default()
+        };
+        edit.delete_file(0, 3);
+        edit.add_file(TableMeta {
+            file_number: 7,
+            level: 1,
+            file_size: 4096,
+            smallest: InternalKey::new("a", 1, ValueType::Value),
+            largest: InternalKey::new("m", 40, ValueType::Deletion),
+            smallest_seq: 1,
+            largest_seq: 40,
+        });
+        let mut encoded = edit.encode();
+        let len = encoded.len();
+        encoded.extend_from_slice(b"next edit");
+        assert_eq!(VersionEdit::decode(&encoded), Some((edit, len)));
+        encoded[6] ^= 1;
+        assert_eq!(VersionEdit::decode(&encoded), None);
+        assert_eq!(VersionEdit::decode(&encoded[..len - 1]), None);
+    }
+}
@@ -0,0 +1,402 @@
+use anyhow::{Context, Result, bail};
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+
+use crate::filename::{self, CURRENT_FILE};
+use crate::version_edit::{TableMeta, VersionEdit};
+
+pub const NUM_LEVELS: usize = 7;
+
+/// The tables making up the database at one point in time. Never changes once built, a new
+/// version is installed for every edit.
+///
+/// Readers clone the `Arc` of the version they read from. Tables an edit dropped are only
+/// deleted once no version that still lists them is alive.
+#[derive(Debug, Default)]
+pub struct Version {
+    // level 0 in flush order, oldest first, other levels sorted by smallest key
+    levels: [Vec<TableMeta>; NUM_LEVELS],
+}
+
+impl Version {
+    pub fn files(&self, level: usize) -> &[TableMeta] {
+        &self.levels[level]
+    }
+
+    pub fn num_files(&self, level: usize) -> usize {
+        self.levels[level].len()
+    }
+
+    /// Bytes of all tables in `level`.
+    pub fn level_size(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|table| table.file_size).sum()
+    }
+
+    pub fn all_files(&self) -> impl Iterator<Item = &TableMeta> {
+        self.levels.iter().flatten()
+    }
+
+    /// Tables of `level` whose user key range overlaps `[smallest, largest]`.
+    pub fn overlapping_files(
+        &self,
+        level: usize,
+        smallest: &[u8],
+        largest: &[u8],
+    ) -> Vec<TableMeta> {
+        self.levels[level]
+            .iter()
+            .filter(|table| {
+                table.smallest.user_key.as_slice() <= largest
+                    && smallest <= table.largest.user_key.as_slice()
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn apply(&self, edit: &VersionEdit) -> Result<Version> {
+        let mut levels = self.levels.clone();
+        for (level, file_number) in &edit.deleted_files {
+            let files = levels
+                .get_mut(*level as usize)
+                .with_context(|| format!("edit deletes from level {}", level))?;
+            let before = files.len();
+            files.retain(|table| table.file_number != *file_number);
+            if files.len() == before {
+                bail!(
+                    "edit deletes table {} missing from level {}",
+                    file_number,
+                    level
+                );
+            }
+        }
+        for table in &edit.new_files {
+            levels
+                .get_mut(table.level as usize)
+                .with_context(|| format!("edit adds table to level {}", table.level))?
+                .push(table.clone());
+        }
+        for files in &mut levels[1..] {
+            files.sort_by(|a, b| a.smallest.cmp(&b.smallest));
+        }
+        levels[0].sort_by_key(|table| table.file_number);
+        Ok(Version { levels })
+    }
+}
+
+struct VersionSetState {
+    current: Arc<Version>,
+    // every version handed out, dropped ones are pruned when files are collected
+    versions: Vec<Weak<Version>>,
+    // tables edits dropped that may still be read through an older version
+    obsolete: Vec<u64>,
+    manifest: File,
+    manifest_number: u64,
+    next_file_number: u64,
+    log_number: Option<u64>,
+    last_sequence: u64,
+}
+
+/// The current `Version` of the database plus the MANIFEST it is logged in.
+///
+/// The MANIFEST is an append-only log of `VersionEdit`s and `CURRENT` names the live one.
+/// Opening replays it and then starts a fresh MANIFEST holding a single edit with the whole
+/// state, so the log does not grow across restarts. `CURRENT` is only swapped once the new
+/// MANIFEST is synced, a crash in between leaves the old one in charge.
+pub struct VersionSet {
+    folder: PathBuf,
+    state: Mutex<VersionSetState>,
+}
+
+impl VersionSet {
+    pub fn open(folder: impl AsRef<Path>) -> Result<Self> {
+        let folder = folder.as_ref().to_path_buf();
+        fs::create_dir_all(&folder)?;
+        let mut version = Version::default();
+        let mut next_file_number = 1;
+        let mut log_number = None;
+        let mut last_sequence = 0;
+        let current_path = folder.join(CURRENT_FILE);
+        if current_path.exists() {
+            let current = fs::read_to_string(&current_path)?;
+            let manifest_path = folder.join(current.trim_end());
+            for edit in Self::replay(&manifest_path)? {
+                version = version
+                    .apply(&edit)
+                    .with_context(|| format!("Failed to replay MANIFEST: {:?}", manifest_path))?;
+                next_file_number = edit.next_file_number.unwrap_or(next_file_number);
+                log_number = edit.log_number.or(log_number);
+                last_sequence = edit.last_sequence.unwrap_or(last_sequence);
+            }
+            next_file_number = version
+                .all_files()
+                .map(|table| table.file_number + 1)
+                .fold(next_file_number, u64::max);
+        }
+
+        let manifest_number = next_file_number;
+        next_file_number += 1;
+        let snapshot = VersionEdit {
+            log_number,
+            next_file_number: Some(next_file_number),
+            last_sequence: Some(last_sequence),
+            deleted_files: Vec::new(),
+            new_files: version.all_files().cloned().collect(),
+        };
+        let manifest_name = filename::manifest_file_name(manifest_number);
+        // truncates a MANIFEST a crash left behind before `CURRENT` pointed at it
+        let mut manifest = File::create(folder.join(&manifest_name))?;
+        manifest.write_all(&snapshot.encode())?;
+        manifest.sync_all()?;
+        let tmp_path = folder.join(format!("{}.tmp", CURRENT_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(format!("{}\n", manifest_name).as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &current_path)?;
+        File::open(&folder)?.sync_all()?;
+
+        // the replayed MANIFEST and any older ones
+        for entry in fs::read_dir(&folder)? {
+            let path = entry?.path();
+            if filename::parse_manifest_file_name(&path).is_some_and(|n| n != manifest_number) {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let current = Arc::new(version);
+        Ok(VersionSet {
+            folder,
+            state: Mutex::new(VersionSetState {
+                versions: vec![Arc::downgrade(&current)],
+                current,
+                obsolete: Vec::new(),
+                manifest,
+                manifest_number,
+                next_file_number,
+                log_number,
+                last_sequence,
+            }),
+        })
+    }
+
+    /// Edits logged in the MANIFEST at `path`. A torn edit at the tail is what a crash during
+    /// `log_and_apply` leaves behind and is dropped, it was never acknowledged.
+    fn replay(path: &Path) -> Result<Vec<VersionEdit>> {
+        let mut buffer = Vec::new();
+        File::open(path)
+            .with_context(|| format!("Failed to open MANIFEST: {:?}", path))?
+            .read_to_end(&mut buffer)?;
+        let mut edits = Vec::new();
+        let mut offset = 0;
+        while offset < buffer.len() {
+            let Some((edit, len)) = VersionEdit::decode(&buffer[offset..]) else {
+                eprintln!("Warning: Torn MANIFEST edit at position {}", offset);
+                break;
+            };
+            edits.push(edit);
+            offset += len;
+        }
+        Ok(edits)
+    }
+
+    /// Durably logs `edit` to the MANIFEST and installs the version it produces. The next file
+    /// number is always logged along, so file numbers handed out before a crash are not reused.
+    pub fn log_and_apply(&self, mut edit: VersionEdit) -> Result<Arc<Version>> {
+        let mut state = self.state.lock();
+        let version = state.current.apply(&edit)?;
+        edit.next_file_number = Some(state.next_file_number);
+        if let Some(seq) = edit.log_number
+            && state.log_number.is_some_and(|log_number| seq < log_number)
+        {
+            bail!(
+                "log number {} is behind the logged {:?}",
+                seq,
+                state.log_number
+            );
+        }
+        state
+            .manifest
+            .write_all(&edit.encode())
+            .and_then(|()| state.manifest.sync_data())
+            .with_context(|| {
+                format!(
+                    "Failed to append to MANIFEST: {:?}",
+                    self.folder
+                        .join(filename::manifest_file_name(state.manifest_number))
+                )
+            })?;
+
+        state.log_number = edit.log_number.or(state.log_number);
+        if let Some(seq) = edit.last_sequence {
+            state.last_sequence = state.last_sequence.max(seq);
+        }
+        let deleted = edit
+            .deleted_files
+            .iter()
+            .map(|(_, file_number)| *file_number);
+        state.obsolete.extend(deleted);
+        let version = Arc::new(version);
+        state.versions.push(Arc::downgrade(&version));
+        state.current = version.clone();
+        Ok(version)
+    }
+
+    /// The latest version. Holding on to it keeps its tables from being deleted.
+    pub fn current(&self) -> Arc<Version> {
+        self.state.lock().current.clone()
+    }
+
+    pub fn new_file_number(&self) -> u64 {
+        let mut state = self.state.lock();
+        let number = state.next_file_number;
+        state.next_file_number += 1;
+        number
+    }
+
+    pub fn log_number(&self) -> Option<u64> {
+        self.state.lock().log_number
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.state.lock().last_sequence
+    }
+
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    /// File numbers of the tables some live version lists.
+    pub fn live_files(&self) -> HashSet<u64> {
+        let mut state = self.state.lock();
+        Self::collect_live_files(&mut state)
+    }
+
+    fn collect_live_files(state: &mut VersionSetState) -> HashSet<u64> {
+        state.versions.retain(|version| version.strong_count() > 0);
+        state
+            .versions
+            .iter()
+            .filter_map(Weak::upgrade)
+            .flat_map(|version| {
+                version
+                    .all_files()
+                    .map(|table| table.file_number)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Deletes the tables edits dropped that no live version lists anymore. Returns their file
+    /// numbers, tables still read through an older version are left for a later call.
+    pub fn delete_obsolete_files(&self) -> Result<Vec<u64>> {
+        let mut state = self.state.lock();
+        let live = Self::collect_live_files(&mut state);
+        let (in_use, deletable): (Vec<_>, Vec<_>) =
+            state.obsolete.iter().partition(|n| live.contains(n));
+        state.obsolete = in_use;
+        drop(state);
+        for file_number in &deletable {
+            let path = filename::table_file_name(&self.folder, *file_number);
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).context(format!("failed to delete obsolete table: {:?}", path));
+                }
+            }
+        }
+        Ok(deletable)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memtable::key::{InternalKey, ValueType};
+
+    fn table(file_number: u64, level: u32, smallest: &str, largest: &str) -> TableMeta {
+        TableMeta {
+            file_number,
+            level,
+            file_size: 100 * file_number,
+            smallest: InternalKey::new(smallest, file_number, ValueType::Value),
+            largest: InternalKey::new(largest, file_number, ValueType::Value),
+            smallest_seq: file_number,
+            largest_seq: file_number,
+        }
+    }
+
+    #[test]
+    fn test_reopen_rebuilds_file_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = VersionSet::open(dir.path()).unwrap();
+        let mut numbers = Vec::new();
+        for _ in 0..3 {
+            numbers.push(versions.new_file_number());
+        }
+        let mut edit = VersionEdit {
+            log_number: Some(10),
+            last_sequence: Some(10),
+            ..Default::default()
+        };
+        edit.add_file(table(numbers[0], 0, "a", "k"));
+        edit.add_file(table(numbers[1], 0, "c", "z"));
+        versions.log_and_apply(edit).unwrap();
+        let mut edit = VersionEdit::default();
+        edit.delete_file(0, numbers[0]);
+        edit.delete_file(0, numbers[1]);
+        edit.add_file(table(numbers[2], 1, "a", "z"));
+        let current = versions.log_and_apply(edit).unwrap();
+        assert_eq!(current.num_files(0), 0);
+        assert!(
+            versions
+                .log_and_apply({
+                    let mut edit = VersionEdit::default();
+                    edit.delete_file(0, numbers[0]);
+                    edit
+                })
+                .is_err()
+        );
+        drop(versions);
+
+        let versions = VersionSet::open(dir.path()).unwrap();
+        let current = versions.current();
+        assert_eq!(current.files(1), &[table(numbers[2], 1, "a", "z")]);
+        assert_eq!(current.level_size(1), 100 * numbers[2]);
+        assert_eq!(versions.log_number(), Some(10));
+        assert_eq!(versions.last_sequence(), 10);
+        assert!(versions.new_file_number() > numbers[2]);
+        // the MANIFEST was rewritten and the old one removed
+        let manifests = fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                filename::parse_manifest_file_name(&entry.as_ref().unwrap().path()).is_some()
+            })
+            .count();
+        assert_eq!(manifests, 1);
+    }
+
+    #[test]
+    fn test_files_of_referenced_versions_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = VersionSet::open(dir.path()).unwrap();
+        let number = versions.new_file_number();
+        fs::write(filename::table_file_name(dir.path(), number), b"table").unwrap();
+        let mut edit = VersionEdit::default();
+        edit.add_file(table(number, 0, "a", "b"));
+        let reader = versions.log_and_apply(edit).unwrap();
+
+        let mut edit = VersionEdit::default();
+        edit.delete_file(0, number);
+        versions.log_and_apply(edit).unwrap();
+        assert!(versions.delete_obsolete_files().unwrap().is_empty());
+        assert!(filename::table_file_name(dir.path(), number).exists());
+
+        drop(reader);
+        assert_eq!(versions.delete_obsolete_files().unwrap(), vec![number]);
+        assert!(!filename::table_file_name(dir.path(), number).exists());
+    }
+}
@@ -0,0 +1,111 @@
+use std::cmp::Ordering;
+
+/// Largest sequence number that fits in the trailer next to the value type.
+pub const MAX_SEQUENCE_NUMBER: u64 = (1 << 56) - 1;
+const TRAILER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum ValueType {
+    Deletion = 0,
+    Value = 1,
+    /// the entry's value is the exclusive end of the deleted range
+    RangeDeletion = 2,
+}
+
+impl ValueType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ValueType::Deletion),
+            1 => Some(ValueType::Value),
+            2 => Some(ValueType::RangeDeletion),
+            _ => None,
+        }
+    }
+}
+
+/// A user key tagged with the sequence number that wrote it and what was written.
+///
+/// Keys order by user key ascending, then by sequence number descending, so the newest version of
+/// a key comes first and a lookup at a snapshot can seek straight to it. Encoded as
+/// `user_key | trailer: u64` with the trailer `seq << 8 | value_type` in big endian.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InternalKey {
+    pub user_key: Vec<u8>,
+    pub seq: u64,
+    pub value_type: ValueType,
+}
+
+impl InternalKey {
+    pub fn new(user_key: impl Into<Vec<u8>>, seq: u64, value_type: ValueType) -> Self {
+        debug_assert!(
+            seq <= MAX_SEQUENCE_NUMBER,
+            "sequence number overflows trailer"
+        );
+        InternalKey {
+            user_key: user_key.into(),
+            seq,
+            value_type,
+        }
+    }
+
+    /// Sorts before every version of `user_key` visible at `snapshot`.
+    pub fn lookup(user_key: &[u8], snapshot: u64) -> Self {
+        InternalKey::new(user_key, snapshot, ValueType::RangeDeletion)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    pub fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.user_key);
+        buf.extend_from_slice(&((self.seq << 8) | self.value_type as u64).to_be_bytes());
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.user_key.len() + TRAILER_SIZE
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let split = buf.len().checked_sub(TRAILER_SIZE)?;
+        let trailer = u64::from_be_bytes(buf[split..].try_into().ok()?);
+        Some(InternalKey {
+            user_key: buf[..split].to_vec(),
+            seq: trailer >> 8,
+            value_type: ValueType::from_u8(trailer as u8)?,
+        })
+    }
+}
+
+impl Ord for InternalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.user_key
+            .cmp(&other.user_key)
+            .then_with(|| other.seq.cmp(&self.seq))
+            .then_with(|| other.value_type.cmp(&self.value_type))
+    }
+}
+
+impl PartialOrd for InternalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders encoded internal keys the way `InternalKey` orders decoded ones. Bytes that do not
+/// decode sort by their raw bytes.
+pub fn compare_encoded(a: &[u8], b: &[u8]) -> Ordering {
+    match (
+        a.len().checked_sub(TRAILER_SIZE),
+        b.len().checked_sub(TRAILER_SIZE),
+    ) {
+        // big endian trailers compare like the numbers, newer first
+        (Some(a_split), Some(b_split)) => a[..a_split]
+            .cmp(&b[..b_split])
+            .then_with(|| b[b_split..].cmp(&a[a_split..])),
+        _ => a.cmp(b),
+    }
+}
@@ -0,0 +1,3 @@
+pub mod key;
+pub mod list;
+pub mod memtable;
@@ -0,0 +1,202 @@
+use anyhow::Result;
+use parking_lot::{Mutex, RwLock};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use wal::backpressure::WriteController;
+use wal::record::WalRecord;
+use wal::wal::RecoveredWindow;
+
+use crate::memtable::{Lookup, Memtable};
+
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct MemtableOptions {
+    /// approximate bytes the active memtable takes before it is frozen
+    pub write_buffer_size: usize,
+    /// told about the number of immutable memtables waiting for a flush, so writers slow down
+    /// when flushes fall behind
+    pub write_controller: Option<Arc<WriteController>>,
+}
+
+impl Default for MemtableOptions {
+    fn default() -> Self {
+        MemtableOptions {
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            write_controller: None,
+        }
+    }
+}
+
+/// The active memtable taking writes plus the frozen ones queued for a flush, oldest first.
+///
+/// Writes go in whole WAL windows so a memtable always covers complete segments. After a window
+/// pushes the active memtable past `write_buffer_size` it is frozen and a fresh one takes over.
+pub struct MemtableList {
+    options: MemtableOptions,
+    next_id: AtomicU64,
+    active: RwLock<Arc<Memtable>>,
+    immutable: Mutex<VecDeque<Arc<Memtable>>>,
+}
+
+impl MemtableList {
+    pub fn new(options: MemtableOptions) -> Self {
+        MemtableList {
+            options,
+            next_id: AtomicU64::new(1),
+            active: RwLock::new(Arc::new(Memtable::new(0))),
+            immutable: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Replays recovered windows, freezing along the way as live writes would.
+    pub fn recover(options: MemtableOptions, windows: &[RecoveredWindow]) -> Result<Self> {
+        let memtables = Self::new(options);
+        for window in windows {
+            let (seq_start, seq_end) = window.seq_range();
+            memtables.apply_window(seq_start, seq_end, window.records())?;
+        }
+        Ok(memtables)
+    }
+
+    pub fn active(&self) -> Arc<Memtable> {
+        self.active.read().clone()
+    }
+
+    /// Applies a durable WAL window to the active memtable. Returns the memtable frozen because
+    /// of it, if any.
+    pub fn apply_window(
+        &self,
+        seq_start: u64,
+        seq_end: u64,
+        records: &[WalRecord],
+    ) -> Result<Option<Arc<Memtable>>> {
+        let full = {
+            let active = self.active.read();
+            active.apply_window(seq_start, seq_end, records)?;
+            active.approximate_size() >= self.options.write_buffer_size
+        };
+        Ok(if full { self.freeze() } else { None })
+    }
+
+    /// Moves the active memtable to the immutable queue and starts a new one. Returns `None` when
+    /// the active memtable is empty and there is nothing to freeze.
+    pub fn freeze(&self) -> Option<Arc<Memtable>> {
+        let mut active = self.active.write();
+        if active.is_empty() {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let frozen = std::mem::replace(&mut *active, Arc::new(Memtable::new(id)));
+        let mut immutable = self.immutable.lock();
+        immutable.push_back(frozen.clone());
+        self.report_immutable_count(immutable.len());
+        Some(frozen)
+    }
+
+    /// Immutable memtables, oldest first.
+    pub fn immutable(&self) -> Vec<Arc<Memtable>> {
+        self.immutable.lock().iter().cloned().collect()
+    }
+
+    pub fn immutable_count(&self) -> usize {
+        self.immutable.lock().len()
+    }
+
+    /// The next memtable to flush.
+    pub fn oldest_immutable(&self) -> Option<Arc<Memtable>> {
+        self.immutable.lock().front().cloned()
+    }
+
+    /// Drops the immutable memtable `id` once its table is durable. Returns false if it was not
+    /// queued.
+    pub fn remove_flushed(&self, id: u64) -> bool {
+        let mut immutable = self.immutable.lock();
+        let Some(position) = immutable.iter().position(|memtable| memtable.id() == id) else {
+            return false;
+        };
+        immutable.remove(position);
+        self.report_immutable_count(immutable.len());
+        true
+    }
+
+    fn report_immutable_count(&self, count: usize) {
+        if let Some(controller) = &self.options.write_controller {
+            controller.set_memtable_count(count);
+        }
+    }
+
+    /// Newest version of `key` at `snapshot` across the active and the immutable memtables.
+    pub fn get(&self, key: &[u8], snapshot: u64) -> Option<Lookup> {
+        if let Some(lookup) = self.active().get(key, snapshot) {
+            return Some(lookup);
+        }
+        let immutable = self.immutable();
+        immutable
+            .iter()
+            .rev()
+            .find_map(|memtable| memtable.get(key, snapshot))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wal::backpressure::{BackpressureOptions, StallReason, WriteState};
+
+    fn put(key: &[u8], value: &[u8]) -> WalRecord {
+        WalRecord::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_freezes_by_size_and_keeps_window_ranges() {
+        let controller = Arc::new(WriteController::new(BackpressureOptions {
+            soft_memtable_count: 2,
+            hard_memtable_count: 3,
+            ..Default::default()
+        }));
+        let memtables = MemtableList::new(MemtableOptions {
+            write_buffer_size: 100,
+            write_controller: Some(controller.clone()),
+        });
+        assert!(memtables.freeze().is_none());
+
+        let value = vec![7u8; 60];
+        assert!(
+            memtables
+                .apply_window(0, 1, &[put(b"a", &value)])
+                .unwrap()
+                .is_none()
+        );
+        let frozen = memtables
+            .apply_window(2, 4, &[put(b"b", &value)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(frozen.seq_range(), Some((0, 4)));
+        memtables.apply_window(5, 5, &[put(b"a", b"new")]).unwrap();
+        let frozen = memtables
+            .apply_window(6, 6, &[put(b"c", &value)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(frozen.seq_range(), Some((5, 6)));
+        assert_eq!(memtables.immutable_count(), 2);
+        assert_eq!(
+            controller.state(),
+            WriteState::Delayed(StallReason::MemtableCount)
+        );
+
+        assert_eq!(memtables.get(b"a", 6), Some(Lookup::Found(b"new".to_vec())));
+        assert_eq!(memtables.get(b"a", 4), Some(Lookup::Found(value.clone())));
+        assert_eq!(memtables.get(b"d", 6), None);
+
+        let oldest = memtables.oldest_immutable().unwrap();
+        assert_eq!(oldest.seq_range(), Some((0, 4)));
+        assert!(memtables.remove_flushed(oldest.id()));
+        assert!(!memtables.remove_flushed(oldest.id()));
+        assert_eq!(controller.state(), WriteState::Normal);
+    }
+}
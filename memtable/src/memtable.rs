@@ -0,0 +1,259 @@
+use anyhow::{Result, bail};
+use crossbeam_skiplist::SkipMap;
+use parking_lot::Mutex;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use wal::record::WalRecord;
+
+use crate::key::{InternalKey, ValueType};
+
+// skiplist node, sequence number and value type on top of the key and value bytes
+const ENTRY_OVERHEAD: usize = 32;
+
+/// What a memtable knows about a key at a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Found(Vec<u8>),
+    /// deleted by a tombstone or a range deletion, older tables must not be consulted
+    Deleted,
+}
+
+/// Sorted in-memory buffer of the most recent writes, keyed by internal key.
+///
+/// Every write carries the sequence number the WAL assigned it, and the memtable remembers the
+/// range of WAL windows it holds. Once it is flushed to a table, exactly the segments for that
+/// range can be released. Range deletions are kept apart from point entries, keyed by their start
+/// with the exclusive end as the value, since a lookup has to check all of them that start before
+/// the key.
+pub struct Memtable {
+    id: u64,
+    table: SkipMap<InternalKey, Vec<u8>>,
+    range_deletions: SkipMap<InternalKey, Vec<u8>>,
+    approximate_size: AtomicUsize,
+    // first and last WAL sequence number covered, `u64::MAX` and 0 while empty
+    seq_start: AtomicU64,
+    seq_end: AtomicU64,
+    // WAL windows applied, in order
+    windows: Mutex<Vec<(u64, u64)>>,
+}
+
+impl Memtable {
+    pub fn new(id: u64) -> Self {
+        Memtable {
+            id,
+            table: SkipMap::new(),
+            range_deletions: SkipMap::new(),
+            approximate_size: AtomicUsize::new(0),
+            seq_start: AtomicU64::new(u64::MAX),
+            seq_end: AtomicU64::new(0),
+            windows: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Increasing with every memtable created, newer memtables have higher ids.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn put(&self, seq: u64, key: &[u8], value: &[u8]) {
+        self.insert(InternalKey::new(key, seq, ValueType::Value), value.to_vec());
+    }
+
+    pub fn delete(&self, seq: u64, key: &[u8]) {
+        self.insert(InternalKey::new(key, seq, ValueType::Deletion), Vec::new());
+    }
+
+    /// Deletes every key in `[start, end)` written before `seq`.
+    pub fn delete_range(&self, seq: u64, start: &[u8], end: &[u8]) {
+        let key = InternalKey::new(start, seq, ValueType::RangeDeletion);
+        self.approximate_size
+            .fetch_add(start.len() + end.len() + ENTRY_OVERHEAD, Ordering::Relaxed);
+        self.range_deletions.insert(key, end.to_vec());
+        self.extend_seq_range(seq, seq);
+    }
+
+    fn insert(&self, key: InternalKey, value: Vec<u8>) {
+        let seq = key.seq;
+        self.approximate_size.fetch_add(
+            key.user_key.len() + value.len() + ENTRY_OVERHEAD,
+            Ordering::Relaxed,
+        );
+        self.table.insert(key, value);
+        self.extend_seq_range(seq, seq);
+    }
+
+    /// Applies the records of the WAL window `[seq_start, seq_end]`, the i-th record taking
+    /// `seq_start + i`. Batch markers and checkpoints carry no data and are skipped.
+    pub fn apply_window(&self, seq_start: u64, seq_end: u64, records: &[WalRecord]) -> Result<()> {
+        if seq_start > seq_end {
+            bail!("invalid sequence range [{}, {}]", seq_start, seq_end);
+        }
+        let data_records: Vec<_> = records
+            .iter()
+            .filter(|record| {
+                matches!(
+                    record,
+                    WalRecord::Put { .. }
+                        | WalRecord::Delete { .. }
+                        | WalRecord::DeleteRange { .. }
+                )
+            })
+            .collect();
+        if data_records.len() as u64 > seq_end - seq_start + 1 {
+            bail!(
+                "window [{}, {}] holds {} records, more than it has sequence numbers",
+                seq_start,
+                seq_end,
+                data_records.len()
+            );
+        }
+        for (seq, record) in (seq_start..).zip(data_records) {
+            match record {
+                WalRecord::Put { key, value } => self.put(seq, key, value),
+                WalRecord::Delete { key } => self.delete(seq, key),
+                WalRecord::DeleteRange { start, end } => self.delete_range(seq, start, end),
+                _ => unreachable!("filtered above"),
+            }
+        }
+        self.extend_seq_range(seq_start, seq_end);
+        self.windows.lock().push((seq_start, seq_end));
+        Ok(())
+    }
+
+    fn extend_seq_range(&self, seq_start: u64, seq_end: u64) {
+        self.seq_start.fetch_min(seq_start, Ordering::SeqCst);
+        self.seq_end.fetch_max(seq_end, Ordering::SeqCst);
+    }
+
+    /// Newest version of `key` visible at `snapshot`, `None` when this memtable does not know the
+    /// key and older data has to be checked.
+    pub fn get(&self, key: &[u8], snapshot: u64) -> Option<Lookup> {
+        let point = self
+            .table
+            .lower_bound(Bound::Included(&InternalKey::lookup(key, snapshot)))
+            .filter(|entry| entry.key().user_key == key);
+        let point_seq = point.as_ref().map(|entry| entry.key().seq);
+        // every version of `key` sorts before its version 0 deletion, so this covers exactly the
+        // ranges starting at or before it
+        let last_start = InternalKey::new(key, 0, ValueType::Deletion);
+        let range_deleted = self.range_deletions.range(..=last_start).any(|entry| {
+            let start = entry.key();
+            key < entry.value().as_slice()
+                && start.seq <= snapshot
+                && point_seq.is_none_or(|seq| start.seq > seq)
+        });
+        if range_deleted {
+            return Some(Lookup::Deleted);
+        }
+        let entry = point?;
+        match entry.key().value_type {
+            ValueType::Value => Some(Lookup::Found(entry.value().clone())),
+            ValueType::Deletion | ValueType::RangeDeletion => Some(Lookup::Deleted),
+        }
+    }
+
+    /// Point entries in internal key order, the input of a flush.
+    pub fn iter(&self) -> impl Iterator<Item = (InternalKey, Vec<u8>)> + '_ {
+        self.table
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+    }
+
+    /// Range deletions in internal key order, each with the exclusive end of its range.
+    pub fn range_deletions(&self) -> impl Iterator<Item = (InternalKey, Vec<u8>)> + '_ {
+        self.range_deletions
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.approximate_size.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len() + self.range_deletions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0 && self.seq_range().is_none()
+    }
+
+    /// First and last WAL sequence number this memtable covers.
+    pub fn seq_range(&self) -> Option<(u64, u64)> {
+        let seq_start = self.seq_start.load(Ordering::SeqCst);
+        let seq_end = self.seq_end.load(Ordering::SeqCst);
+        (seq_start <= seq_end).then_some((seq_start, seq_end))
+    }
+
+    /// WAL windows applied through `apply_window`, in order. These are the segments a flush of
+    /// this memtable releases.
+    pub fn windows(&self) -> Vec<(u64, u64)> {
+        self.windows.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_get_sees_newest_version_at_snapshot() {
+        let memtable = Memtable::new(1);
+        memtable.put(1, b"a", b"v1");
+        memtable.put(3, b"a", b"v3");
+        memtable.delete(5, b"a");
+        memtable.put(2, b"b", b"v2");
+        memtable.delete_range(4, b"b", b"c");
+
+        assert_eq!(memtable.get(b"a", 0), None);
+        assert_eq!(memtable.get(b"a", 2), Some(Lookup::Found(b"v1".to_vec())));
+        assert_eq!(memtable.get(b"a", 4), Some(Lookup::Found(b"v3".to_vec())));
+        assert_eq!(memtable.get(b"a", 5), Some(Lookup::Deleted));
+        assert_eq!(memtable.get(b"b", 3), Some(Lookup::Found(b"v2".to_vec())));
+        assert_eq!(memtable.get(b"b", 4), Some(Lookup::Deleted));
+        assert_eq!(memtable.get(b"bb", 4), Some(Lookup::Deleted));
+        assert_eq!(memtable.get(b"c", 9), None);
+        assert_eq!(memtable.seq_range(), Some((1, 5)));
+        // ranges starting after the key are not looked at, one starting at it applies
+        memtable.delete_range(6, b"d", b"f");
+        assert_eq!(memtable.get(b"cz", 9), None);
+        assert_eq!(memtable.get(b"d", 6), Some(Lookup::Deleted));
+        assert_eq!(memtable.get(b"e", 5), None);
+
+        let keys: Vec<_> = memtable
+            .iter()
+            .map(|(key, _)| (key.user_key, key.seq))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (b"a".to_vec(), 5),
+                (b"a".to_vec(), 3),
+                (b"a".to_vec(), 1),
+                (b"b".to_vec(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_window_assigns_sequence_numbers() {
+        let memtable = Memtable::new(1);
+        let records = vec![
+            WalRecord::BatchBegin { batch_id: 1 },
+            WalRecord::Put {
+                key: b"k".to_vec(),
+                value: b"v".to_vec(),
+            },
+            WalRecord::Delete { key: b"k".to_vec() },
+            WalRecord::BatchCommit { batch_id: 1 },
+        ];
+        memtable.apply_window(10, 14, &records).unwrap();
+        assert_eq!(memtable.get(b"k", 10), Some(Lookup::Found(b"v".to_vec())));
+        assert_eq!(memtable.get(b"k", 11), Some(Lookup::Deleted));
+        // the window range is covered even though only two of its numbers were used
+        assert_eq!(memtable.seq_range(), Some((10, 14)));
+        assert!(memtable.apply_window(20, 20, &records).is_err());
+        // a rejected window is not recorded
+        assert_eq!(memtable.windows(), vec![(10, 14)]);
+    }
+}
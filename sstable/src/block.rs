@@ -0,0 +1,396 @@
+use bytes::BufMut;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+// shared | non_shared | value_len
+const ENTRY_HEADER_SIZE: usize = 4 + 4 + 4;
+
+/// Orders the keys of a block, internal key order for data and index blocks and plain bytes for
+/// the name keyed meta blocks.
+pub type Comparator = fn(&[u8], &[u8]) -> Ordering;
+
+/// Builds a block of sorted entries with prefix compressed keys:
+///
+/// `entry* | restart: u32 * num_restarts | num_restarts: u32`
+///
+/// where each entry is `shared: u32 | non_shared: u32 | value_len: u32 | key[shared..] | value`.
+/// Every `restart_interval` entries the key is stored in full and its offset recorded as a
+/// restart point, so a reader can binary search the restarts and only scan forward from there.
+pub struct BlockBuilder {
+    restart_interval: usize,
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    // entries since the last restart point
+    counter: usize,
+    last_key: Vec<u8>,
+    entries: usize,
+}
+
+impl BlockBuilder {
+    pub fn new(restart_interval: usize) -> Self {
+        assert!(restart_interval > 0, "restart interval must be positive");
+        BlockBuilder {
+            restart_interval,
+            buf: Vec::new(),
+            restarts: vec![0],
+            counter: 0,
+            last_key: Vec::new(),
+            entries: 0,
+        }
+    }
+
+    /// Keys have to be added in order, the table builder checks that.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.counter < self.restart_interval {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            self.restarts.push(self.buf.len() as u32);
+            self.counter = 0;
+            0
+        };
+        self.buf.put_u32(shared as u32);
+        self.buf.put_u32((key.len() - shared) as u32);
+        self.buf.put_u32(value.len() as u32);
+        self.buf.put_slice(&key[shared..]);
+        self.buf.put_slice(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.counter += 1;
+        self.entries += 1;
+    }
+
+    /// Appends the restart array and returns the block contents, leaving the builder empty.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.buf);
+        for restart in &self.restarts {
+            buf.put_u32(*restart);
+        }
+        buf.put_u32(self.restarts.len() as u32);
+        self.restarts = vec![0];
+        self.counter = 0;
+        self.last_key.clear();
+        self.entries = 0;
+        buf
+    }
+
+    /// Size of the block if it were finished now.
+    pub fn size_estimate(&self) -> usize {
+        self.buf.len() + (self.restarts.len() + 1) * 4
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+}
+
+/// A decoded block, validated once when it is read so iterating it cannot run off its end.
+pub struct Block {
+    data: Vec<u8>,
+    restarts_offset: usize,
+    num_restarts: usize,
+}
+
+fn read_u32(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+impl Block {
+    /// Checks the restart array and walks every entry, so a corrupt block is refused here and
+    /// not halfway through an iteration. Returns what is wrong with it otherwise.
+    pub fn new(data: Vec<u8>) -> Result<Self, String> {
+        let num_restarts = data
+            .len()
+            .checked_sub(4)
+            .and_then(|at| read_u32(&data, at))
+            .ok_or("block too short for a restart count")? as usize;
+        let restarts_offset = num_restarts
+            .checked_mul(4)
+            .and_then(|restarts| data.len().checked_sub(4 + restarts))
+            .filter(|_| num_restarts > 0)
+            .ok_or_else(|| format!("invalid restart count {}", num_restarts))?;
+        let block = Block {
+            data,
+            restarts_offset,
+            num_restarts,
+        };
+        let mut restarts = (0..num_restarts).map(|i| block.restart(i)).peekable();
+        let mut offset = 0;
+        let mut last_key_len = 0;
+        while offset < restarts_offset {
+            let (shared, non_shared, value_len) = block
+                .entry_header(offset)
+                .ok_or_else(|| format!("entry at {} overruns the block", offset))?;
+            let is_restart = restarts.peek() == Some(&offset);
+            if is_restart {
+                restarts.next();
+            }
+            if shared > last_key_len || (is_restart && shared != 0) {
+                return Err(format!("entry at {} shares {} key bytes", offset, shared));
+            }
+            let end = offset + ENTRY_HEADER_SIZE + non_shared + value_len;
+            if end > restarts_offset {
+                return Err(format!("entry at {} overruns the block", offset));
+            }
+            last_key_len = shared + non_shared;
+            offset = end;
+        }
+        // an empty block, like the index of a table holding only range deletions, keeps its
+        // single restart at 0 without an entry there
+        let empty = restarts_offset == 0 && num_restarts == 1;
+        if restarts
+            .next()
+            .is_some_and(|restart| !(empty && restart == 0))
+        {
+            return Err("restart point is not at an entry".to_string());
+        }
+        Ok(block)
+    }
+
+    fn restart(&self, i: usize) -> usize {
+        read_u32(&self.data, self.restarts_offset + i * 4).unwrap_or(0) as usize
+    }
+
+    fn entry_header(&self, offset: usize) -> Option<(usize, usize, usize)> {
+        Some((
+            read_u32(&self.data, offset)? as usize,
+            read_u32(&self.data, offset + 4)? as usize,
+            read_u32(&self.data, offset + 8)? as usize,
+        ))
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn iter(self: &Arc<Self>, comparator: Comparator) -> BlockIter {
+        BlockIter {
+            block: self.clone(),
+            comparator,
+            current: self.restarts_offset,
+            next_offset: 0,
+            restart_index: 0,
+            key: Vec::new(),
+            value: 0..0,
+        }
+    }
+}
+
+/// Cursor over the entries of a block. Starts out invalid, position it with one of the seeks.
+pub struct BlockIter {
+    block: Arc<Block>,
+    comparator: Comparator,
+    // offset of the current entry, `restarts_offset` when the iterator is not on one
+    current: usize,
+    next_offset: usize,
+    // restart point the current entry belongs to
+    restart_index: usize,
+    key: Vec<u8>,
+    value: std::ops::Range<usize>,
+}
+
+impl BlockIter {
+    pub fn valid(&self) -> bool {
+        self.current < self.block.restarts_offset
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.block.data[self.value.clone()]
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.seek_to_restart(0);
+        self.parse_next_entry();
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.seek_to_restart(self.block.num_restarts - 1);
+        while self.parse_next_entry() && self.next_offset < self.block.restarts_offset {}
+    }
+
+    /// Positions at the first key at or after `target`, invalid if there is none.
+    pub fn seek(&mut self, target: &[u8]) {
+        // last restart point whose key sorts before the target, restart keys are stored in full
+        let (mut left, mut right) = (0, self.block.num_restarts - 1);
+        while left < right {
+            let mid = (left + right).div_ceil(2);
+            self.seek_to_restart(mid);
+            self.parse_next_entry();
+            if (self.comparator)(&self.key, target) == Ordering::Less {
+                left = mid;
+            } else {
+                right = mid - 1;
+            }
+        }
+        self.seek_to_restart(left);
+        while self.parse_next_entry() {
+            if (self.comparator)(&self.key, target) != Ordering::Less {
+                return;
+            }
+        }
+    }
+
+    pub fn next(&mut self) {
+        if self.valid() {
+            self.parse_next_entry();
+        }
+    }
+
+    pub fn prev(&mut self) {
+        if !self.valid() {
+            return;
+        }
+        let original = self.current;
+        while self.block.restart(self.restart_index) >= original {
+            if self.restart_index == 0 {
+                // already on the first entry
+                self.current = self.block.restarts_offset;
+                return;
+            }
+            self.restart_index -= 1;
+        }
+        self.seek_to_restart(self.restart_index);
+        while self.parse_next_entry() && self.next_offset < original {}
+    }
+
+    fn seek_to_restart(&mut self, index: usize) {
+        self.key.clear();
+        self.restart_index = index;
+        self.next_offset = self.block.restart(index);
+        self.current = self.block.restarts_offset;
+    }
+
+    /// Decodes the entry at `next_offset`. Returns false and becomes invalid at the end of the
+    /// block.
+    fn parse_next_entry(&mut self) -> bool {
+        let block = &self.block;
+        if self.next_offset >= block.restarts_offset {
+            self.current = block.restarts_offset;
+            return false;
+        }
+        self.current = self.next_offset;
+        let (shared, non_shared, value_len) = block
+            .entry_header(self.current)
+            .expect("validated when the block was read");
+        let key_start = self.current + ENTRY_HEADER_SIZE;
+        self.key.truncate(shared);
+        self.key
+            .extend_from_slice(&block.data[key_start..key_start + non_shared]);
+        let value_start = key_start + non_shared;
+        self.value = value_start..value_start + value_len;
+        self.next_offset = self.value.end;
+        while self.restart_index + 1 < block.num_restarts
+            && block.restart(self.restart_index + 1) <= self.current
+        {
+            self.restart_index += 1;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys_share_prefixes_between_restarts() {
+        let mut builder = BlockBuilder::new(2);
+        for key in [&b"apple"[..], b"applesauce", b"apricot"] {
+            builder.add(key, b"v");
+        }
+        let block = builder.finish();
+        let entry = |shared: u32, suffix: &[u8]| {
+            let mut buf = Vec::new();
+            buf.put_u32(shared);
+            buf.put_u32(suffix.len() as u32);
+            buf.put_u32(1);
+            buf.put_slice(suffix);
+            buf.put_u8(b'v');
+            buf
+        };
+        let mut expected = entry(0, b"apple");
+        expected.extend(entry(5, b"sauce"));
+        let restart = expected.len() as u32;
+        // the third key starts a new restart point and is stored in full
+        expected.extend(entry(0, b"apricot"));
+        expected.put_u32(0);
+        expected.put_u32(restart);
+        expected.put_u32(2);
+        assert_eq!(block, expected);
+        assert!(builder.is_empty());
+    }
+
+    #[test]
+    fn test_iterator_seeks_and_steps_both_ways() {
+        let mut builder = BlockBuilder::new(3);
+        let keys: Vec<_> = (0..10u32).map(|i| format!("key{:02}", i * 2)).collect();
+        for key in &keys {
+            builder.add(key.as_bytes(), key.as_bytes());
+        }
+        let block = Arc::new(Block::new(builder.finish()).unwrap());
+        let mut iter = block.iter(|a, b| a.cmp(b));
+        assert!(!iter.valid());
+
+        iter.seek(b"key07");
+        assert_eq!(iter.key(), b"key08");
+        assert_eq!(iter.value(), b"key08");
+        iter.prev();
+        assert_eq!(iter.key(), b"key06");
+        iter.seek(b"key99");
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        let mut seen = Vec::new();
+        while iter.valid() {
+            seen.push(String::from_utf8(iter.key().to_vec()).unwrap());
+            iter.prev();
+        }
+        seen.reverse();
+        assert_eq!(seen, keys);
+
+        iter.seek_to_first();
+        for key in &keys {
+            assert_eq!(iter.key(), key.as_bytes());
+            iter.next();
+        }
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_corrupt_block_is_refused() {
+        let mut builder = BlockBuilder::new(2);
+        builder.add(b"a", b"1");
+        builder.add(b"b", b"2");
+        let mut block = builder.finish();
+        assert!(Block::new(block.clone()).is_ok());
+        // non_shared of the second entry now points past the end
+        block[14 + 4..14 + 8].copy_from_slice(&1000u32.to_be_bytes());
+        assert!(Block::new(block).is_err());
+        assert!(Block::new(vec![0, 0]).is_err());
+        // a restart past the entries of an empty block is still refused
+        assert!(Block::new([0, 0, 0, 4, 0, 0, 0, 1].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_empty_block_has_no_entries() {
+        let block = Arc::new(Block::new(BlockBuilder::new(2).finish()).unwrap());
+        let mut iter = block.iter(|a, b| a.cmp(b));
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        iter.seek(b"a");
+        assert!(!iter.valid());
+    }
+}
@@ -0,0 +1,347 @@
+use anyhow::{Result, bail};
+use memtable::key::{self, InternalKey, ValueType};
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use wal::compression::CompressionType;
+
+use crate::block::BlockBuilder;
+use crate::error::SstableError;
+use crate::filter::{self, BloomFilterBuilder};
+use crate::format::{
+    self, BLOCK_TRAILER_SIZE, BlockHandle, Footer, METAINDEX_FILTER, METAINDEX_PROPERTIES,
+    METAINDEX_RANGE_DELETIONS, TABLE_VERSION,
+};
+use crate::properties::TableProperties;
+
+const DEFAULT_BLOCK_SIZE: usize = 4096;
+const DEFAULT_BLOCK_RESTART_INTERVAL: usize = 16;
+
+/// Reads the timestamp of a value, `None` for values without one.
+pub type TimestampExtractor = fn(&[u8]) -> Option<u64>;
+
+#[derive(Debug, Clone)]
+pub struct TableOptions {
+    /// uncompressed bytes a data block grows to before it is written out
+    pub block_size: usize,
+    /// entries between two keys stored in full
+    pub block_restart_interval: usize,
+    /// codec for data and meta blocks, blocks that do not compress well are stored as is
+    pub compression: CompressionType,
+    /// bits per key of the table's bloom filter over user keys, `None` writes no filter
+    pub filter_bits_per_key: Option<usize>,
+    /// records the timestamp range of the values in the table properties when set
+    pub timestamp_extractor: Option<TimestampExtractor>,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        TableOptions {
+            block_size: DEFAULT_BLOCK_SIZE,
+            block_restart_interval: DEFAULT_BLOCK_RESTART_INTERVAL,
+            compression: CompressionType::None,
+            filter_bits_per_key: Some(filter::DEFAULT_BITS_PER_KEY),
+            timestamp_extractor: None,
+        }
+    }
+}
+
+/// What `TableBuilder::finish` wrote.
+#[derive(Debug, Clone)]
+pub struct TableInfo {
+    pub path: PathBuf,
+    pub file_size: u64,
+    /// smallest and largest key of the table, range deletions included, `None` when it is empty
+    pub smallest: Option<InternalKey>,
+    pub largest: Option<InternalKey>,
+    pub properties: TableProperties,
+}
+
+/// Writes a sorted table:
+///
+/// `data block* | filter block? | properties block | range deletion block? | metaindex block |
+/// index block | footer`
+///
+/// Entries are internal keys in `InternalKey` order. The index block maps the last key of every
+/// data block to its handle, the metaindex block maps the names of the meta blocks to theirs and
+/// the footer points at both. Every block is followed by a trailer with its checksum.
+pub struct TableBuilder {
+    options: TableOptions,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    range_deletions: BlockBuilder,
+    filter: Option<BloomFilterBuilder>,
+    // last encoded key added, of point entries and of range deletions
+    last_key: Option<Vec<u8>>,
+    last_range_deletion: Option<Vec<u8>>,
+    smallest: Option<InternalKey>,
+    largest: Option<InternalKey>,
+    properties: TableProperties,
+}
+
+impl TableBuilder {
+    pub fn create(path: impl AsRef<Path>, options: TableOptions) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path)?;
+        Ok(TableBuilder {
+            data_block: BlockBuilder::new(options.block_restart_interval),
+            index_block: BlockBuilder::new(1),
+            range_deletions: BlockBuilder::new(1),
+            filter: options.filter_bits_per_key.map(BloomFilterBuilder::new),
+            options,
+            path,
+            writer: BufWriter::new(file),
+            offset: 0,
+            last_key: None,
+            last_range_deletion: None,
+            smallest: None,
+            largest: None,
+            properties: TableProperties::new(),
+        })
+    }
+
+    /// Adds a value or a deletion. Keys have to be added in increasing `InternalKey` order.
+    pub fn add(&mut self, key: &InternalKey, value: &[u8]) -> Result<()> {
+        if key.value_type == ValueType::RangeDeletion {
+            bail!("range deletions go through add_range_deletion");
+        }
+        let encoded = key.encode();
+        Self::check_order(self.last_key.as_deref(), &encoded)?;
+        self.data_block.add(&encoded, value);
+        self.last_key = Some(encoded);
+        if let Some(filter) = &mut self.filter {
+            filter.add(&key.user_key);
+        }
+
+        self.properties.num_entries += 1;
+        if key.value_type == ValueType::Deletion {
+            self.properties.num_deletions += 1;
+        } else if let Some(timestamp) = self.options.timestamp_extractor.and_then(|f| f(value)) {
+            self.properties.min_timestamp = self.properties.min_timestamp.min(timestamp);
+            self.properties.max_timestamp = self.properties.max_timestamp.max(timestamp);
+        }
+        self.properties.raw_key_size += key.encoded_len() as u64;
+        self.properties.raw_value_size += value.len() as u64;
+        self.track_bounds(key, key);
+
+        if self.data_block.size_estimate() >= self.options.block_size {
+            self.flush_data_block()?;
+        }
+        Ok(())
+    }
+
+    /// Adds the deletion of `[start.user_key, end)`. Range deletions are ordered among themselves
+    /// by their start and kept in a meta block of their own.
+    pub fn add_range_deletion(&mut self, start: &InternalKey, end: &[u8]) -> Result<()> {
+        if start.value_type != ValueType::RangeDeletion {
+            bail!("range deletion start has value type {:?}", start.value_type);
+        }
+        let encoded = start.encode();
+        Self::check_order(self.last_range_deletion.as_deref(), &encoded)?;
+        self.range_deletions.add(&encoded, end);
+        self.last_range_deletion = Some(encoded);
+        self.properties.num_range_deletions += 1;
+        // the end is exclusive, so the table reaches up to but not including it
+        let end_key = InternalKey::new(end, start.seq, ValueType::RangeDeletion);
+        self.track_bounds(start, &end_key);
+        Ok(())
+    }
+
+    fn check_order(last_key: Option<&[u8]>, key: &[u8]) -> Result<()> {
+        match last_key {
+            Some(last_key) if key::compare_encoded(key, last_key) != Ordering::Greater => {
+                Err(SstableError::KeyOutOfOrder {
+                    last_key: last_key.to_vec(),
+                    key: key.to_vec(),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn track_bounds(&mut self, smallest: &InternalKey, largest: &InternalKey) {
+        if self.smallest.as_ref().is_none_or(|s| smallest < s) {
+            self.smallest = Some(smallest.clone());
+        }
+        if self.largest.as_ref().is_none_or(|l| largest > l) {
+            self.largest = Some(largest.clone());
+        }
+        self.properties.smallest_seq = self.properties.smallest_seq.min(smallest.seq);
+        self.properties.largest_seq = self.properties.largest_seq.max(largest.seq);
+    }
+
+    fn flush_data_block(&mut self) -> Result<()> {
+        if self.data_block.is_empty() {
+            return Ok(());
+        }
+        let last_key = self.data_block.last_key().to_vec();
+        let contents = self.data_block.finish();
+        let handle = self.write_block(&contents, self.options.compression)?;
+        self.index_block.add(&last_key, &handle.encode());
+        self.properties.num_data_blocks += 1;
+        self.properties.data_size += handle.size + BLOCK_TRAILER_SIZE as u64;
+        Ok(())
+    }
+
+    fn write_block(
+        &mut self,
+        contents: &[u8],
+        compression: CompressionType,
+    ) -> Result<BlockHandle> {
+        let block = format::encode_block(contents, compression)?;
+        self.writer.write_all(&block)?;
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: (block.len() - BLOCK_TRAILER_SIZE) as u64,
+        };
+        self.offset += block.len() as u64;
+        Ok(handle)
+    }
+
+    /// Number of entries added so far, range deletions included.
+    pub fn num_entries(&self) -> u64 {
+        self.properties.num_entries + self.properties.num_range_deletions
+    }
+
+    /// Bytes the table would take if it were finished now, give or take the meta blocks.
+    pub fn file_size_estimate(&self) -> u64 {
+        self.offset + self.data_block.size_estimate() as u64
+    }
+
+    /// Writes the remaining blocks and the footer and syncs the file.
+    pub fn finish(mut self) -> Result<TableInfo> {
+        self.flush_data_block()?;
+        let mut metaindex = BlockBuilder::new(1);
+        // metaindex entries are added in name order
+        let index = self.index_block.finish();
+        self.properties.index_size = (index.len() + BLOCK_TRAILER_SIZE) as u64;
+        if let Some(filter) = self.filter.as_mut().filter(|f| !f.is_empty()) {
+            let filter = filter.finish();
+            let handle = self.write_block(&filter, CompressionType::None)?;
+            self.properties.filter_size = handle.size + BLOCK_TRAILER_SIZE as u64;
+            metaindex.add(METAINDEX_FILTER.as_bytes(), &handle.encode());
+        }
+        let properties = self.properties.encode();
+        let properties_handle = self.write_block(&properties, CompressionType::None)?;
+        metaindex.add(METAINDEX_PROPERTIES.as_bytes(), &properties_handle.encode());
+        if !self.range_deletions.is_empty() {
+            let range_deletions = self.range_deletions.finish();
+            let handle = self.write_block(&range_deletions, self.options.compression)?;
+            metaindex.add(METAINDEX_RANGE_DELETIONS.as_bytes(), &handle.encode());
+        }
+        let metaindex = metaindex.finish();
+        let metaindex_handle = self.write_block(&metaindex, CompressionType::None)?;
+        let index_handle = self.write_block(&index, CompressionType::None)?;
+        let footer = Footer {
+            metaindex: metaindex_handle,
+            index: index_handle,
+            version: TABLE_VERSION,
+        };
+        let footer = footer.encode();
+        self.writer.write_all(&footer)?;
+        self.offset += footer.len() as u64;
+        self.writer.flush()?;
+        self.writer.get_mut().sync_all()?;
+        Ok(TableInfo {
+            path: self.path,
+            file_size: self.offset,
+            smallest: self.smallest,
+            largest: self.largest,
+            properties: self.properties,
+        })
+    }
+
+    /// Gives up on the table and removes the partially written file.
+    pub fn abandon(self) -> Result<()> {
+        drop(self.writer);
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::FOOTER_SIZE;
+
+    #[test]
+    fn test_finish_writes_checksummed_blocks_and_footer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.sst");
+        let options = TableOptions {
+            block_size: 64,
+            // the first value byte stands in for a timestamp
+            timestamp_extractor: Some(|value| value.first().map(|b| *b as u64)),
+            ..Default::default()
+        };
+        let mut builder = TableBuilder::create(&path, options).unwrap();
+        for i in 0..20u64 {
+            let key = InternalKey::new(format!("key{:03}", i), 100 - i, ValueType::Value);
+            builder.add(&key, &[i as u8; 16]).unwrap();
+        }
+        builder
+            .add(&InternalKey::new("key019", 1, ValueType::Deletion), b"")
+            .unwrap();
+        let start = InternalKey::new("key005", 200, ValueType::RangeDeletion);
+        builder.add_range_deletion(&start, b"key010").unwrap();
+        let info = builder.finish().unwrap();
+
+        let file = fs::read(&path).unwrap();
+        assert_eq!(file.len() as u64, info.file_size);
+        let (footer, magic) = Footer::decode(file[file.len() - FOOTER_SIZE..].try_into().unwrap());
+        assert_eq!(magic, format::TABLE_MAGIC);
+        assert_eq!(footer.version, TABLE_VERSION);
+        assert_eq!(
+            footer.index.offset + footer.index.size + BLOCK_TRAILER_SIZE as u64,
+            file.len() as u64 - FOOTER_SIZE as u64
+        );
+        // the first index entry is stored in full and points at the first data block, whose
+        // trailer checksum covers it
+        let index = &file[footer.index.offset as usize..];
+        let key_len = u32::from_be_bytes(index[4..8].try_into().unwrap()) as usize;
+        let first = BlockHandle::decode(&index[12 + key_len..]).unwrap();
+        assert_eq!(first.offset, 0);
+        let end = (first.offset + first.size) as usize;
+        let stored = u32::from_be_bytes(file[end + 5..end + 9].try_into().unwrap());
+        assert_eq!(crc32c::crc32c(&file[..end + 5]), stored);
+
+        let properties = info.properties;
+        assert_eq!(properties.num_entries, 21);
+        assert_eq!(properties.num_deletions, 1);
+        assert_eq!(properties.num_range_deletions, 1);
+        assert!(properties.num_data_blocks > 1);
+        assert_eq!((properties.smallest_seq, properties.largest_seq), (1, 200));
+        // the deletion's empty value has no timestamp
+        assert_eq!(properties.timestamp_range(), Some((0, 19)));
+        assert_eq!(info.smallest.unwrap().user_key, b"key000");
+        assert_eq!(info.largest.unwrap().user_key, b"key019");
+    }
+
+    #[test]
+    fn test_add_rejects_out_of_order_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.sst");
+        let mut builder = TableBuilder::create(&path, TableOptions::default()).unwrap();
+        builder
+            .add(&InternalKey::new("b", 5, ValueType::Value), b"v")
+            .unwrap();
+        // a newer version of the same key sorts before the older one
+        let err = builder
+            .add(&InternalKey::new("b", 6, ValueType::Value), b"v")
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SstableError>(),
+            Some(SstableError::KeyOutOfOrder { .. })
+        ));
+        builder
+            .add(&InternalKey::new("b", 4, ValueType::Value), b"v")
+            .unwrap();
+        builder.abandon().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,33 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SstableError {
+    #[error("key added out of order: {key:?} does not sort after {last_key:?}")]
+    KeyOutOfOrder { last_key: Vec<u8>, key: Vec<u8> },
+    #[error("{path:?} is not a table: bad magic {magic:#018x}")]
+    BadMagic { path: PathBuf, magic: u64 },
+    #[error("{path:?} has table version {version}, only {supported} is supported")]
+    UnsupportedVersion {
+        path: PathBuf,
+        version: u32,
+        supported: u32,
+    },
+    #[error("{path:?} is {size} bytes, too small for a table footer")]
+    TooSmall { path: PathBuf, size: u64 },
+    #[error(
+        "checksum mismatch for block at offset {offset} of {path:?}. Expected: {expected:#010x}, Got: {actual:#010x}"
+    )]
+    BlockChecksumMismatch {
+        path: PathBuf,
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("corrupt block at offset {offset} of {path:?}: {reason}")]
+    CorruptBlock {
+        path: PathBuf,
+        offset: u64,
+        reason: String,
+    },
+}
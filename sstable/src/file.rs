@@ -0,0 +1,37 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Positional reads of an immutable file. Tables only ever read through this, so the file can be
+/// shared between iterators without a seek position to fight over.
+pub trait RandomAccessFile: Send + Sync {
+    /// Reads exactly `len` bytes at `offset`, failing with `UnexpectedEof` past the end.
+    fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+
+    fn size(&self) -> io::Result<u64>;
+}
+
+pub struct StdRandomAccessFile {
+    file: File,
+}
+
+impl StdRandomAccessFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(StdRandomAccessFile {
+            file: File::open(path)?,
+        })
+    }
+}
+
+impl RandomAccessFile for StdRandomAccessFile {
+    fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const DEFAULT_BITS_PER_KEY: usize = 10;
+// probes above this are reserved for other filter encodings, readers treat them as a match
+const MAX_PROBES: u8 = 30;
+
+/// Collects the user keys of a table and encodes them as a bloom filter:
+///
+/// `bits | probes: u8`
+///
+/// Keys are hashed once and probed with double hashing, so the filter costs one hash per key on
+/// both sides.
+pub struct BloomFilterBuilder {
+    bits_per_key: usize,
+    hashes: Vec<u32>,
+}
+
+impl BloomFilterBuilder {
+    pub fn new(bits_per_key: usize) -> Self {
+        assert!(bits_per_key > 0, "bits per key must be positive");
+        BloomFilterBuilder {
+            bits_per_key,
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Adds a user key. Versions of the same key arrive next to each other and are only added
+    /// once.
+    pub fn add(&mut self, user_key: &[u8]) {
+        let hash = bloom_hash(user_key);
+        if self.hashes.last() != Some(&hash) {
+            self.hashes.push(hash);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        // ln(2) * bits per key minimizes the false positive rate
+        let probes = ((self.bits_per_key as f64 * 0.69) as u8).clamp(1, MAX_PROBES);
+        // tiny filters have a high false positive rate, give them at least 64 bits
+        let bits = (self.hashes.len() * self.bits_per_key).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+        let mut filter = vec![0; bytes + 1];
+        for hash in self.hashes.drain(..) {
+            for bit in probe_bits(hash, probes, bits) {
+                filter[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter[bytes] = probes;
+        filter
+    }
+}
+
+/// Whether `user_key` may have been added to `filter`. False means it definitely was not.
+pub fn bloom_may_contain(filter: &[u8], user_key: &[u8]) -> bool {
+    let Some((&probes, bits)) = filter.split_last() else {
+        return true;
+    };
+    if bits.is_empty() || probes > MAX_PROBES {
+        return true;
+    }
+    probe_bits(bloom_hash(user_key), probes, bits.len() * 8)
+        .all(|bit| bits[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+fn probe_bits(hash: u32, probes: u8, bits: usize) -> impl Iterator<Item = usize> {
+    let delta = hash.rotate_right(17);
+    (0..probes as u32).map(move |i| (hash.wrapping_add(delta.wrapping_mul(i)) as usize) % bits)
+}
+
+/// Murmur like hash, stable across platforms since it ends up on disk.
+fn bloom_hash(data: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+    let mut h = SEED ^ (data.len() as u32).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h.wrapping_add(u32::from_le_bytes(chunk.try_into().unwrap()));
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((*byte as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+/// How often table filters saved a data block read. Shared by the tables of a database so the
+/// counts cover all of them.
+#[derive(Debug, Default)]
+pub struct FilterStats {
+    useful: AtomicU64,
+    useless: AtomicU64,
+}
+
+impl FilterStats {
+    pub(crate) fn record(&self, useful: bool) {
+        let counter = if useful { &self.useful } else { &self.useless };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// lookups the filter answered without reading a data block
+    pub fn useful(&self) -> u64 {
+        self.useful.load(Ordering::Relaxed)
+    }
+
+    /// lookups the filter let through that then did not find the key
+    pub fn useless(&self) -> u64 {
+        self.useless.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filter_has_no_false_negatives_and_few_false_positives() {
+        let mut builder = BloomFilterBuilder::new(10);
+        for i in 0..10_000u32 {
+            builder.add(format!("key{}", i).as_bytes());
+        }
+        let filter = builder.finish();
+        assert!(builder.is_empty());
+        for i in 0..10_000u32 {
+            assert!(bloom_may_contain(&filter, format!("key{}", i).as_bytes()));
+        }
+        let false_positives = (0..10_000u32)
+            .filter(|i| bloom_may_contain(&filter, format!("missing{}", i).as_bytes()))
+            .count();
+        // around 1% at 10 bits per key
+        assert!(false_positives < 200, "{} false positives", false_positives);
+
+        let empty = BloomFilterBuilder::new(10).finish();
+        assert!(!bloom_may_contain(&empty, b"key0"));
+    }
+}
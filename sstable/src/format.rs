@@ -0,0 +1,107 @@
+use anyhow::Result;
+use bytes::BufMut;
+use wal::compression::CompressionType;
+
+pub const TABLE_MAGIC: u64 = 0x5353_5441_424c_4531; // "SSTABLE1"
+pub const TABLE_VERSION: u32 = 1;
+pub const BLOCK_HANDLE_SIZE: usize = 8 + 8;
+// metaindex handle | index handle | version | magic
+pub const FOOTER_SIZE: usize = BLOCK_HANDLE_SIZE * 2 + 4 + 8;
+// compression | uncompressed_len | checksum
+pub const BLOCK_TRAILER_SIZE: usize = 1 + 4 + 4;
+
+pub const METAINDEX_FILTER: &str = "filter.bloom";
+pub const METAINDEX_PROPERTIES: &str = "properties";
+pub const METAINDEX_RANGE_DELETIONS: &str = "rangedel";
+
+/// Where a block sits in the table file, trailer excluded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlockHandle {
+    pub fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.offset);
+        buf.put_u64(self.size);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(BLOCK_HANDLE_SIZE);
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < BLOCK_HANDLE_SIZE {
+            return None;
+        }
+        Some(BlockHandle {
+            offset: u64::from_be_bytes(buf[..8].try_into().ok()?),
+            size: u64::from_be_bytes(buf[8..16].try_into().ok()?),
+        })
+    }
+}
+
+/// Fixed size tail of every table, the entry point for reading it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    pub metaindex: BlockHandle,
+    pub index: BlockHandle,
+    pub version: u32,
+}
+
+impl Footer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FOOTER_SIZE);
+        self.metaindex.encode_to(&mut buf);
+        self.index.encode_to(&mut buf);
+        buf.put_u32(self.version);
+        buf.put_u64(TABLE_MAGIC);
+        buf
+    }
+
+    /// Decodes the footer fields and returns them along with the magic, which the caller checks.
+    pub fn decode(buf: &[u8; FOOTER_SIZE]) -> (Self, u64) {
+        let metaindex = BlockHandle::decode(&buf[..BLOCK_HANDLE_SIZE]).expect("sized by footer");
+        let index = BlockHandle::decode(&buf[BLOCK_HANDLE_SIZE..]).expect("sized by footer");
+        let version_at = BLOCK_HANDLE_SIZE * 2;
+        let version = u32::from_be_bytes(buf[version_at..version_at + 4].try_into().unwrap());
+        let magic = u64::from_be_bytes(buf[version_at + 4..].try_into().unwrap());
+        (
+            Footer {
+                metaindex,
+                index,
+                version,
+            },
+            magic,
+        )
+    }
+}
+
+/// Compresses a finished block and appends the trailer:
+///
+/// `block | compression: u8 | uncompressed_len: u32 | checksum: u32`
+///
+/// where the crc32c covers the stored block bytes plus the two fields before it. Blocks that do
+/// not shrink by at least 1/8th are stored uncompressed.
+pub fn encode_block(contents: &[u8], compression: CompressionType) -> Result<Vec<u8>> {
+    let (compression, stored) = match compression {
+        CompressionType::None => (CompressionType::None, contents.to_vec()),
+        compression => {
+            let compressed = compression.compress(contents)?;
+            if compressed.len() < contents.len() - contents.len() / 8 {
+                (compression, compressed)
+            } else {
+                (CompressionType::None, contents.to_vec())
+            }
+        }
+    };
+    let mut buf = stored;
+    buf.put_u8(compression as u8);
+    buf.put_u32(contents.len() as u32);
+    let checksum = crc32c::crc32c(&buf);
+    buf.put_u32(checksum);
+    Ok(buf)
+}
@@ -0,0 +1,8 @@
+pub mod block;
+pub mod builder;
+pub mod error;
+pub mod file;
+pub mod filter;
+pub mod format;
+pub mod properties;
+pub mod table;
@@ -0,0 +1,109 @@
+use crate::block::BlockBuilder;
+
+const PROPERTY_DATA_SIZE: &str = "sstable.data.size";
+const PROPERTY_FILTER_SIZE: &str = "sstable.filter.size";
+const PROPERTY_INDEX_SIZE: &str = "sstable.index.size";
+const PROPERTY_LARGEST_SEQ: &str = "sstable.largest.seq";
+const PROPERTY_MAX_TIMESTAMP: &str = "sstable.max.timestamp";
+const PROPERTY_MIN_TIMESTAMP: &str = "sstable.min.timestamp";
+const PROPERTY_NUM_DATA_BLOCKS: &str = "sstable.num.data.blocks";
+const PROPERTY_NUM_DELETIONS: &str = "sstable.num.deletions";
+const PROPERTY_NUM_ENTRIES: &str = "sstable.num.entries";
+const PROPERTY_NUM_RANGE_DELETIONS: &str = "sstable.num.range.deletions";
+const PROPERTY_RAW_KEY_SIZE: &str = "sstable.raw.key.size";
+const PROPERTY_RAW_VALUE_SIZE: &str = "sstable.raw.value.size";
+const PROPERTY_SMALLEST_SEQ: &str = "sstable.smallest.seq";
+
+/// Statistics collected while a table is built, stored in the properties meta block as
+/// `name -> u64` entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TableProperties {
+    pub num_entries: u64,
+    pub num_deletions: u64,
+    pub num_range_deletions: u64,
+    pub num_data_blocks: u64,
+    pub raw_key_size: u64,
+    pub raw_value_size: u64,
+    pub data_size: u64,
+    pub index_size: u64,
+    pub filter_size: u64,
+    /// sequence number range of every entry, `u64::MAX` and 0 for an empty table
+    pub smallest_seq: u64,
+    pub largest_seq: u64,
+    /// timestamp range of the values `TableOptions::timestamp_extractor` found one in,
+    /// `u64::MAX` and 0 when there were none
+    pub min_timestamp: u64,
+    pub max_timestamp: u64,
+}
+
+impl TableProperties {
+    pub(crate) fn new() -> Self {
+        TableProperties {
+            smallest_seq: u64::MAX,
+            min_timestamp: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    /// Oldest and newest timestamp of the table, `None` when no value carried one.
+    pub fn timestamp_range(&self) -> Option<(u64, u64)> {
+        (self.min_timestamp <= self.max_timestamp)
+            .then_some((self.min_timestamp, self.max_timestamp))
+    }
+
+    fn fields(&self) -> [(&'static str, u64); 13] {
+        // sorted by name, block keys have to be added in order
+        [
+            (PROPERTY_DATA_SIZE, self.data_size),
+            (PROPERTY_FILTER_SIZE, self.filter_size),
+            (PROPERTY_INDEX_SIZE, self.index_size),
+            (PROPERTY_LARGEST_SEQ, self.largest_seq),
+            (PROPERTY_MAX_TIMESTAMP, self.max_timestamp),
+            (PROPERTY_MIN_TIMESTAMP, self.min_timestamp),
+            (PROPERTY_NUM_DATA_BLOCKS, self.num_data_blocks),
+            (PROPERTY_NUM_DELETIONS, self.num_deletions),
+            (PROPERTY_NUM_ENTRIES, self.num_entries),
+            (PROPERTY_NUM_RANGE_DELETIONS, self.num_range_deletions),
+            (PROPERTY_RAW_KEY_SIZE, self.raw_key_size),
+            (PROPERTY_RAW_VALUE_SIZE, self.raw_value_size),
+            (PROPERTY_SMALLEST_SEQ, self.smallest_seq),
+        ]
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut block = BlockBuilder::new(1);
+        for (name, value) in self.fields() {
+            block.add(name.as_bytes(), &value.to_be_bytes());
+        }
+        block.finish()
+    }
+
+    /// Rebuilds the properties from the entries of a properties block. Unknown names are skipped
+    /// so newer writers can add properties.
+    pub fn decode<'a>(entries: impl IntoIterator<Item = (&'a [u8], &'a [u8])>) -> Self {
+        let mut properties = TableProperties::new();
+        for (name, value) in entries {
+            let Ok(value) = <[u8; 8]>::try_from(value).map(u64::from_be_bytes) else {
+                continue;
+            };
+            let field = match std::str::from_utf8(name) {
+                Ok(PROPERTY_DATA_SIZE) => &mut properties.data_size,
+                Ok(PROPERTY_FILTER_SIZE) => &mut properties.filter_size,
+                Ok(PROPERTY_INDEX_SIZE) => &mut properties.index_size,
+                Ok(PROPERTY_LARGEST_SEQ) => &mut properties.largest_seq,
+                Ok(PROPERTY_MAX_TIMESTAMP) => &mut properties.max_timestamp,
+                Ok(PROPERTY_MIN_TIMESTAMP) => &mut properties.min_timestamp,
+                Ok(PROPERTY_NUM_DATA_BLOCKS) => &mut properties.num_data_blocks,
+                Ok(PROPERTY_NUM_DELETIONS) => &mut properties.num_deletions,
+                Ok(PROPERTY_NUM_ENTRIES) => &mut properties.num_entries,
+                Ok(PROPERTY_NUM_RANGE_DELETIONS) => &mut properties.num_range_deletions,
+                Ok(PROPERTY_RAW_KEY_SIZE) => &mut properties.raw_key_size,
+                Ok(PROPERTY_RAW_VALUE_SIZE) => &mut properties.raw_value_size,
+                Ok(PROPERTY_SMALLEST_SEQ) => &mut properties.smallest_seq,
+                _ => continue,
+            };
+            *field = value;
+        }
+        properties
+    }
+}
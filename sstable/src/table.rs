@@ -0,0 +1,572 @@
+use anyhow::Result;
+use memtable::key::{self, InternalKey, ValueType};
+use memtable::memtable::Lookup;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use wal::compression::CompressionType;
+
+use crate::block::{Block, BlockIter};
+use crate::error::SstableError;
+use crate::file::{RandomAccessFile, StdRandomAccessFile};
+use crate::filter::{self, FilterStats};
+use crate::format::{
+    BLOCK_TRAILER_SIZE, BlockHandle, FOOTER_SIZE, Footer, METAINDEX_FILTER, METAINDEX_PROPERTIES,
+    METAINDEX_RANGE_DELETIONS, TABLE_MAGIC, TABLE_VERSION,
+};
+use crate::properties::TableProperties;
+
+/// An open table written by `TableBuilder`. The index, filter, properties and range deletions are
+/// read when it is opened, data blocks on demand through a `TableIterator`.
+pub struct Table {
+    path: PathBuf,
+    file: Arc<dyn RandomAccessFile>,
+    file_size: u64,
+    footer: Footer,
+    index: Arc<Block>,
+    properties: TableProperties,
+    range_deletions: Vec<(InternalKey, Vec<u8>)>,
+    filter: Option<Vec<u8>>,
+    filter_stats: Arc<FilterStats>,
+}
+
+impl Table {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = StdRandomAccessFile::open(path)?;
+        Self::open_file(path, Arc::new(file))
+    }
+
+    /// Opens a table from an already open file, `path` is only used in errors.
+    pub fn open_file(path: impl AsRef<Path>, file: Arc<dyn RandomAccessFile>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file_size = file.size()?;
+        if file_size < FOOTER_SIZE as u64 {
+            return Err(SstableError::TooSmall {
+                path,
+                size: file_size,
+            }
+            .into());
+        }
+        let footer = file.read_at(file_size - FOOTER_SIZE as u64, FOOTER_SIZE)?;
+        let (footer, magic) = Footer::decode(footer.as_slice().try_into()?);
+        if magic != TABLE_MAGIC {
+            return Err(SstableError::BadMagic { path, magic }.into());
+        }
+        if footer.version != TABLE_VERSION {
+            return Err(SstableError::UnsupportedVersion {
+                path,
+                version: footer.version,
+                supported: TABLE_VERSION,
+            }
+            .into());
+        }
+        let index = read_block(&path, file.as_ref(), file_size, footer.index)?;
+        let mut table = Table {
+            path,
+            file,
+            file_size,
+            footer,
+            index,
+            properties: TableProperties::new(),
+            range_deletions: Vec::new(),
+            filter: None,
+            filter_stats: Arc::default(),
+        };
+        table.read_meta_blocks()?;
+        Ok(table)
+    }
+
+    fn read_meta_blocks(&mut self) -> Result<()> {
+        let metaindex = self.read_block(self.footer.metaindex)?;
+        let mut iter = metaindex.iter(|a, b| a.cmp(b));
+        iter.seek_to_first();
+        while iter.valid() {
+            let handle = BlockHandle::decode(iter.value())
+                .ok_or_else(|| self.corrupt(self.footer.metaindex, "bad meta block handle"))?;
+            if iter.key() == METAINDEX_FILTER.as_bytes() {
+                self.filter = Some(read_block_contents(
+                    &self.path,
+                    self.file.as_ref(),
+                    self.file_size,
+                    handle,
+                )?);
+            } else if iter.key() == METAINDEX_PROPERTIES.as_bytes() {
+                let block = self.read_block(handle)?;
+                let mut entries = block.iter(|a, b| a.cmp(b));
+                entries.seek_to_first();
+                let mut properties = Vec::new();
+                while entries.valid() {
+                    properties.push((entries.key().to_vec(), entries.value().to_vec()));
+                    entries.next();
+                }
+                self.properties = TableProperties::decode(
+                    properties.iter().map(|(k, v)| (k.as_slice(), v.as_slice())),
+                );
+            } else if iter.key() == METAINDEX_RANGE_DELETIONS.as_bytes() {
+                let block = self.read_block(handle)?;
+                let mut entries = block.iter(key::compare_encoded);
+                entries.seek_to_first();
+                while entries.valid() {
+                    let start = InternalKey::decode(entries.key())
+                        .ok_or_else(|| self.corrupt(handle, "bad range deletion key"))?;
+                    self.range_deletions.push((start, entries.value().to_vec()));
+                    entries.next();
+                }
+            }
+            iter.next();
+        }
+        Ok(())
+    }
+
+    /// Reads the block at `handle` and verifies its checksum before decompressing and decoding
+    /// it.
+    pub fn read_block(&self, handle: BlockHandle) -> Result<Arc<Block>> {
+        read_block(&self.path, self.file.as_ref(), self.file_size, handle)
+    }
+
+    fn corrupt(&self, handle: BlockHandle, reason: impl Into<String>) -> anyhow::Error {
+        corrupt(&self.path, handle, reason)
+    }
+
+    /// Counts filter decisions into `stats` instead of counters of this table alone.
+    pub fn with_filter_stats(mut self, stats: Arc<FilterStats>) -> Self {
+        self.filter_stats = stats;
+        self
+    }
+
+    pub fn filter_stats(&self) -> &FilterStats {
+        &self.filter_stats
+    }
+
+    /// Whether the table may hold a point entry for `key`, without reading a data block.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter
+            .as_deref()
+            .is_none_or(|filter| filter::bloom_may_contain(filter, key))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn properties(&self) -> &TableProperties {
+        &self.properties
+    }
+
+    /// Range deletions in internal key order, each with the exclusive end of its range.
+    pub fn range_deletions(&self) -> &[(InternalKey, Vec<u8>)] {
+        &self.range_deletions
+    }
+
+    pub fn iter(&self) -> TableIterator<'_> {
+        TableIterator {
+            table: self,
+            index: self.index.iter(key::compare_encoded),
+            data: None,
+        }
+    }
+
+    /// Newest version of `key` visible at `snapshot`, `None` when this table does not know the key
+    /// and older tables have to be checked. Same rules as `Memtable::get`.
+    ///
+    /// The filter is consulted first and the data block only read when it lets the key through.
+    pub fn get(&self, key: &[u8], snapshot: u64) -> Result<Option<Lookup>> {
+        let point = if self.may_contain(key) {
+            let point = self.get_point(key, snapshot)?;
+            if self.filter.is_some() && point.is_none() {
+                self.filter_stats.record(false);
+            }
+            point
+        } else {
+            self.filter_stats.record(true);
+            None
+        };
+        let point_seq = point.as_ref().map(|(found, _)| found.seq);
+        let range_deleted = self.range_deletions.iter().any(|(start, end)| {
+            start.user_key.as_slice() <= key
+                && key < end.as_slice()
+                && start.seq <= snapshot
+                && point_seq.is_none_or(|seq| start.seq > seq)
+        });
+        if range_deleted {
+            return Ok(Some(Lookup::Deleted));
+        }
+        Ok(point.map(|(found, value)| match found.value_type {
+            ValueType::Value => Lookup::Found(value),
+            ValueType::Deletion | ValueType::RangeDeletion => Lookup::Deleted,
+        }))
+    }
+
+    fn get_point(&self, key: &[u8], snapshot: u64) -> Result<Option<(InternalKey, Vec<u8>)>> {
+        let mut iter = self.iter();
+        iter.seek(&InternalKey::lookup(key, snapshot))?;
+        if !iter.valid() {
+            return Ok(None);
+        }
+        Ok(InternalKey::decode(iter.key())
+            .filter(|found| found.user_key == key)
+            .map(|found| (found, iter.value().to_vec())))
+    }
+}
+
+fn read_block(
+    path: &Path,
+    file: &dyn RandomAccessFile,
+    file_size: u64,
+    handle: BlockHandle,
+) -> Result<Arc<Block>> {
+    let contents = read_block_contents(path, file, file_size, handle)?;
+    let block = Block::new(contents).map_err(|reason| corrupt(path, handle, reason))?;
+    Ok(Arc::new(block))
+}
+
+/// Verified and decompressed contents of the block at `handle`, for meta blocks that are not
+/// made of entries.
+fn read_block_contents(
+    path: &Path,
+    file: &dyn RandomAccessFile,
+    file_size: u64,
+    handle: BlockHandle,
+) -> Result<Vec<u8>> {
+    let len = handle.size as usize + BLOCK_TRAILER_SIZE;
+    let end = handle.offset.checked_add(len as u64);
+    if end.is_none_or(|end| end > file_size - FOOTER_SIZE as u64) {
+        return Err(corrupt(
+            path,
+            handle,
+            "block handle past the end of the table",
+        ));
+    }
+    let raw = file.read_at(handle.offset, len)?;
+    let (stored, trailer) = raw.split_at(handle.size as usize);
+    let expected = u32::from_be_bytes(trailer[5..].try_into()?);
+    let actual = crc32c::crc32c(&raw[..len - 4]);
+    if expected != actual {
+        return Err(SstableError::BlockChecksumMismatch {
+            path: path.to_path_buf(),
+            offset: handle.offset,
+            expected,
+            actual,
+        }
+        .into());
+    }
+    let uncompressed_len = u32::from_be_bytes(trailer[1..5].try_into()?) as usize;
+    let contents = CompressionType::from_u8(trailer[0])
+        .and_then(|compression| compression.decompress(stored, uncompressed_len))
+        .map_err(|e| corrupt(path, handle, e.to_string()))?;
+    Ok(contents)
+}
+
+fn corrupt(path: &Path, handle: BlockHandle, reason: impl Into<String>) -> anyhow::Error {
+    SstableError::CorruptBlock {
+        path: path.to_path_buf(),
+        offset: handle.offset,
+        reason: reason.into(),
+    }
+    .into()
+}
+
+/// Iterates the point entries of a table in internal key order. Keys are encoded internal keys.
+///
+/// The index iterator picks the data block and a block iterator walks it, moving on to the
+/// neighbouring block when it runs off either end. Reading a block can fail, so every move
+/// returns a result.
+pub struct TableIterator<'a> {
+    table: &'a Table,
+    index: BlockIter,
+    data: Option<BlockIter>,
+}
+
+impl TableIterator<'_> {
+    pub fn valid(&self) -> bool {
+        self.data.as_ref().is_some_and(BlockIter::valid)
+    }
+
+    pub fn key(&self) -> &[u8] {
+        self.data.as_ref().expect("iterator is not valid").key()
+    }
+
+    pub fn value(&self) -> &[u8] {
+        self.data.as_ref().expect("iterator is not valid").value()
+    }
+
+    /// Positions at the first entry at or after `target`.
+    pub fn seek(&mut self, target: &InternalKey) -> Result<()> {
+        let target = target.encode();
+        // index keys are the last key of their block, so this finds the only block that can hold
+        // the target
+        self.index.seek(&target);
+        self.load_data_block()?;
+        if let Some(data) = &mut self.data {
+            data.seek(&target);
+        }
+        self.skip_forward()
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<()> {
+        self.index.seek_to_first();
+        self.load_data_block()?;
+        if let Some(data) = &mut self.data {
+            data.seek_to_first();
+        }
+        self.skip_forward()
+    }
+
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        self.index.seek_to_last();
+        self.load_data_block()?;
+        if let Some(data) = &mut self.data {
+            data.seek_to_last();
+        }
+        self.skip_backward()
+    }
+
+    // a cursor that can also seek and step back, not an `Iterator`
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<()> {
+        if let Some(data) = &mut self.data {
+            data.next();
+        }
+        self.skip_forward()
+    }
+
+    pub fn prev(&mut self) -> Result<()> {
+        if let Some(data) = &mut self.data {
+            data.prev();
+        }
+        self.skip_backward()
+    }
+
+    fn load_data_block(&mut self) -> Result<()> {
+        self.data = None;
+        if !self.index.valid() {
+            return Ok(());
+        }
+        let table = self.table;
+        let handle = BlockHandle::decode(self.index.value())
+            .ok_or_else(|| table.corrupt(table.footer.index, "bad data block handle"))?;
+        self.data = Some(table.read_block(handle)?.iter(key::compare_encoded));
+        Ok(())
+    }
+
+    fn skip_forward(&mut self) -> Result<()> {
+        while self.data.as_ref().is_some_and(|data| !data.valid()) {
+            self.index.next();
+            self.load_data_block()?;
+            if let Some(data) = &mut self.data {
+                data.seek_to_first();
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_backward(&mut self) -> Result<()> {
+        while self.data.as_ref().is_some_and(|data| !data.valid()) {
+            self.index.prev();
+            self.load_data_block()?;
+            if let Some(data) = &mut self.data {
+                data.seek_to_last();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::{TableBuilder, TableOptions};
+    use std::fs;
+
+    fn build(path: &Path, compression: CompressionType) {
+        let options = TableOptions {
+            block_size: 128,
+            block_restart_interval: 4,
+            compression,
+            filter_bits_per_key: Some(10),
+            timestamp_extractor: None,
+        };
+        let mut builder = TableBuilder::create(path, options).unwrap();
+        for i in 0..100u64 {
+            let user_key = format!("key{:03}", i);
+            builder
+                .add(
+                    &InternalKey::new(user_key.as_str(), 10 + i, ValueType::Value),
+                    &[1; 8],
+                )
+                .unwrap();
+            if i % 10 == 0 {
+                let older = InternalKey::new(user_key.as_str(), i, ValueType::Deletion);
+                builder.add(&older, b"").unwrap();
+            }
+        }
+        let start = InternalKey::new("key050", 500, ValueType::RangeDeletion);
+        builder.add_range_deletion(&start, b"key060").unwrap();
+        builder.finish().unwrap();
+    }
+
+    fn user_key(iter: &TableIterator) -> String {
+        String::from_utf8(InternalKey::decode(iter.key()).unwrap().user_key).unwrap()
+    }
+
+    #[test]
+    fn test_iterator_walks_blocks_both_ways() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.sst");
+        build(&path, CompressionType::Lz4);
+        let table = Table::open(&path).unwrap();
+        assert_eq!(table.properties().num_entries, 110);
+        assert_eq!(table.properties().timestamp_range(), None);
+        assert!(table.properties().num_data_blocks > 10);
+        assert_eq!(table.range_deletions().len(), 1);
+
+        let mut iter = table.iter();
+        iter.seek_to_first().unwrap();
+        let mut forward = Vec::new();
+        while iter.valid() {
+            forward.push(iter.key().to_vec());
+            iter.next().unwrap();
+        }
+        assert_eq!(forward.len(), 110);
+        assert!(forward.is_sorted_by(|a, b| key::compare_encoded(a, b).is_lt()));
+
+        iter.seek_to_last().unwrap();
+        let mut backward = Vec::new();
+        while iter.valid() {
+            backward.push(iter.key().to_vec());
+            iter.prev().unwrap();
+        }
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        iter.seek(&InternalKey::lookup(b"key0305", u64::MAX >> 8))
+            .unwrap();
+        assert_eq!(user_key(&iter), "key031");
+        iter.prev().unwrap();
+        assert_eq!(user_key(&iter), "key030");
+        iter.seek(&InternalKey::lookup(b"key999", 0)).unwrap();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_get_respects_snapshots_and_range_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.sst");
+        build(&path, CompressionType::None);
+        let table = Table::open(&path).unwrap();
+        assert_eq!(
+            table.get(b"key020", 1000).unwrap(),
+            Some(Lookup::Found(vec![1; 8]))
+        );
+        // only the older deletion is visible at this snapshot
+        assert_eq!(table.get(b"key020", 25).unwrap(), Some(Lookup::Deleted));
+        assert_eq!(table.get(b"key021", 25).unwrap(), None);
+        assert_eq!(table.get(b"key055", 1000).unwrap(), Some(Lookup::Deleted));
+        assert_eq!(
+            table.get(b"key055", 499).unwrap(),
+            Some(Lookup::Found(vec![1; 8]))
+        );
+        assert_eq!(table.get(b"nokey", 1000).unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_consults_filter_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.sst");
+        build(&path, CompressionType::None);
+        let stats = Arc::new(FilterStats::default());
+        let table = Table::open(&path).unwrap().with_filter_stats(stats.clone());
+        assert!(table.properties().filter_size > 0);
+        for i in 0..100 {
+            assert!(table.may_contain(format!("key{:03}", i).as_bytes()));
+        }
+        for i in 0..1000 {
+            let key = format!("missing{}", i);
+            assert_eq!(table.get(key.as_bytes(), 1000).unwrap(), None);
+        }
+        assert_eq!(stats.useful() + stats.useless(), 1000);
+        assert!(stats.useful() > 950, "{} useful", stats.useful());
+        // a range deletion still applies when the filter rules out point entries
+        let hidden = table.get(b"key0555", 1000).unwrap();
+        assert_eq!(hidden, Some(Lookup::Deleted));
+
+        let unfiltered = dir.path().join("000002.sst");
+        let mut builder = TableBuilder::create(
+            &unfiltered,
+            TableOptions {
+                filter_bits_per_key: None,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        builder
+            .add(&InternalKey::new("a", 1, ValueType::Value), b"v")
+            .unwrap();
+        builder.finish().unwrap();
+        let table = Table::open(&unfiltered).unwrap();
+        assert!(table.may_contain(b"b"));
+        assert_eq!(table.get(b"b", 10).unwrap(), None);
+        assert_eq!(table.filter_stats().useless(), 0);
+    }
+
+    #[test]
+    fn test_table_of_only_range_deletions_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.sst");
+        let mut builder = TableBuilder::create(&path, TableOptions::default()).unwrap();
+        let start = InternalKey::new("b", 7, ValueType::RangeDeletion);
+        builder.add_range_deletion(&start, b"d").unwrap();
+        let info = builder.finish().unwrap();
+        assert_eq!(info.smallest.unwrap().user_key, b"b");
+
+        let table = Table::open(&path).unwrap();
+        assert_eq!(table.properties().num_data_blocks, 0);
+        assert_eq!(table.range_deletions().len(), 1);
+        let mut iter = table.iter();
+        iter.seek_to_first().unwrap();
+        assert!(!iter.valid());
+        iter.seek_to_last().unwrap();
+        assert!(!iter.valid());
+        assert_eq!(table.get(b"c", 10).unwrap(), Some(Lookup::Deleted));
+        assert_eq!(table.get(b"c", 6).unwrap(), None);
+        assert_eq!(table.get(b"d", 10).unwrap(), None);
+    }
+
+    #[test]
+    fn test_corruption_surfaces_as_typed_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.sst");
+        build(&path, CompressionType::None);
+        let mut file = fs::read(&path).unwrap();
+
+        // a flipped bit in the first data block is caught by its checksum
+        file[20] ^= 1;
+        fs::write(&path, &file).unwrap();
+        let table = Table::open(&path).unwrap();
+        let err = table.iter().seek_to_first().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SstableError>(),
+            Some(SstableError::BlockChecksumMismatch { offset: 0, .. })
+        ));
+
+        let magic_at = file.len() - 8;
+        file[magic_at] ^= 1;
+        fs::write(&path, &file).unwrap();
+        let err = Table::open(&path).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<SstableError>(),
+            Some(SstableError::BadMagic { .. })
+        ));
+
+        fs::write(&path, &file[..10]).unwrap();
+        let err = Table::open(&path).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<SstableError>(),
+            Some(SstableError::TooSmall { size: 10, .. })
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+use memtable::memtable::Memtable;
 use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
 use monoio::net::{TcpListener, TcpStream};
 use std::sync::Arc;
@@ -9,8 +10,6 @@ enum SyncMode {
     ByMemtableFrozen,
 }
 
-struct Memtable {}
-
 struct Account<'a> {
     key: &'a [u8; 32],
     data: &'a [u8],
@@ -10,6 +10,7 @@ const MAX_INSTRUCTION_TRANSACTION: usize = 5;
 pub struct PendingTransaction {
     pub tx: Transaction,
     // bytes: Option<&'a [u8]>,
+    // assigned by the window the transaction joins, from the WAL's sequence allocator
     pub seq_num: u64,
     pub response_tx: AsyncSender<()>,
     closed: bool,
@@ -18,12 +19,11 @@ pub struct PendingTransaction {
 impl PendingTransaction {
     pub fn new(
         tx: Transaction,
-        seq_num: u64,
         // bytes: Option<&'a [u8]>,
         response_tx: AsyncSender<()>,
     ) -> PendingTransaction {
         PendingTransaction {
-            seq_num,
+            seq_num: 0,
             //  bytes,
             tx,
             response_tx,
@@ -149,12 +149,17 @@ impl Transaction {
     }
 
     pub fn is_older_than(&self, seconds: u64) -> bool {
+        Self::timestamp_is_older_than(self.timestamp, seconds)
+    }
+
+    /// Same check as `is_older_than` for a bare timestamp, e.g. one kept in table properties
+    pub fn timestamp_is_older_than(timestamp: u64, seconds: u64) -> bool {
         let current_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
-        current_time.saturating_sub(self.timestamp) > seconds
+        current_time.saturating_sub(timestamp) > seconds
     }
 
     pub fn storage_delta(&self) -> StorageDelta {
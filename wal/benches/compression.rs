@@ -0,0 +1,63 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use transaction::{Instruction, Signer, Transaction};
+use wal::checksum::ChecksumType;
+use wal::compression::CompressionType;
+use wal::record::encode_puts;
+use wal::wal::encode_batch;
+
+const BATCH_SIZE: usize = 300;
+
+// transactions as they show up in practice: a short instruction payload followed by the zero
+// padding of the fixed size `Instruction::data` array
+fn batch() -> Vec<(Vec<u8>, Vec<u8>)> {
+    (0..BATCH_SIZE as u64)
+        .map(|seq_num| {
+            let mut itx = Instruction {
+                contract: [seq_num as u8; 32],
+                ..Default::default()
+            };
+            itx.data[..64].copy_from_slice(&[0xab; 64]);
+            let mut itxs = [Instruction::default(); 5];
+            itxs[0] = itx;
+            let tx = Transaction::new_with_timestamp(Signer::new([7; 32]), itxs, seq_num);
+            (seq_num.to_le_bytes().to_vec(), tx.to_bytes().unwrap())
+        })
+        .collect()
+}
+
+fn bench_encode_batch(c: &mut Criterion) {
+    let entries = batch();
+    let raw_bytes: usize = entries.iter().map(|(k, v)| k.len() + v.len()).sum();
+    let payload = encode_puts(&entries);
+    let mut group = c.benchmark_group("encode_batch");
+    group.throughput(Throughput::Bytes(raw_bytes as u64));
+    for compression in [
+        CompressionType::None,
+        CompressionType::Lz4,
+        CompressionType::Zstd,
+    ] {
+        let written = encode_batch(1, compression, ChecksumType::Crc32, None, 0, &payload)
+            .unwrap()
+            .len();
+        println!(
+            "{:?}: {} bytes written for {} raw bytes ({:.1}%)",
+            compression,
+            written,
+            raw_bytes,
+            written as f64 * 100.0 / raw_bytes as f64
+        );
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", compression)),
+            &compression,
+            |b, compression| {
+                b.iter(|| {
+                    encode_batch(1, *compression, ChecksumType::Crc32, None, 0, &payload).unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode_batch);
+criterion_main!(benches);
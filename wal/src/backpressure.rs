@@ -0,0 +1,274 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+const DEFAULT_SOFT_PENDING_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_HARD_PENDING_BYTES: u64 = 128 * 1024 * 1024;
+const DEFAULT_SOFT_PENDING_WINDOWS: usize = 8;
+const DEFAULT_HARD_PENDING_WINDOWS: usize = 16;
+const DEFAULT_SOFT_MEMTABLE_COUNT: usize = 4;
+const DEFAULT_HARD_MEMTABLE_COUNT: usize = 6;
+const DEFAULT_SOFT_L0_FILES: usize = 20;
+const DEFAULT_HARD_L0_FILES: usize = 36;
+const DEFAULT_SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// Soft limits delay every submission by `slowdown_delay`, hard limits make submissions wait
+/// until the backlog drains below them.
+#[derive(Debug, Clone)]
+pub struct BackpressureOptions {
+    /// bytes of transactions admitted but not yet written and acknowledged
+    pub soft_pending_bytes: u64,
+    pub hard_pending_bytes: u64,
+    /// windows formed but not yet written and acknowledged
+    pub soft_pending_windows: usize,
+    pub hard_pending_windows: usize,
+    /// immutable memtables waiting to be flushed
+    pub soft_memtable_count: usize,
+    pub hard_memtable_count: usize,
+    /// L0 tables waiting to be compacted
+    pub soft_l0_files: usize,
+    pub hard_l0_files: usize,
+    pub slowdown_delay: Duration,
+}
+
+impl Default for BackpressureOptions {
+    fn default() -> Self {
+        BackpressureOptions {
+            soft_pending_bytes: DEFAULT_SOFT_PENDING_BYTES,
+            hard_pending_bytes: DEFAULT_HARD_PENDING_BYTES,
+            soft_pending_windows: DEFAULT_SOFT_PENDING_WINDOWS,
+            hard_pending_windows: DEFAULT_HARD_PENDING_WINDOWS,
+            soft_memtable_count: DEFAULT_SOFT_MEMTABLE_COUNT,
+            hard_memtable_count: DEFAULT_HARD_MEMTABLE_COUNT,
+            soft_l0_files: DEFAULT_SOFT_L0_FILES,
+            hard_l0_files: DEFAULT_HARD_L0_FILES,
+            slowdown_delay: DEFAULT_SLOWDOWN_DELAY,
+        }
+    }
+}
+
+/// Why ingest slowed down or stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum StallReason {
+    /// pending bytes or windows piled up because WAL writes and fsyncs fall behind
+    WalFsync = 0,
+    /// too many immutable memtables waiting for a flush
+    MemtableCount = 1,
+    /// too many L0 tables waiting for compaction
+    L0Files = 2,
+}
+
+const STALL_REASONS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteState {
+    Normal,
+    Delayed(StallReason),
+    Stopped(StallReason),
+}
+
+/// How often and for how long submissions were held back for one reason.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StallStats {
+    pub delayed: u64,
+    pub stopped: u64,
+    pub stall_micros: u64,
+}
+
+#[derive(Default)]
+struct StallCounters {
+    delayed: AtomicU64,
+    stopped: AtomicU64,
+    stall_micros: AtomicU64,
+}
+
+/// Admission control for the ingest path, modelled on rocksdb's write controller.
+pub struct WriteController {
+    options: BackpressureOptions,
+    pending_bytes: AtomicU64,
+    pending_windows: AtomicUsize,
+    memtable_count: AtomicUsize,
+    l0_files: AtomicUsize,
+    drained: Notify,
+    stats: [StallCounters; STALL_REASONS],
+}
+
+impl WriteController {
+    pub fn new(options: BackpressureOptions) -> Self {
+        WriteController {
+            options,
+            pending_bytes: AtomicU64::new(0),
+            pending_windows: AtomicUsize::new(0),
+            memtable_count: AtomicUsize::new(0),
+            l0_files: AtomicUsize::new(0),
+            drained: Notify::new(),
+            stats: Default::default(),
+        }
+    }
+
+    /// Hard limits are checked before soft ones, and within each the WAL comes first since it
+    /// is closest to the writers.
+    pub fn state(&self) -> WriteState {
+        let o = &self.options;
+        let bytes = self.pending_bytes.load(Ordering::SeqCst);
+        let windows = self.pending_windows.load(Ordering::SeqCst);
+        let memtables = self.memtable_count.load(Ordering::SeqCst);
+        let l0_files = self.l0_files.load(Ordering::SeqCst);
+        let checks = [
+            (
+                StallReason::WalFsync,
+                bytes >= o.hard_pending_bytes || windows >= o.hard_pending_windows,
+                bytes >= o.soft_pending_bytes || windows >= o.soft_pending_windows,
+            ),
+            (
+                StallReason::MemtableCount,
+                memtables >= o.hard_memtable_count,
+                memtables >= o.soft_memtable_count,
+            ),
+            (
+                StallReason::L0Files,
+                l0_files >= o.hard_l0_files,
+                l0_files >= o.soft_l0_files,
+            ),
+        ];
+        if let Some((reason, _, _)) = checks.iter().find(|(_, hard, _)| *hard) {
+            return WriteState::Stopped(*reason);
+        }
+        if let Some((reason, _, _)) = checks.iter().find(|(_, _, soft)| *soft) {
+            return WriteState::Delayed(*reason);
+        }
+        WriteState::Normal
+    }
+
+    /// Waits while a hard limit is hit and sleeps `slowdown_delay` while a soft one is, then
+    /// accounts `bytes` as pending. Pair with `release`.
+    pub async fn admit(&self, bytes: u64) {
+        let started = Instant::now();
+        let mut stalled = None;
+        loop {
+            // register before checking so a release in between is not missed
+            let drained = self.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+            match self.state() {
+                WriteState::Stopped(reason) => {
+                    if stalled.is_none() {
+                        self.stats[reason as usize]
+                            .stopped
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    stalled = Some(reason);
+                    drained.await;
+                }
+                WriteState::Delayed(reason) => {
+                    self.stats[reason as usize]
+                        .delayed
+                        .fetch_add(1, Ordering::Relaxed);
+                    stalled.get_or_insert(reason);
+                    tokio::time::sleep(self.options.slowdown_delay).await;
+                    break;
+                }
+                WriteState::Normal => break,
+            }
+        }
+        if let Some(reason) = stalled {
+            self.stats[reason as usize]
+                .stall_micros
+                .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        }
+        self.pending_bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// `bytes` admitted earlier were written and acknowledged.
+    pub fn release(&self, bytes: u64) {
+        self.pending_bytes.fetch_sub(bytes, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    pub fn window_opened(&self) {
+        self.pending_windows.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn window_closed(&self) {
+        self.pending_windows.fetch_sub(1, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    /// Reported by the memtable side whenever its immutable queue changes.
+    pub fn set_memtable_count(&self, count: usize) {
+        self.memtable_count.store(count, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    /// Reported by flushes and compactions whenever the number of L0 tables changes.
+    pub fn set_l0_files(&self, count: usize) {
+        self.l0_files.store(count, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    pub fn stall_stats(&self, reason: StallReason) -> StallStats {
+        let counters = &self.stats[reason as usize];
+        StallStats {
+            delayed: counters.delayed.load(Ordering::Relaxed),
+            stopped: counters.stopped.load(Ordering::Relaxed),
+            stall_micros: counters.stall_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_hard_limit_waits_for_release() {
+        let controller = Arc::new(WriteController::new(BackpressureOptions {
+            soft_pending_bytes: 100,
+            hard_pending_bytes: 100,
+            ..Default::default()
+        }));
+        controller.admit(100).await;
+        assert_eq!(
+            controller.state(),
+            WriteState::Stopped(StallReason::WalFsync)
+        );
+
+        let waiting = tokio::spawn({
+            let controller = controller.clone();
+            async move { controller.admit(10).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        controller.release(100);
+        waiting.await.unwrap();
+        assert_eq!(controller.stall_stats(StallReason::WalFsync).stopped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_soft_limit_delays_and_records_reason() {
+        let controller = WriteController::new(BackpressureOptions {
+            soft_l0_files: 2,
+            hard_l0_files: 4,
+            ..Default::default()
+        });
+        controller.set_l0_files(3);
+        assert_eq!(
+            controller.state(),
+            WriteState::Delayed(StallReason::L0Files)
+        );
+        controller.admit(1).await;
+        assert_eq!(controller.stall_stats(StallReason::L0Files).delayed, 1);
+        assert_eq!(
+            controller.stall_stats(StallReason::MemtableCount),
+            StallStats::default()
+        );
+
+        controller.set_memtable_count(DEFAULT_HARD_MEMTABLE_COUNT);
+        assert_eq!(
+            controller.state(),
+            WriteState::Stopped(StallReason::MemtableCount)
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use transaction::Transaction;
+use wal::encryption::{KEY_SIZE, KeyProvider};
+use wal::reader::{SegmentEvent, SegmentReader};
+use wal::record::WalRecord;
+use wal::wal::WriteAheadLog;
+
+#[derive(Parser)]
+#[command(name = "wal-tool", about = "inspect write ahead log segments")]
+struct Cli {
+    /// hex encoded 32 byte key for encrypted segments, used for every key id
+    #[arg(long, global = true)]
+    key: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// print every record as a line of JSON
+    Dump {
+        /// segment files or WAL folders
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// check every batch checksum and report the offsets that fail
+    Verify {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// record counts, size distribution and sequence range per segment
+    Stats {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// salvage the intact batches of a damaged segment and quarantine the original. The WAL must
+    /// not be open while this runs
+    Repair { path: PathBuf },
+}
+
+struct StaticKey([u8; KEY_SIZE]);
+
+impl KeyProvider for StaticKey {
+    fn current_key_id(&self) -> u32 {
+        0
+    }
+
+    fn key(&self, _key_id: u32) -> Result<[u8; KEY_SIZE]> {
+        Ok(self.0)
+    }
+}
+
+fn parse_key(hex: &str) -> Result<StaticKey> {
+    if hex.len() != KEY_SIZE * 2 {
+        bail!("key must be {} hex characters", KEY_SIZE * 2);
+    }
+    let mut key = [0u8; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).context("key is not valid hex")?;
+    }
+    Ok(StaticKey(key))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Expands folders into their segments, in sequence order.
+fn segments(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut segments = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            segments.extend(WriteAheadLog::find_wal_files(path)?.unwrap_or_default());
+        } else {
+            segments.push(path.clone());
+        }
+    }
+    Ok(segments)
+}
+
+fn record_json(record: &WalRecord) -> serde_json::Value {
+    match record {
+        WalRecord::Put { key, value } => match Transaction::from_bytes(value) {
+            Ok(transaction) => serde_json::json!({
+                "kind": "put",
+                "key": to_hex(key),
+                "transaction": transaction,
+            }),
+            Err(_) => serde_json::json!({
+                "kind": "put",
+                "key": to_hex(key),
+                "value": to_hex(value),
+            }),
+        },
+        WalRecord::Delete { key } => serde_json::json!({ "kind": "delete", "key": to_hex(key) }),
+        WalRecord::DeleteRange { start, end } => serde_json::json!({
+            "kind": "delete_range",
+            "start": to_hex(start),
+            "end": to_hex(end),
+        }),
+        WalRecord::BatchBegin { batch_id } => {
+            serde_json::json!({ "kind": "batch_begin", "batch_id": batch_id })
+        }
+        WalRecord::BatchCommit { batch_id } => {
+            serde_json::json!({ "kind": "batch_commit", "batch_id": batch_id })
+        }
+        WalRecord::Checkpoint { seq } => serde_json::json!({ "kind": "checkpoint", "seq": seq }),
+    }
+}
+
+fn dump(path: &Path, key_provider: Option<&dyn KeyProvider>) -> Result<()> {
+    let reader = SegmentReader::open(path, key_provider)?;
+    for event in reader {
+        let SegmentEvent::Batch {
+            offset, records, ..
+        } = event?
+        else {
+            continue;
+        };
+        for record in &records {
+            let mut json = record_json(record);
+            json["segment"] = serde_json::json!(path);
+            json["offset"] = serde_json::json!(offset);
+            println!("{}", json);
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the segment is intact.
+fn verify(path: &Path, key_provider: Option<&dyn KeyProvider>) -> Result<bool> {
+    let reader = SegmentReader::open(path, key_provider)?;
+    let mut batches = 0;
+    let mut intact = reader.header().is_some();
+    for event in reader {
+        match event {
+            Ok(SegmentEvent::Batch { .. }) => batches += 1,
+            Ok(SegmentEvent::Corrupt {
+                offset,
+                size,
+                reason,
+            }) => {
+                intact = false;
+                println!(
+                    "{}: bad batch at offset {} ({} bytes): {}",
+                    path.display(),
+                    offset,
+                    size,
+                    reason
+                );
+            }
+            Ok(SegmentEvent::End {
+                offset,
+                reason: Some(reason),
+            }) => println!(
+                "{}: log ends at offset {}: {}",
+                path.display(),
+                offset,
+                reason
+            ),
+            Ok(SegmentEvent::End { reason: None, .. }) => {}
+            Ok(SegmentEvent::Stale { offset, log_number }) => println!(
+                "{}: log ends at offset {}: stale record from log {}",
+                path.display(),
+                offset,
+                log_number
+            ),
+            Err(e) => {
+                intact = false;
+                println!("{}: {:#}", path.display(), e);
+            }
+        }
+    }
+    println!(
+        "{}: {} ({} batches)",
+        path.display(),
+        if intact { "ok" } else { "CORRUPT" },
+        batches
+    );
+    Ok(intact)
+}
+
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+fn stats(path: &Path, key_provider: Option<&dyn KeyProvider>) -> Result<()> {
+    let reader = SegmentReader::open(path, key_provider)?;
+    let header = reader.header();
+    let file_size = reader.file_size();
+    let mut batch_sizes = Vec::new();
+    let mut entry_sizes = Vec::new();
+    let mut corrupt = 0;
+    let mut end = file_size;
+    for event in reader {
+        match event? {
+            SegmentEvent::Batch { size, records, .. } => {
+                batch_sizes.push(size);
+                entry_sizes.extend(records.iter().map(|r| r.encoded_len() as u64));
+            }
+            SegmentEvent::Corrupt { .. } => corrupt += 1,
+            SegmentEvent::End { offset, .. } | SegmentEvent::Stale { offset, .. } => end = offset,
+        }
+    }
+    batch_sizes.sort_unstable();
+    entry_sizes.sort_unstable();
+    let seq_range = WriteAheadLog::extract_seq_range_from_path(path)
+        .map(|(start, end)| format!("[{}, {}]", start, end))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("{}", path.display());
+    println!("  sequence range: {}", seq_range);
+    match header {
+        Some(header) => println!(
+            "  log number: {}, checksum: {:?}, cipher: {:?}",
+            header.log_number, header.checksum, header.cipher
+        ),
+        None => println!("  no segment header"),
+    }
+    println!("  file size: {} bytes, log ends at {}", file_size, end);
+    println!(
+        "  batches: {} ({} corrupt), records: {}",
+        batch_sizes.len(),
+        corrupt,
+        entry_sizes.len()
+    );
+    for (name, sizes) in [("batch", &batch_sizes), ("record", &entry_sizes)] {
+        println!(
+            "  {} bytes: min {} p50 {} p99 {} max {}",
+            name,
+            sizes.first().copied().unwrap_or(0),
+            percentile(sizes, 50),
+            percentile(sizes, 99),
+            sizes.last().copied().unwrap_or(0)
+        );
+    }
+    Ok(())
+}
+
+fn main() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    let key = cli.key.as_deref().map(parse_key).transpose()?;
+    let key_provider = key.as_ref().map(|k| k as &dyn KeyProvider);
+    match cli.command {
+        Command::Dump { paths } => {
+            for path in segments(&paths)? {
+                dump(&path, key_provider)?;
+            }
+        }
+        Command::Verify { paths } => {
+            let mut intact = true;
+            for path in segments(&paths)? {
+                intact &= verify(&path, key_provider)?;
+            }
+            if !intact {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Stats { paths } => {
+            for path in segments(&paths)? {
+                stats(&path, key_provider)?;
+            }
+        }
+        Command::Repair { path } => {
+            let report = WriteAheadLog::repair(&path, key_provider)?;
+            println!(
+                "{}: salvaged {} batches ({} records), original quarantined at {}",
+                path.display(),
+                report.salvaged_batches,
+                report.salvaged_records,
+                report.quarantined.display()
+            );
+            for lost in &report.lost {
+                println!(
+                    "  lost bytes [{}, {}) ({} bytes): {}",
+                    lost.start,
+                    lost.end,
+                    lost.end - lost.start,
+                    lost.reason
+                );
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
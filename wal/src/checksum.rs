@@ -0,0 +1,39 @@
+use anyhow::{Result, bail};
+use std::hash::Hasher;
+
+/// Checksum over each batch record. Recorded in the segment header so a directory holding
+/// segments written with different settings still recovers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum ChecksumType {
+    /// IEEE polynomial, what segments were checksummed with before this was configurable
+    #[default]
+    Crc32 = 0,
+    /// Castagnoli polynomial, hardware accelerated on x86_64 (sse4.2) and aarch64
+    Crc32c = 1,
+    /// xxh3 64 bit, truncated to the low 32 bits like rocksdb does
+    Xxh3 = 2,
+}
+
+impl ChecksumType {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(ChecksumType::Crc32),
+            1 => Ok(ChecksumType::Crc32c),
+            2 => Ok(ChecksumType::Xxh3),
+            other => bail!("unknown checksum type {}", other),
+        }
+    }
+
+    pub fn compute(&self, data: &[u8]) -> u32 {
+        match self {
+            ChecksumType::Crc32 => {
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.write(data);
+                hasher.finalize()
+            }
+            ChecksumType::Crc32c => crc32c::crc32c(data),
+            ChecksumType::Xxh3 => xxhash_rust::xxh3::xxh3_64(data) as u32,
+        }
+    }
+}
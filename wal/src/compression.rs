@@ -0,0 +1,53 @@
+use anyhow::{Context, Result, bail};
+
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Codec applied to a whole batch before it is framed and checksummed. Stored as one byte in the
+/// batch header so batches written with different settings can share a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum CompressionType {
+    #[default]
+    None = 0,
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl CompressionType {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Lz4),
+            2 => Ok(CompressionType::Zstd),
+            other => bail!("unknown compression type {}", other),
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            CompressionType::None => Ok(data.to_vec()),
+            CompressionType::Lz4 => Ok(lz4_flex::block::compress(data)),
+            CompressionType::Zstd => {
+                zstd::bulk::compress(data, DEFAULT_ZSTD_LEVEL).context("zstd compression failed")
+            }
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8], uncompressed_len: usize) -> Result<Vec<u8>> {
+        let decompressed = match self {
+            CompressionType::None => data.to_vec(),
+            CompressionType::Lz4 => lz4_flex::block::decompress(data, uncompressed_len)
+                .context("lz4 decompression failed")?,
+            CompressionType::Zstd => zstd::bulk::decompress(data, uncompressed_len)
+                .context("zstd decompression failed")?,
+        };
+        if decompressed.len() != uncompressed_len {
+            bail!(
+                "decompressed {} bytes, batch header says {}",
+                decompressed.len(),
+                uncompressed_len
+            );
+        }
+        Ok(decompressed)
+    }
+}
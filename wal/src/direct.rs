@@ -0,0 +1,168 @@
+use parking_lot::Mutex;
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::ptr::NonNull;
+
+/// Offset, length and memory alignment `O_DIRECT` writes use. 4KiB covers the logical block size
+/// of every device we run on.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+const DEFAULT_MAX_POOLED_BUFFERS: usize = 8;
+
+pub fn align_up(len: usize) -> usize {
+    len.div_ceil(DIRECT_IO_ALIGNMENT) * DIRECT_IO_ALIGNMENT
+}
+
+/// Opens `path` for writing with `O_DIRECT`, creating and truncating it when `create` is set.
+/// Returns `None` when the filesystem does not support direct I/O (tmpfs rejects it with
+/// `EINVAL`), so the caller can fall back to buffered writes.
+#[cfg(target_os = "linux")]
+pub fn open_direct(path: &Path, create: bool) -> io::Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let result = OpenOptions::new()
+        .write(true)
+        .create(create)
+        .truncate(create)
+        .custom_flags(libc::O_DIRECT)
+        .open(path);
+    match result {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_direct(_path: &Path, _create: bool) -> io::Result<Option<File>> {
+    Ok(None)
+}
+
+/// A fixed capacity byte buffer whose memory is aligned to `DIRECT_IO_ALIGNMENT`, as `O_DIRECT`
+/// requires.
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    capacity: usize,
+}
+
+// the buffer owns its allocation exclusively, like a `Vec<u8>`
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    /// Allocates at least `capacity` bytes, rounded up to whole blocks.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = align_up(capacity.max(1));
+        let layout = Self::layout(capacity);
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        AlignedBuf {
+            ptr,
+            len: 0,
+            capacity,
+        }
+    }
+
+    fn layout(capacity: usize) -> Layout {
+        Layout::from_size_align(capacity, DIRECT_IO_ALIGNMENT).expect("valid aligned layout")
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Panics if `data` does not fit in the remaining capacity.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        assert!(
+            self.len + data.len() <= self.capacity,
+            "aligned buffer overflow"
+        );
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.ptr.as_ptr().add(self.len),
+                data.len(),
+            );
+        }
+        self.len += data.len();
+    }
+
+    /// Zero fills up to the next block boundary.
+    pub fn pad_to_alignment(&mut self) {
+        let padded = align_up(self.len);
+        unsafe {
+            std::ptr::write_bytes(self.ptr.as_ptr().add(self.len), 0, padded - self.len);
+        }
+        self.len = padded;
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.capacity)) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe impl tokio_uring::buf::IoBuf for AlignedBuf {
+    fn stable_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Reuses aligned buffers across writes so the write path does not allocate per batch.
+pub struct AlignedBufferPool {
+    max_pooled: usize,
+    buffers: Mutex<Vec<AlignedBuf>>,
+}
+
+impl Default for AlignedBufferPool {
+    fn default() -> Self {
+        AlignedBufferPool {
+            max_pooled: DEFAULT_MAX_POOLED_BUFFERS,
+            buffers: Mutex::new(Vec::with_capacity(DEFAULT_MAX_POOLED_BUFFERS)),
+        }
+    }
+}
+
+impl AlignedBufferPool {
+    /// An empty buffer that can hold `len` bytes padded to a whole number of blocks.
+    pub fn take(&self, len: usize) -> AlignedBuf {
+        let needed = align_up(len.max(1));
+        let mut buffers = self.buffers.lock();
+        match buffers.iter().position(|buf| buf.capacity() >= needed) {
+            Some(i) => buffers.swap_remove(i),
+            None => AlignedBuf::with_capacity(needed),
+        }
+    }
+
+    pub fn put(&self, mut buf: AlignedBuf) {
+        buf.clear();
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < self.max_pooled {
+            buffers.push(buf);
+        }
+    }
+}
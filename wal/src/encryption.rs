@@ -0,0 +1,125 @@
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use anyhow::{Result, bail};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::sync::Arc;
+
+pub const KEY_SIZE: usize = 32;
+pub const SALT_SIZE: usize = 16;
+pub const TAG_SIZE: usize = 16;
+// HKDF info binding derived keys to their use
+const SEGMENT_KEY_INFO: &[u8] = b"wal segment key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum CipherType {
+    #[default]
+    None = 0,
+    Aes256Gcm = 1,
+    ChaCha20Poly1305 = 2,
+}
+
+impl CipherType {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(CipherType::None),
+            1 => Ok(CipherType::Aes256Gcm),
+            2 => Ok(CipherType::ChaCha20Poly1305),
+            other => bail!("unknown cipher type {}", other),
+        }
+    }
+}
+
+/// Source of the keys segments are encrypted with. Only the key id is persisted in the segment
+/// header, so keys can be rotated as long as old ids stay resolvable until their segments are
+/// checkpointed away.
+pub trait KeyProvider: Send + Sync {
+    /// id of the key new segments are encrypted with
+    fn current_key_id(&self) -> u32;
+    fn key(&self, key_id: u32) -> Result<[u8; KEY_SIZE]>;
+}
+
+#[derive(Clone)]
+pub struct Encryption {
+    pub cipher: CipherType,
+    pub key_provider: Arc<dyn KeyProvider>,
+}
+
+enum Cipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+/// AEAD state for one segment. Records are sealed with a key of their own for the segment,
+/// derived with HKDF-SHA256 from the provider's key and the random salt in the segment header, so
+/// nonces only have to be unique within the segment. They are the offset of the record in the
+/// file, which is known again on recovery and never used twice: a segment is not written to
+/// again after a failed write.
+pub struct SegmentCipher {
+    cipher: Cipher,
+}
+
+impl SegmentCipher {
+    pub fn new(
+        cipher_type: CipherType,
+        key: &[u8; KEY_SIZE],
+        salt: [u8; SALT_SIZE],
+    ) -> Result<Self> {
+        let mut segment_key = [0u8; KEY_SIZE];
+        Hkdf::<Sha256>::new(Some(&salt), key)
+            .expand(SEGMENT_KEY_INFO, &mut segment_key)
+            .map_err(|_| anyhow::anyhow!("failed to derive segment key"))?;
+        let cipher = match cipher_type {
+            CipherType::Aes256Gcm => {
+                Cipher::Aes256Gcm(Box::new(Aes256Gcm::new((&segment_key).into())))
+            }
+            CipherType::ChaCha20Poly1305 => {
+                Cipher::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new((&segment_key).into())))
+            }
+            CipherType::None => bail!("segment cipher requires a cipher type"),
+        };
+        Ok(SegmentCipher { cipher })
+    }
+
+    pub fn random_salt() -> [u8; SALT_SIZE] {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    fn nonce(&self, offset: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&offset.to_be_bytes());
+        nonce
+    }
+
+    pub fn encrypt(&self, offset: u64, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce(offset);
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = match &self.cipher {
+            Cipher::Aes256Gcm(cipher) => cipher.encrypt((&nonce).into(), payload),
+            Cipher::ChaCha20Poly1305(cipher) => cipher.encrypt((&nonce).into(), payload),
+        };
+        ciphertext.map_err(|_| anyhow::anyhow!("failed to encrypt batch at offset {}", offset))
+    }
+
+    /// Returns `None` when the tag does not verify, i.e. the wrong key or tampered data.
+    pub fn decrypt(&self, offset: u64, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = self.nonce(offset);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        match &self.cipher {
+            Cipher::Aes256Gcm(cipher) => cipher.decrypt((&nonce).into(), payload),
+            Cipher::ChaCha20Poly1305(cipher) => cipher.decrypt((&nonce).into(), payload),
+        }
+        .ok()
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum WalError {
+    #[error("authentication failed for batch at offset {offset} of {path:?}")]
+    Authentication { path: PathBuf, offset: u64 },
+    #[error("{path:?} is encrypted but no key provider is configured")]
+    MissingKeyProvider { path: PathBuf },
+    #[error(
+        "sequence gap between segments: [{prev_start}, {prev_end}] is followed by [{next_start}, {next_end}]"
+    )]
+    SequenceGap {
+        prev_start: u64,
+        prev_end: u64,
+        next_start: u64,
+        next_end: u64,
+    },
+    #[error("overlapping segments: [{prev_start}, {prev_end}] and [{next_start}, {next_end}]")]
+    SequenceOverlap {
+        prev_start: u64,
+        prev_end: u64,
+        next_start: u64,
+        next_end: u64,
+    },
+    #[error(
+        "segment [{seq_start}, {seq_end}] is in the WAL manifest but missing from the directory"
+    )]
+    SegmentMissing { seq_start: u64, seq_end: u64 },
+    #[error("{path:?} is not in the WAL manifest and older than its newest sealed segment")]
+    SegmentUnknown { path: PathBuf },
+    #[error("{path:?} was sealed with {size} bytes but only {actual} are left", size = expected)]
+    SegmentTruncated {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    #[error(
+        "checksum mismatch for sealed segment {path:?}. Expected: {expected:#010x}, Got: {actual:#010x}"
+    )]
+    SegmentChecksumMismatch {
+        path: PathBuf,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("a write to {filename} failed before, the segment has to be rotated")]
+    SegmentPoisoned { filename: String },
+    #[error("lane {lane} still holds segments but only {lanes} lanes are configured")]
+    LaneNotConfigured { lane: usize, lanes: usize },
+}
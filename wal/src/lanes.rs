@@ -0,0 +1,455 @@
+use anyhow::{Context, Result, bail};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::WalError;
+use crate::manifest::{SealedSegment, WalManifest};
+use crate::sequence::SequenceAllocator;
+use crate::wal::{
+    self, DEFAULT_QUARANTINE_FOLDER, FolderRecovery, RecoveredWindow, WalFile, WalOptions,
+    WriteAheadLog,
+};
+
+pub const DEFAULT_LANE_FOLDER_PREFIX: &str = "lane-";
+
+/// `N` independent WALs sharing one sequence space, so writer groups on different lanes commit
+/// on separate fsync streams.
+///
+/// Lane `i` lives in `<folder>/lane-<i>` with its own segments, manifest and recycle pool. Sequence
+/// numbers come from a single allocator, so every window still has a global position: a lane
+/// only holds some of the windows and recovery merges all lanes back into one sequence, which
+/// has to be free of gaps and overlaps just like a single WAL. Lanes make their windows durable
+/// independently though, so a crash can leave a window on one lane without an earlier one on
+/// another. Such a window was never published, and recovery sets it aside.
+pub struct MultiLaneWal {
+    lanes: Vec<WriteAheadLog>,
+    // per lane, one past the highest sequence number it made durable, 0 while it has none
+    durable: Vec<AtomicU64>,
+    sequences: Arc<SequenceAllocator>,
+}
+
+impl MultiLaneWal {
+    /// Recovers every lane and returns the windows of all of them ordered by sequence number,
+    /// along with the next sequence number to allocate. Fails if a lane beyond `lanes` still
+    /// holds segments, their windows would otherwise be lost.
+    ///
+    /// Segments past the first gap in the merged sequence are moved to the `quarantine` folder of
+    /// their lane and dropped from its manifest, recovery continues from the gap.
+    pub fn recover(
+        folder: impl AsRef<Path>,
+        lanes: usize,
+        options: WalOptions,
+    ) -> Result<(Self, Option<Vec<RecoveredWindow>>, u64)> {
+        let folder = folder.as_ref();
+        if lanes == 0 {
+            bail!("a multi-lane WAL needs at least one lane");
+        }
+        fs::create_dir_all(folder)?;
+        for entry in fs::read_dir(folder)? {
+            let path = entry?.path();
+            let lane = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix(DEFAULT_LANE_FOLDER_PREFIX))
+                .and_then(|n| n.parse::<usize>().ok());
+            if let Some(lane) = lane
+                && lane >= lanes
+                && path.is_dir()
+                && WriteAheadLog::find_wal_files(&path)?.is_some()
+            {
+                return Err(WalError::LaneNotConfigured { lane, lanes }.into());
+            }
+        }
+
+        let released = Self::released_ranges(folder, lanes)?;
+        let cut = Self::first_gap(folder, lanes, &released)?;
+        if let Some(cut) = cut {
+            for lane in 0..lanes {
+                Self::quarantine_from(&Self::lane_folder(folder, lane), cut)?;
+            }
+        }
+
+        let mut wals = Vec::with_capacity(lanes);
+        let mut durable = Vec::with_capacity(lanes);
+        let mut windows = Vec::new();
+        let mut seq_ranges = Vec::new();
+        let mut next_seq_num = 0;
+        for lane in 0..lanes {
+            let recovery = WriteAheadLog::recover_folder(
+                &Self::lane_folder(folder, lane),
+                options.clone(),
+                false,
+            )?;
+            let FolderRecovery {
+                wal,
+                windows: lane_windows,
+                seq_ranges: lane_ranges,
+                next_seq_num: lane_next,
+            } = recovery;
+            wals.push(wal);
+            durable.push(AtomicU64::new(lane_next));
+            windows.extend(lane_windows);
+            seq_ranges.extend(lane_ranges);
+            next_seq_num = next_seq_num.max(lane_next);
+        }
+        // everything past the first gap was set aside above, what is left is gap free once the
+        // released ranges in between are counted
+        seq_ranges.extend(
+            released
+                .iter()
+                .filter(|(seq_start, _)| cut.is_none_or(|cut| *seq_start < cut)),
+        );
+        seq_ranges.sort_unstable();
+        seq_ranges.dedup();
+        wal::check_contiguous(&seq_ranges, true)?;
+        windows.sort_by_key(|window| window.seq_range().0);
+
+        let wal = MultiLaneWal {
+            lanes: wals,
+            durable,
+            sequences: Arc::new(SequenceAllocator::new(next_seq_num)),
+        };
+        if windows.is_empty() {
+            println!("No windows recovered from {} WAL lanes", lanes);
+            return Ok((wal, None, next_seq_num));
+        }
+        println!(
+            "Recovered {} windows from {} WAL lanes, continuing from sequence number {}",
+            windows.len(),
+            lanes,
+            next_seq_num
+        );
+        Ok((wal, Some(windows), next_seq_num))
+    }
+
+    /// Ranges the manifests of all lanes logged a release for. Retention and checkpoints release
+    /// segments lane by lane, so a released range can sit between live segments of other lanes.
+    fn released_ranges(folder: &Path, lanes: usize) -> Result<Vec<(u64, u64)>> {
+        let mut released = Vec::new();
+        for lane in 0..lanes {
+            let lane_folder = Self::lane_folder(folder, lane);
+            if !lane_folder.is_dir() {
+                continue;
+            }
+            if let Some(state) = WalManifest::load(&lane_folder)? {
+                released.extend(state.released);
+            }
+        }
+        released.sort_unstable();
+        released.dedup();
+        Ok(released)
+    }
+
+    /// Start of the first segment that does not pick up where the ones before it, across all
+    /// lanes, ended. Released ranges fill in for the segments they covered.
+    fn first_gap(folder: &Path, lanes: usize, released: &[(u64, u64)]) -> Result<Option<u64>> {
+        let mut seq_ranges = released.to_vec();
+        for lane in 0..lanes {
+            let lane_folder = Self::lane_folder(folder, lane);
+            if !lane_folder.is_dir() {
+                continue;
+            }
+            for path in WriteAheadLog::find_wal_files(&lane_folder)?
+                .into_iter()
+                .flatten()
+            {
+                if let Some(range) = WriteAheadLog::extract_seq_range_from_path(&path) {
+                    seq_ranges.push(range);
+                }
+            }
+        }
+        seq_ranges.sort_unstable();
+        seq_ranges.dedup();
+        wal::check_contiguous(&seq_ranges, false)?;
+        Ok(seq_ranges
+            .windows(2)
+            .find(|pair| pair[1].0 > pair[0].1 + 1)
+            .map(|pair| pair[1].0))
+    }
+
+    /// Sets aside the segments of a lane starting at or after `cut`. The manifest goes first: a
+    /// crash before the files are moved leaves segments it does not know about past everything
+    /// it covers, which the next recovery takes for unsealed ones and sets aside again.
+    fn quarantine_from(lane_folder: &Path, cut: u64) -> Result<()> {
+        if !lane_folder.is_dir() {
+            return Ok(());
+        }
+        if let Some(state) = WalManifest::load(lane_folder)? {
+            let sealed: Vec<_> = state
+                .sealed
+                .values()
+                .filter(|segment| segment.seq_start < cut)
+                .copied()
+                .collect();
+            if sealed.len() < state.sealed.len() {
+                WalManifest::rewrite(
+                    lane_folder,
+                    &sealed,
+                    state.max_released(),
+                    state.max_log_number,
+                )?;
+            }
+        }
+        let mut moved = false;
+        for path in WriteAheadLog::find_wal_files(lane_folder)?
+            .into_iter()
+            .flatten()
+        {
+            let Some((seq_start, seq_end)) = WriteAheadLog::extract_seq_range_from_path(&path)
+            else {
+                continue;
+            };
+            let Some(filename) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if seq_start < cut {
+                continue;
+            }
+            let quarantined = wal::quarantine_path(lane_folder, filename)?;
+            eprintln!(
+                "Warning: Quarantining WAL segment [{}, {}] of {:?} past a sequence gap",
+                seq_start, seq_end, lane_folder
+            );
+            fs::rename(&path, &quarantined)
+                .context(format!("failed to quarantine WAL file: {:?}", path))?;
+            moved = true;
+        }
+        if moved {
+            File::open(lane_folder.join(DEFAULT_QUARANTINE_FOLDER))?.sync_all()?;
+            File::open(lane_folder)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn lane_folder(folder: &Path, lane: usize) -> PathBuf {
+        folder.join(format!("{}{:03}", DEFAULT_LANE_FOLDER_PREFIX, lane))
+    }
+
+    pub fn lanes(&self) -> usize {
+        self.lanes.len()
+    }
+
+    /// Lane a writer group commits to. Groups keep their lane so their windows stay in order.
+    pub fn lane_for(&self, group: u64) -> usize {
+        (group % self.lanes.len() as u64) as usize
+    }
+
+    pub fn lane(&self, lane: usize) -> &WriteAheadLog {
+        &self.lanes[lane]
+    }
+
+    /// Sequence numbers for every lane, continuing after everything recovered.
+    pub fn sequences(&self) -> Arc<SequenceAllocator> {
+        self.sequences.clone()
+    }
+
+    /// Opens the segment for `[seq_start, seq_end]` on `lane`. The range has to come from
+    /// `sequences`.
+    pub fn new_wal_file(&self, lane: usize, seq_start: u64, seq_end: u64) -> Result<WalFile<'_>> {
+        self.lanes[lane].new_wal_file(seq_start, seq_end)
+    }
+
+    /// Writes the window of `file` to `lane`. Once it is durable the lane's high water mark moves
+    /// up and the range is published, returning the highest sequence number readers may see
+    /// across all lanes.
+    pub async fn commit_window(
+        &self,
+        lane: usize,
+        file: &mut WalFile<'_>,
+        entries: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<Option<u64>> {
+        self.lanes[lane].put_batch_async(file, entries).await?;
+        let seq_end = file.seq_end.unwrap_or(file.seq_start);
+        self.durable[lane].fetch_max(seq_end + 1, Ordering::SeqCst);
+        self.sequences.publish(file.seq_start, seq_end)
+    }
+
+    pub fn seal_wal_file(&self, lane: usize, file: &mut WalFile) -> Result<SealedSegment> {
+        self.lanes[lane].seal_wal_file(file)
+    }
+
+    /// Highest sequence number `lane` has made durable, recovered segments included.
+    pub fn durable_seq(&self, lane: usize) -> Option<u64> {
+        self.durable[lane].load(Ordering::SeqCst).checked_sub(1)
+    }
+
+    pub fn durable_sequences(&self) -> Vec<Option<u64>> {
+        (0..self.lanes.len())
+            .map(|lane| self.durable_seq(lane))
+            .collect()
+    }
+
+    /// Retires the checkpointed segment for `[seq_start, seq_end]` from whichever lane holds it
+    /// and returns that lane.
+    pub fn release_wal_file_by_seq(&self, seq_start: u64, seq_end: u64) -> Result<Option<usize>> {
+        for (lane, wal) in self.lanes.iter().enumerate() {
+            if wal.release_wal_file_by_seq(seq_start, seq_end)? {
+                return Ok(Some(lane));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use transaction::Transaction;
+
+    fn window(timestamps: std::ops::RangeInclusive<u64>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        timestamps
+            .map(|i| {
+                let tx = Transaction::new_with_timestamp(Default::default(), Default::default(), i);
+                (tx.timestamp.to_be_bytes().to_vec(), tx.to_bytes().unwrap())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_lanes_merge_by_sequence_on_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, windows, _) =
+            MultiLaneWal::recover(dir.path(), 2, WalOptions::default()).unwrap();
+        assert!(windows.is_none());
+        let sequences = wal.sequences();
+        let ranges: Vec<_> = (0..4).map(|_| sequences.allocate_range(3)).collect();
+        // later windows become durable first, nothing is visible until the first one is
+        for (i, &(seq_start, seq_end)) in ranges.iter().enumerate().rev() {
+            let lane = wal.lane_for(i as u64);
+            let mut file = wal.new_wal_file(lane, seq_start, seq_end).unwrap();
+            let visible = wal
+                .commit_window(lane, &mut file, &window(seq_start..=seq_end))
+                .await
+                .unwrap();
+            assert_eq!(visible, (i == 0).then_some(11));
+        }
+        assert_eq!(wal.durable_sequences(), vec![Some(8), Some(11)]);
+        drop(wal);
+
+        let (wal, windows, next_seq_num) =
+            MultiLaneWal::recover(dir.path(), 2, WalOptions::default()).unwrap();
+        let windows = windows.unwrap();
+        assert_eq!(next_seq_num, 12);
+        assert_eq!(
+            windows.iter().map(|w| w.seq_range()).collect::<Vec<_>>(),
+            ranges
+        );
+        assert_eq!(windows[3].transactions()[0].timestamp, 9);
+        assert_eq!(wal.durable_sequences(), vec![Some(8), Some(11)]);
+        assert_eq!(wal.release_wal_file_by_seq(3, 5).unwrap(), Some(1));
+        assert_eq!(wal.release_wal_file_by_seq(3, 5).unwrap(), None);
+    }
+
+    #[test]
+    fn test_recover_rejects_dropped_lanes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = MultiLaneWal::recover(dir.path(), 3, WalOptions::default()).unwrap();
+        let mut file = wal.new_wal_file(0, 0, 4).unwrap();
+        wal.lane(0).put_batch(&mut file, &window(0..=4)).unwrap();
+        let mut file = wal.new_wal_file(2, 5, 9).unwrap();
+        wal.lane(2).put_batch(&mut file, &window(5..=9)).unwrap();
+        drop(wal);
+
+        let err = MultiLaneWal::recover(dir.path(), 2, WalOptions::default())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WalError>(),
+            Some(WalError::LaneNotConfigured { lane: 2, lanes: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_recover_sets_aside_windows_past_a_gap() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = MultiLaneWal::recover(dir.path(), 3, WalOptions::default()).unwrap();
+        let sequences = wal.sequences();
+        let ranges: Vec<_> = (0..3).map(|_| sequences.allocate_range(3)).collect();
+        // the process dies while lane 1 is still writing the middle window, lane 2 was faster
+        for lane in [0, 2] {
+            let (seq_start, seq_end) = ranges[lane];
+            let mut file = wal.new_wal_file(lane, seq_start, seq_end).unwrap();
+            wal.commit_window(lane, &mut file, &window(seq_start..=seq_end))
+                .await
+                .unwrap();
+            wal.seal_wal_file(lane, &mut file).unwrap();
+        }
+        drop(wal);
+
+        let (wal, windows, next_seq_num) =
+            MultiLaneWal::recover(dir.path(), 3, WalOptions::default()).unwrap();
+        let windows = windows.unwrap();
+        assert_eq!(
+            windows.iter().map(|w| w.seq_range()).collect::<Vec<_>>(),
+            vec![(0, 2)]
+        );
+        assert_eq!(next_seq_num, 3);
+        let lane2 = MultiLaneWal::lane_folder(dir.path(), 2);
+        assert!(WriteAheadLog::find_wal_files(&lane2).unwrap().is_none());
+        assert!(
+            lane2
+                .join(DEFAULT_QUARANTINE_FOLDER)
+                .join("wal-00000000000000000006-00000000000000000008.log")
+                .exists()
+        );
+
+        // the numbers set aside are handed out again and the next restart is clean
+        let sequences = wal.sequences();
+        for lane in [1, 2] {
+            let (seq_start, seq_end) = sequences.allocate_range(3);
+            let mut file = wal.new_wal_file(lane, seq_start, seq_end).unwrap();
+            wal.commit_window(lane, &mut file, &window(seq_start..=seq_end))
+                .await
+                .unwrap();
+        }
+        drop(wal);
+        let (_, windows, next_seq_num) =
+            MultiLaneWal::recover(dir.path(), 3, WalOptions::default()).unwrap();
+        assert_eq!(
+            windows
+                .unwrap()
+                .iter()
+                .map(|w| w.seq_range())
+                .collect::<Vec<_>>(),
+            ranges
+        );
+        assert_eq!(next_seq_num, 9);
+    }
+
+    #[tokio::test]
+    async fn test_recover_counts_released_ranges_as_contiguous() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = MultiLaneWal::recover(dir.path(), 2, WalOptions::default()).unwrap();
+        let sequences = wal.sequences();
+        let ranges: Vec<_> = (0..3).map(|_| sequences.allocate_range(3)).collect();
+        for (lane, (seq_start, seq_end)) in [0, 1, 0].into_iter().zip(ranges.iter().copied()) {
+            let mut file = wal.new_wal_file(lane, seq_start, seq_end).unwrap();
+            wal.commit_window(lane, &mut file, &window(seq_start..=seq_end))
+                .await
+                .unwrap();
+            wal.seal_wal_file(lane, &mut file).unwrap();
+        }
+        // lane 1 checkpointed its window while lane 0 still holds the ones around it
+        assert_eq!(wal.release_wal_file_by_seq(3, 5).unwrap(), Some(1));
+        drop(wal);
+
+        for _ in 0..2 {
+            let (_, windows, next_seq_num) =
+                MultiLaneWal::recover(dir.path(), 2, WalOptions::default()).unwrap();
+            assert_eq!(
+                windows
+                    .unwrap()
+                    .iter()
+                    .map(|w| w.seq_range())
+                    .collect::<Vec<_>>(),
+                vec![ranges[0], ranges[2]]
+            );
+            assert_eq!(next_seq_num, 9);
+            let quarantine =
+                MultiLaneWal::lane_folder(dir.path(), 0).join(DEFAULT_QUARANTINE_FOLDER);
+            assert!(!quarantine.exists());
+        }
+    }
+}
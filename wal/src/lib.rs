@@ -5,5 +5,19 @@ use std::sync::Arc;
 // use crate::wal;
 // use crate::window;
 
-mod wal;
-mod window;
+pub mod backpressure;
+pub mod checksum;
+pub mod compression;
+pub mod direct;
+pub mod encryption;
+pub mod error;
+pub mod lanes;
+pub mod manifest;
+pub mod reader;
+pub mod record;
+pub mod retention;
+pub mod segment;
+pub mod sequence;
+pub mod wal;
+pub mod window;
+pub mod writer;
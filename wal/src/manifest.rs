@@ -0,0 +1,246 @@
+use anyhow::{Context, Result};
+use bytes::BufMut;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::WalError;
+
+pub const DEFAULT_WAL_MANIFEST_FILE: &str = "wal-manifest";
+
+const RECORD_SEALED: u8 = 1;
+const RECORD_RELEASED: u8 = 2;
+// kind | seq_start | seq_end | log_number | size | checksum | record crc
+const RECORD_SIZE: usize = 1 + 8 + 8 + 4 + 8 + 4 + 4;
+
+/// A segment that will not be written to again, along with the crc32c of its first `size` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SealedSegment {
+    pub seq_start: u64,
+    pub seq_end: u64,
+    pub log_number: u32,
+    pub size: u64,
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestRecord {
+    Sealed(SealedSegment),
+    /// the segment was checkpointed and recycled or deleted
+    Released {
+        seq_start: u64,
+        seq_end: u64,
+        log_number: u32,
+    },
+}
+
+impl ManifestRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(RECORD_SIZE);
+        match self {
+            ManifestRecord::Sealed(segment) => {
+                buf.put_u8(RECORD_SEALED);
+                buf.put_u64(segment.seq_start);
+                buf.put_u64(segment.seq_end);
+                buf.put_u32(segment.log_number);
+                buf.put_u64(segment.size);
+                buf.put_u32(segment.checksum);
+            }
+            ManifestRecord::Released {
+                seq_start,
+                seq_end,
+                log_number,
+            } => {
+                buf.put_u8(RECORD_RELEASED);
+                buf.put_u64(*seq_start);
+                buf.put_u64(*seq_end);
+                buf.put_u32(*log_number);
+                buf.put_bytes(0, 8 + 4);
+            }
+        }
+        buf.put_u32(crc32c::crc32c(&buf));
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let stored_crc = u32::from_be_bytes(buf[RECORD_SIZE - 4..RECORD_SIZE].try_into().ok()?);
+        if crc32c::crc32c(&buf[..RECORD_SIZE - 4]) != stored_crc {
+            return None;
+        }
+        let seq_start = u64::from_be_bytes(buf[1..9].try_into().ok()?);
+        let seq_end = u64::from_be_bytes(buf[9..17].try_into().ok()?);
+        let log_number = u32::from_be_bytes(buf[17..21].try_into().ok()?);
+        match buf[0] {
+            RECORD_SEALED => Some(ManifestRecord::Sealed(SealedSegment {
+                seq_start,
+                seq_end,
+                log_number,
+                size: u64::from_be_bytes(buf[21..29].try_into().ok()?),
+                checksum: u32::from_be_bytes(buf[29..33].try_into().ok()?),
+            })),
+            RECORD_RELEASED => Some(ManifestRecord::Released {
+                seq_start,
+                seq_end,
+                log_number,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// What the manifest says the directory should hold after replaying every record.
+#[derive(Default)]
+pub struct ManifestState {
+    pub sealed: BTreeMap<u64, SealedSegment>,
+    pub released: Vec<(u64, u64)>,
+    /// highest sequence number any segment, live or released, ever covered
+    pub max_seq_end: Option<u64>,
+    /// highest log number any segment, live or released, was written with
+    pub max_log_number: u32,
+}
+
+impl ManifestState {
+    fn apply(&mut self, record: ManifestRecord) -> Result<()> {
+        match record {
+            ManifestRecord::Sealed(segment) => {
+                let overlapping = self
+                    .sealed
+                    .values()
+                    .find(|s| s.seq_start <= segment.seq_end && segment.seq_start <= s.seq_end);
+                if let Some(existing) = overlapping {
+                    return Err(WalError::SequenceOverlap {
+                        prev_start: existing.seq_start,
+                        prev_end: existing.seq_end,
+                        next_start: segment.seq_start,
+                        next_end: segment.seq_end,
+                    }
+                    .into());
+                }
+                self.sealed.insert(segment.seq_start, segment);
+                self.bump_max_seq_end(segment.seq_end);
+                self.max_log_number = self.max_log_number.max(segment.log_number);
+            }
+            ManifestRecord::Released {
+                seq_start,
+                seq_end,
+                log_number,
+            } => {
+                self.sealed.remove(&seq_start);
+                self.released.push((seq_start, seq_end));
+                self.bump_max_seq_end(seq_end);
+                self.max_log_number = self.max_log_number.max(log_number);
+            }
+        }
+        Ok(())
+    }
+
+    /// The release covering the highest sequence numbers, all a rewritten manifest has to keep of
+    /// the released segments.
+    pub fn max_released(&self) -> Option<(u64, u64)> {
+        self.released
+            .iter()
+            .copied()
+            .max_by_key(|(_, seq_end)| *seq_end)
+    }
+
+    fn bump_max_seq_end(&mut self, seq_end: u64) {
+        self.max_seq_end = Some(self.max_seq_end.map_or(seq_end, |max| max.max(seq_end)));
+    }
+}
+
+/// Append-only log of sealed and released segments. The directory listing alone cannot tell a
+/// segment that was checkpointed away from one that went missing, the manifest can.
+pub struct WalManifest {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl WalManifest {
+    /// Replays the manifest in `folder`. Returns `None` when there is none yet. A torn record at
+    /// the tail is what a crash during `append` leaves behind and is dropped.
+    pub fn load(folder: &Path) -> Result<Option<ManifestState>> {
+        let path = folder.join(DEFAULT_WAL_MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(&path)
+            .with_context(|| format!("Failed to open WAL manifest: {:?}", path))?;
+        let mut buffer = Vec::new();
+        BufReader::new(file).read_to_end(&mut buffer)?;
+        let mut state = ManifestState::default();
+        for (i, chunk) in buffer.chunks(RECORD_SIZE).enumerate() {
+            let record = (chunk.len() == RECORD_SIZE)
+                .then(|| ManifestRecord::decode(chunk))
+                .flatten();
+            let Some(record) = record else {
+                eprintln!(
+                    "Warning: Torn WAL manifest record at position {}",
+                    i * RECORD_SIZE
+                );
+                break;
+            };
+            state.apply(record)?;
+        }
+        Ok(Some(state))
+    }
+
+    /// Writes a fresh manifest holding only `sealed` (plus a release marker preserving the
+    /// highest sequence number and log number seen) and atomically swaps it in.
+    pub fn rewrite(
+        folder: &Path,
+        sealed: &[SealedSegment],
+        max_released: Option<(u64, u64)>,
+        max_log_number: u32,
+    ) -> Result<Self> {
+        let path = folder.join(DEFAULT_WAL_MANIFEST_FILE);
+        let tmp_path = folder.join(format!("{}.tmp", DEFAULT_WAL_MANIFEST_FILE));
+        let mut buf = Vec::with_capacity((sealed.len() + 1) * RECORD_SIZE);
+        if let Some((seq_start, seq_end)) = max_released {
+            let marker = ManifestRecord::Released {
+                seq_start,
+                seq_end,
+                log_number: max_log_number,
+            };
+            buf.extend_from_slice(&marker.encode());
+        }
+        for segment in sealed {
+            buf.extend_from_slice(&ManifestRecord::Sealed(*segment).encode());
+        }
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&buf)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        File::open(folder)?.sync_all()?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(WalManifest {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, record: ManifestRecord) -> Result<()> {
+        let mut file = self.file.lock();
+        file.write_all(&record.encode())
+            .with_context(|| format!("Failed to append to WAL manifest: {:?}", self.path))?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// crc32c of the first `size` bytes of `path`, the same value `WalFile` tracks while writing.
+pub fn segment_checksum(path: &Path, size: u64) -> Result<u32> {
+    let file = File::open(path).with_context(|| format!("Failed to open WAL file: {:?}", path))?;
+    let mut buffer = Vec::with_capacity(size as usize);
+    BufReader::new(file).take(size).read_to_end(&mut buffer)?;
+    if (buffer.len() as u64) < size {
+        return Err(WalError::SegmentTruncated {
+            path: path.to_path_buf(),
+            expected: size,
+            actual: buffer.len() as u64,
+        }
+        .into());
+    }
+    Ok(crc32c::crc32c(&buffer))
+}
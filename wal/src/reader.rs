@@ -0,0 +1,256 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::compression::CompressionType;
+use crate::direct::align_up;
+use crate::encryption::{CipherType, KeyProvider, SegmentCipher};
+use crate::error::WalError;
+use crate::record::WalRecord;
+use crate::segment::{SEGMENT_HEADER_SIZE, SegmentHeader};
+use crate::wal::{BATCH_AAD_SIZE, BATCH_HEADER_SIZE};
+
+/// One step through a segment, see `SegmentReader`.
+#[derive(Debug)]
+pub enum SegmentEvent {
+    /// a batch that passed its checksum (and authentication when encrypted), decompressed into
+    /// its records
+    Batch {
+        offset: u64,
+        size: u64,
+        compression: CompressionType,
+        records: Vec<WalRecord>,
+    },
+    /// a complete batch that could not be used, reading carries on after it
+    Corrupt {
+        offset: u64,
+        size: u64,
+        reason: String,
+    },
+    /// the log ends at `offset`. `reason` is set unless it ended at end of file or in unwritten
+    /// preallocated space
+    End { offset: u64, reason: Option<String> },
+    /// the log ends at `offset`, where a record left over from an earlier use of a recycled file
+    /// starts
+    Stale { offset: u64, log_number: u32 },
+}
+
+/// Walks the batch records of a segment. This is the parsing recovery uses, exposed so tooling
+/// sees a segment exactly the way recovery does.
+pub struct SegmentReader {
+    path: PathBuf,
+    buffer: Vec<u8>,
+    header: Option<SegmentHeader>,
+    cipher: Option<SegmentCipher>,
+    pos: usize,
+    done: bool,
+}
+
+impl SegmentReader {
+    pub fn open(path: &Path, key_provider: Option<&dyn KeyProvider>) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open WAL file: {:?}", path))?;
+        let mut buffer = Vec::new();
+        BufReader::new(file).read_to_end(&mut buffer)?;
+        let mut reader = SegmentReader {
+            path: path.to_path_buf(),
+            buffer,
+            header: None,
+            cipher: None,
+            pos: SEGMENT_HEADER_SIZE,
+            done: false,
+        };
+        if reader.buffer.len() < SEGMENT_HEADER_SIZE {
+            return Ok(reader);
+        }
+        let header = SegmentHeader::decode(&reader.buffer)
+            .with_context(|| format!("Invalid segment header in WAL file: {:?}", path))?;
+        reader.cipher = match header.cipher {
+            CipherType::None => None,
+            cipher_type => {
+                let key_provider = key_provider.ok_or_else(|| WalError::MissingKeyProvider {
+                    path: path.to_path_buf(),
+                })?;
+                let key = key_provider.key(header.key_id)?;
+                Some(SegmentCipher::new(cipher_type, &key, header.salt)?)
+            }
+        };
+        reader.header = Some(header);
+        Ok(reader)
+    }
+
+    /// `None` when the file is too short to even hold a segment header.
+    pub fn header(&self) -> Option<SegmentHeader> {
+        self.header
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.buffer.len() as u64
+    }
+
+    fn read_u32(&self, pos: usize) -> u32 {
+        u32::from_be_bytes([
+            self.buffer[pos],
+            self.buffer[pos + 1],
+            self.buffer[pos + 2],
+            self.buffer[pos + 3],
+        ])
+    }
+
+    fn end(&self, offset: usize, reason: Option<String>) -> SegmentEvent {
+        SegmentEvent::End {
+            offset: offset as u64,
+            reason,
+        }
+    }
+
+    /// Whether a complete batch of this segment whose checksum matches starts at `pos`.
+    fn frame_valid_at(&self, header: &SegmentHeader, pos: usize) -> bool {
+        if pos + BATCH_HEADER_SIZE > self.buffer.len() || self.read_u32(pos) != header.log_number {
+            return false;
+        }
+        let data_end = pos + BATCH_HEADER_SIZE + self.read_u32(pos + 9) as usize;
+        if data_end + 4 > self.buffer.len() {
+            return false;
+        }
+        header.checksum.compute(&self.buffer[pos..data_end]) == self.read_u32(data_end)
+    }
+
+    /// Scans forward from `from` for the next offset where a batch length and checksum pair
+    /// validates and continues reading from there. Returns that offset, or `None` (leaving the
+    /// reader finished) when nothing past `from` validates.
+    pub fn resync(&mut self, from: u64) -> Option<u64> {
+        let header = self.header?;
+        let from = (from as usize).max(SEGMENT_HEADER_SIZE);
+        let found = (from..self.buffer.len()).find(|&pos| self.frame_valid_at(&header, pos))?;
+        self.pos = found;
+        self.done = false;
+        Some(found as u64)
+    }
+
+    /// Offset just past the last non-zero byte, i.e. where the written part of a preallocated
+    /// file ends.
+    pub fn written_len(&self) -> u64 {
+        self.buffer
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |i| i + 1) as u64
+    }
+
+    fn next_event(&mut self) -> Result<SegmentEvent> {
+        let Some(header) = self.header else {
+            return Ok(self.end(0, Some("no segment header".to_string())));
+        };
+        let buffer_len = self.buffer.len();
+        let data_start = self.pos;
+        if data_start + 4 > buffer_len {
+            return Ok(self.end(data_start, None));
+        }
+        let log_number = self.read_u32(data_start);
+        // zeroes are unwritten preallocated space, any other log number is what a recycled
+        // file held before it was reused. either way the log ends here.
+        if log_number == 0 {
+            // O_DIRECT segments pad every write with zeroes up to the next block, the next record
+            // of this log may start there
+            let next_block = align_up(data_start);
+            if next_block > data_start
+                && next_block + 4 <= buffer_len
+                && self.buffer[data_start..next_block].iter().all(|&b| b == 0)
+                && self.read_u32(next_block) == header.log_number
+            {
+                self.pos = next_block;
+                return self.next_event();
+            }
+            return Ok(self.end(data_start, None));
+        }
+        if log_number != header.log_number {
+            return Ok(SegmentEvent::Stale {
+                offset: data_start as u64,
+                log_number,
+            });
+        }
+        if data_start + BATCH_HEADER_SIZE > buffer_len {
+            let reason = "incomplete batch header".to_string();
+            return Ok(self.end(data_start, Some(reason)));
+        }
+        let compression = self.buffer[data_start + 4];
+        let uncompressed_len = self.read_u32(data_start + 5) as usize;
+        let payload_len = self.read_u32(data_start + 9) as usize;
+        let payload_start = data_start + BATCH_HEADER_SIZE;
+        let data_end = payload_start + payload_len;
+        if data_end > buffer_len {
+            let reason = "incomplete batch payload".to_string();
+            return Ok(self.end(data_start, Some(reason)));
+        }
+        if data_end + 4 > buffer_len {
+            let reason = "incomplete checksum".to_string();
+            return Ok(self.end(data_start, Some(reason)));
+        }
+        let stored_checksum = self.read_u32(data_end);
+        self.pos = data_end + 4;
+        let offset = data_start as u64;
+        let size = (self.pos - data_start) as u64;
+        let computed_checksum = header.checksum.compute(&self.buffer[data_start..data_end]);
+        if stored_checksum != computed_checksum {
+            return Ok(SegmentEvent::Corrupt {
+                offset,
+                size,
+                reason: format!(
+                    "{:?} checksum mismatch. Expected: {:#010x}, Got: {:#010x}",
+                    header.checksum, computed_checksum, stored_checksum
+                ),
+            });
+        }
+        let payload = &self.buffer[payload_start..data_end];
+        // the checksum already ruled out torn or corrupted writes, so a tag that does not verify
+        // means the wrong key or tampering and reading must not carry on
+        let payload = match &self.cipher {
+            Some(cipher) => {
+                let aad = &self.buffer[data_start..data_start + BATCH_AAD_SIZE];
+                cipher
+                    .decrypt(offset, aad, payload)
+                    .ok_or_else(|| WalError::Authentication {
+                        path: self.path.clone(),
+                        offset,
+                    })?
+            }
+            None => payload.to_vec(),
+        };
+        let decoded = CompressionType::from_u8(compression).and_then(|compression| {
+            let records = compression.decompress(&payload, uncompressed_len)?;
+            Ok((compression, WalRecord::decode_all(&records)?))
+        });
+        match decoded {
+            Ok((compression, records)) => Ok(SegmentEvent::Batch {
+                offset,
+                size,
+                compression,
+                records,
+            }),
+            Err(e) => Ok(SegmentEvent::Corrupt {
+                offset,
+                size,
+                reason: e.to_string(),
+            }),
+        }
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = Result<SegmentEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let event = self.next_event();
+        if matches!(
+            event,
+            Ok(SegmentEvent::End { .. }) | Ok(SegmentEvent::Stale { .. }) | Err(_)
+        ) {
+            self.done = true;
+        }
+        Some(event)
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{Result, bail};
+use bytes::BufMut;
+
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+const KIND_DELETE_RANGE: u8 = 3;
+const KIND_BATCH_BEGIN: u8 = 4;
+const KIND_BATCH_COMMIT: u8 = 5;
+const KIND_CHECKPOINT: u8 = 6;
+
+/// One logical entry of a batch payload, encoded as `kind: u8` followed by its fields. Keys and
+/// values are `len: u32 | bytes`, ids and sequence numbers are big endian `u64`.
+///
+/// Records between a `BatchBegin` and the `BatchCommit` with the same id form an atomic batch
+/// that may span several physical batches of a segment. Recovery only replays it once the commit
+/// is found, records outside of such a bracket take effect on their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalRecord {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// removes every key in `[start, end)`
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    BatchBegin {
+        batch_id: u64,
+    },
+    BatchCommit {
+        batch_id: u64,
+    },
+    /// everything up to and including `seq` has been flushed out of the WAL
+    Checkpoint {
+        seq: u64,
+    },
+}
+
+impl WalRecord {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            WalRecord::Put { key, value } => Self::encode_put(buf, key, value),
+            WalRecord::Delete { key } => {
+                buf.put_u8(KIND_DELETE);
+                put_bytes(buf, key);
+            }
+            WalRecord::DeleteRange { start, end } => {
+                buf.put_u8(KIND_DELETE_RANGE);
+                put_bytes(buf, start);
+                put_bytes(buf, end);
+            }
+            WalRecord::BatchBegin { batch_id } => {
+                buf.put_u8(KIND_BATCH_BEGIN);
+                buf.put_u64(*batch_id);
+            }
+            WalRecord::BatchCommit { batch_id } => {
+                buf.put_u8(KIND_BATCH_COMMIT);
+                buf.put_u64(*batch_id);
+            }
+            WalRecord::Checkpoint { seq } => {
+                buf.put_u8(KIND_CHECKPOINT);
+                buf.put_u64(*seq);
+            }
+        }
+    }
+
+    /// Encodes a `Put` without having to own the key and value.
+    pub fn encode_put(buf: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+        buf.put_u8(KIND_PUT);
+        put_bytes(buf, key);
+        put_bytes(buf, value);
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        let bytes_len = |bytes: &[u8]| 4 + bytes.len();
+        1 + match self {
+            WalRecord::Put { key, value } => bytes_len(key) + bytes_len(value),
+            WalRecord::Delete { key } => bytes_len(key),
+            WalRecord::DeleteRange { start, end } => bytes_len(start) + bytes_len(end),
+            WalRecord::BatchBegin { .. }
+            | WalRecord::BatchCommit { .. }
+            | WalRecord::Checkpoint { .. } => 8,
+        }
+    }
+
+    /// Splits a decompressed batch payload into its records.
+    pub fn decode_all(buf: &[u8]) -> Result<Vec<WalRecord>> {
+        let mut decoder = Decoder { buf, pos: 0 };
+        let mut records = Vec::new();
+        while decoder.pos < buf.len() {
+            let kind = decoder.u8()?;
+            let record = match kind {
+                KIND_PUT => WalRecord::Put {
+                    key: decoder.bytes()?,
+                    value: decoder.bytes()?,
+                },
+                KIND_DELETE => WalRecord::Delete {
+                    key: decoder.bytes()?,
+                },
+                KIND_DELETE_RANGE => WalRecord::DeleteRange {
+                    start: decoder.bytes()?,
+                    end: decoder.bytes()?,
+                },
+                KIND_BATCH_BEGIN => WalRecord::BatchBegin {
+                    batch_id: decoder.u64()?,
+                },
+                KIND_BATCH_COMMIT => WalRecord::BatchCommit {
+                    batch_id: decoder.u64()?,
+                },
+                KIND_CHECKPOINT => WalRecord::Checkpoint {
+                    seq: decoder.u64()?,
+                },
+                other => bail!(
+                    "unknown record kind {} at batch offset {}",
+                    other,
+                    decoder.pos - 1
+                ),
+            };
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
+
+/// The uncompressed payload of a batch holding `records`.
+pub fn encode_records(records: &[WalRecord]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(records.iter().map(WalRecord::encoded_len).sum());
+    for record in records {
+        record.encode(&mut buf);
+    }
+    buf
+}
+
+/// The uncompressed payload of a batch of `Put` records.
+pub fn encode_puts(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(
+        entries
+            .iter()
+            .map(|(key, value)| 1 + 4 + key.len() + 4 + value.len())
+            .sum(),
+    );
+    for (key, value) in entries {
+        WalRecord::encode_put(&mut buf, key, value);
+    }
+    buf
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.put_u32(bytes.len() as u32);
+    buf.put_slice(bytes);
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.pos + len > self.buf.len() {
+            bail!("incomplete record at batch offset {}", self.pos);
+        }
+        let slice = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = u32::from_be_bytes(self.take(4)?.try_into()?) as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
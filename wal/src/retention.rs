@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::wal::WriteAheadLog;
+
+pub const DEFAULT_RETAINED_FILE_PREFIX: &str = "retained";
+pub const DEFAULT_ARCHIVE_FOLDER: &str = "archive";
+
+/// How long checkpointed segments stick around before they are deleted or archived. With neither
+/// `ttl` nor `max_size` set segments leave retention as soon as they are released.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionOptions {
+    /// keeps a segment for this long after its release
+    pub ttl: Option<Duration>,
+    /// upper bound on the bytes of all retained segments, the oldest ones go first
+    pub max_size: Option<u64>,
+    /// moves segments leaving retention to `archive/` instead of deleting them
+    pub archive: bool,
+}
+
+impl RetentionOptions {
+    /// Whether released segments go through retention at all rather than being recycled or
+    /// deleted right away.
+    pub fn enabled(&self) -> bool {
+        self.ttl.is_some() || self.max_size.is_some() || self.archive
+    }
+}
+
+/// What one `enforce` pass did with the segments that left retention.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub deleted: Vec<PathBuf>,
+    pub archived: Vec<PathBuf>,
+}
+
+/// Keeps released segments next to the live ones under a `retained-` prefix, so recovery and
+/// the recycle pool leave them alone while point-in-time recovery and followers catching up can
+/// still read them.
+pub struct Retention {
+    options: RetentionOptions,
+    // serializes enforcement with segments being retained
+    lock: Mutex<()>,
+}
+
+impl Retention {
+    pub fn new(options: RetentionOptions) -> Self {
+        Retention {
+            options,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn options(&self) -> &RetentionOptions {
+        &self.options
+    }
+
+    /// Moves the released `wal_path` into retention and stamps it with the release time the TTL
+    /// counts from. Returns false when retention is disabled and the caller should recycle or
+    /// delete the segment instead.
+    pub fn retain(&self, wal_path: &Path) -> Result<bool> {
+        if !self.options.enabled() {
+            return Ok(false);
+        }
+        let _guard = self.lock.lock();
+        let folder = wal_path.parent().unwrap_or(Path::new("."));
+        let Some(filename) = wal_path.file_name().and_then(|n| n.to_str()) else {
+            return Ok(false);
+        };
+        let retained_path = folder.join(format!("{}-{}", DEFAULT_RETAINED_FILE_PREFIX, filename));
+        fs::rename(wal_path, &retained_path)
+            .context(format!("failed to retain WAL file: {:?}", wal_path))?;
+        File::options()
+            .write(true)
+            .open(&retained_path)?
+            .set_modified(SystemTime::now())?;
+        Ok(true)
+    }
+
+    /// Retained segments of `folder`, oldest first.
+    pub fn retained(&self, folder: &Path) -> Result<Vec<PathBuf>> {
+        let mut retained: Vec<(u64, PathBuf)> = fs::read_dir(folder)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter_map(|path| Some((Self::seq_start(&path)?, path)))
+            .collect();
+        retained.sort();
+        Ok(retained.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Deletes or archives every retained segment of `folder` past its TTL, then the oldest ones
+    /// until the rest fit in `max_size`. Leaves them alone when retention is disabled, they may
+    /// have been retained under options the WAL was opened with before.
+    pub fn enforce(&self, folder: &Path) -> Result<RetentionReport> {
+        if !self.options.enabled() {
+            return Ok(RetentionReport::default());
+        }
+        let _guard = self.lock.lock();
+        let now = SystemTime::now();
+        let mut segments = Vec::new();
+        for path in self.retained(folder)? {
+            let metadata = fs::metadata(&path)?;
+            segments.push((path, metadata.len(), metadata.modified()?));
+        }
+        let mut total: u64 = segments.iter().map(|(_, size, _)| size).sum();
+        let mut report = RetentionReport::default();
+        for (path, size, released_at) in segments {
+            // a clock that went backwards counts as not expired yet
+            let expired = self.options.ttl.is_some_and(|ttl| {
+                now.duration_since(released_at)
+                    .is_ok_and(|elapsed| elapsed >= ttl)
+            });
+            let oversized = self.options.max_size.is_some_and(|max| total > max);
+            // archiving without limits keeps nothing in retention
+            let unlimited = self.options.ttl.is_none() && self.options.max_size.is_none();
+            if !(expired || oversized || unlimited) {
+                continue;
+            }
+            total -= size;
+            if self.options.archive {
+                report.archived.push(Self::archive(folder, &path)?);
+            } else {
+                fs::remove_file(&path)
+                    .context(format!("failed to delete retained WAL file: {:?}", path))?;
+                report.deleted.push(path);
+            }
+        }
+        Ok(report)
+    }
+
+    fn archive(folder: &Path, path: &Path) -> Result<PathBuf> {
+        let archive = folder.join(DEFAULT_ARCHIVE_FOLDER);
+        fs::create_dir_all(&archive)?;
+        let filename = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix(DEFAULT_RETAINED_FILE_PREFIX))
+            .and_then(|n| n.strip_prefix('-'))
+            .context(format!("invalid retained WAL file name: {:?}", path))?;
+        let archived = archive.join(filename);
+        fs::rename(path, &archived).context(format!("failed to archive WAL file: {:?}", path))?;
+        File::open(&archive)?.sync_all()?;
+        Ok(archived)
+    }
+
+    fn seq_start(path: &Path) -> Option<u64> {
+        let filename = path.file_name()?.to_str()?;
+        let wal_filename = filename
+            .strip_prefix(DEFAULT_RETAINED_FILE_PREFIX)?
+            .strip_prefix('-')?;
+        WriteAheadLog::extract_seq_range_from_path(Path::new(wal_filename))
+            .map(|(seq_start, _)| seq_start)
+    }
+}
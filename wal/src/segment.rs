@@ -0,0 +1,201 @@
+use anyhow::{Result, bail};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::ChecksumType;
+use crate::encryption::{CipherType, SALT_SIZE};
+
+pub const SEGMENT_MAGIC: u32 = 0x5741_4c31; // "WAL1"
+pub const SEGMENT_VERSION: u8 = 6;
+pub const SEGMENT_HEADER_SIZE: usize = 4 + 1 + 4 + 1 + 4 + SALT_SIZE + 1;
+pub const DEFAULT_RECYCLED_FILE_PREFIX: &str = "recycle";
+
+/// Written at offset 0 of every segment. The log number is unique per segment (even when the file
+/// underneath was recycled) and is repeated in every record so stale tail data left over from a
+/// previous use of the file can be told apart from records written by the current one.
+///
+/// Encrypted segments also carry the cipher, the id of the key (resolved through the
+/// `KeyProvider`) and the random salt the segment's own key is derived with. The checksum
+/// algorithm is per segment as well, so changing it only affects segments created afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub version: u8,
+    pub log_number: u32,
+    pub cipher: CipherType,
+    pub key_id: u32,
+    pub salt: [u8; SALT_SIZE],
+    pub checksum: ChecksumType,
+}
+
+impl SegmentHeader {
+    pub fn new(log_number: u32) -> Self {
+        SegmentHeader {
+            version: SEGMENT_VERSION,
+            log_number,
+            cipher: CipherType::None,
+            key_id: 0,
+            salt: [0u8; SALT_SIZE],
+            checksum: ChecksumType::Crc32,
+        }
+    }
+
+    pub fn encrypted(
+        log_number: u32,
+        cipher: CipherType,
+        key_id: u32,
+        salt: [u8; SALT_SIZE],
+    ) -> Self {
+        SegmentHeader {
+            cipher,
+            key_id,
+            salt,
+            ..Self::new(log_number)
+        }
+    }
+
+    pub fn encode(&self) -> [u8; SEGMENT_HEADER_SIZE] {
+        let mut buf = [0u8; SEGMENT_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&SEGMENT_MAGIC.to_be_bytes());
+        buf[4] = self.version;
+        buf[5..9].copy_from_slice(&self.log_number.to_be_bytes());
+        buf[9] = self.cipher as u8;
+        buf[10..14].copy_from_slice(&self.key_id.to_be_bytes());
+        buf[14..14 + SALT_SIZE].copy_from_slice(&self.salt);
+        buf[14 + SALT_SIZE] = self.checksum as u8;
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < SEGMENT_HEADER_SIZE {
+            bail!("segment header truncated: {} bytes", buf.len());
+        }
+        let magic = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        if magic != SEGMENT_MAGIC {
+            bail!("bad segment magic {:#010x}", magic);
+        }
+        let version = buf[4];
+        if version != SEGMENT_VERSION {
+            bail!("unsupported segment version {}", version);
+        }
+        let log_number = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
+        let cipher = CipherType::from_u8(buf[9])?;
+        let key_id = u32::from_be_bytes([buf[10], buf[11], buf[12], buf[13]]);
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(&buf[14..14 + SALT_SIZE]);
+        let checksum = ChecksumType::from_u8(buf[14 + SALT_SIZE])?;
+        Ok(SegmentHeader {
+            version,
+            log_number,
+            cipher,
+            key_id,
+            salt,
+            checksum,
+        })
+    }
+}
+
+/// Reads just the header of the segment at `path`, `None` if the file is too short to hold one.
+pub fn read_header(path: &Path) -> Result<Option<SegmentHeader>> {
+    let mut buf = Vec::with_capacity(SEGMENT_HEADER_SIZE);
+    File::open(path)?
+        .take(SEGMENT_HEADER_SIZE as u64)
+        .read_to_end(&mut buf)?;
+    if buf.len() < SEGMENT_HEADER_SIZE {
+        return Ok(None);
+    }
+    SegmentHeader::decode(&buf).map(Some)
+}
+
+/// Reserves `len` bytes of disk for `file` up front so appends land in already allocated blocks
+/// and an fsync no longer has to persist block allocation and file size changes.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        // filesystem has no fallocate support, a sparse file is the best we can do
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => file.set_len(len),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(file: &File, len: u64) -> std::io::Result<()> {
+    file.set_len(len)
+}
+
+/// Sealed and checkpointed segments parked for reuse, mirroring rocksdb's `recycle_log_file_num`.
+/// Reusing a file keeps its blocks allocated, so writing into it does not touch file metadata.
+pub struct RecyclePool {
+    capacity: usize,
+    files: Mutex<VecDeque<PathBuf>>,
+}
+
+impl RecyclePool {
+    pub fn new(capacity: usize) -> Self {
+        RecyclePool {
+            capacity,
+            files: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Picks up files recycled before a restart. Anything beyond the pool capacity is deleted.
+    /// Returns the highest log number the kept files were written with, new segments have to
+    /// use higher ones so the batches left in a reused file read as stale.
+    pub fn load(&self, folder: &Path) -> Result<u32> {
+        let mut recycled: Vec<PathBuf> = fs::read_dir(folder)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && Self::is_recycled_file(path))
+            .collect();
+        recycled.sort();
+        let mut files = self.files.lock();
+        let mut max_log_number = 0;
+        for path in recycled {
+            // without a header there is no telling which log numbers the file still holds
+            let header = read_header(&path).ok().flatten();
+            match header {
+                Some(header) if files.len() < self.capacity => {
+                    max_log_number = max_log_number.max(header.log_number);
+                    files.push_back(path);
+                }
+                _ => fs::remove_file(&path)?,
+            }
+        }
+        Ok(max_log_number)
+    }
+
+    /// Parks `wal_path` in the pool. Returns false when the pool is full and the caller should
+    /// delete the segment instead.
+    pub fn recycle(&self, folder: &Path, wal_path: &Path) -> Result<bool> {
+        let mut files = self.files.lock();
+        if files.len() >= self.capacity {
+            return Ok(false);
+        }
+        let Some(filename) = wal_path.file_name().and_then(|n| n.to_str()) else {
+            return Ok(false);
+        };
+        let recycled_path = folder.join(format!("{}-{}", DEFAULT_RECYCLED_FILE_PREFIX, filename));
+        fs::rename(wal_path, &recycled_path)?;
+        files.push_back(recycled_path);
+        Ok(true)
+    }
+
+    pub fn take(&self) -> Option<PathBuf> {
+        self.files.lock().pop_front()
+    }
+
+    fn is_recycled_file(path: &Path) -> bool {
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with(DEFAULT_RECYCLED_FILE_PREFIX) && n.ends_with(".log"))
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,124 @@
+use anyhow::{Result, bail};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Hands out sequence numbers and tracks which of them readers may see.
+///
+/// Allocation is a single atomic increment, so numbers are strictly increasing across threads.
+/// A window is published once it is durable, but windows can become durable out of order, so the
+/// visible sequence only moves past a range once everything allocated before it was published as
+/// well. Readers bounded by `last_visible` never see part of a window that is still in flight.
+pub struct SequenceAllocator {
+    // next number to hand out
+    next: AtomicU64,
+    // every number below this one is published
+    visible: AtomicU64,
+    // published ranges, keyed by their start, waiting on an earlier one
+    pending: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl SequenceAllocator {
+    /// Starts allocating at `next_seq_num`, as returned by `WriteAheadLog::recover`. Everything
+    /// recovered is already visible.
+    pub fn new(next_seq_num: u64) -> Self {
+        SequenceAllocator {
+            next: AtomicU64::new(next_seq_num),
+            visible: AtomicU64::new(next_seq_num),
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn allocate(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Allocates `count` consecutive numbers and returns the first and last of them.
+    pub fn allocate_range(&self, count: u64) -> (u64, u64) {
+        assert!(count > 0, "cannot allocate an empty range");
+        let start = self.next.fetch_add(count, Ordering::SeqCst);
+        (start, start + count - 1)
+    }
+
+    pub fn last_allocated(&self) -> Option<u64> {
+        self.next.load(Ordering::SeqCst).checked_sub(1)
+    }
+
+    /// Highest sequence number readers may see, `None` until the first one is published.
+    pub fn last_visible(&self) -> Option<u64> {
+        self.visible.load(Ordering::Acquire).checked_sub(1)
+    }
+
+    /// Marks `[seq_start, seq_end]` as durable. Returns the new last visible sequence number.
+    pub fn publish(&self, seq_start: u64, seq_end: u64) -> Result<Option<u64>> {
+        if seq_start > seq_end {
+            bail!("invalid sequence range [{}, {}]", seq_start, seq_end);
+        }
+        if self.last_allocated().is_none_or(|last| seq_end > last) {
+            bail!(
+                "sequence range [{}, {}] was never allocated",
+                seq_start,
+                seq_end
+            );
+        }
+        let mut pending = self.pending.lock();
+        let mut visible = self.visible.load(Ordering::Acquire);
+        if seq_start < visible || pending.contains_key(&seq_start) {
+            bail!(
+                "sequence range [{}, {}] already published",
+                seq_start,
+                seq_end
+            );
+        }
+        pending.insert(seq_start, seq_end);
+        while let Some(seq_end) = pending.remove(&visible) {
+            visible = seq_end + 1;
+        }
+        self.visible.store(visible, Ordering::Release);
+        Ok(visible.checked_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_concurrent_allocations_are_unique_and_increasing() {
+        let allocator = Arc::new(SequenceAllocator::new(10));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let allocator = allocator.clone();
+                thread::spawn(move || {
+                    let seqs: Vec<_> = (0..1000).map(|_| allocator.allocate()).collect();
+                    assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+                    seqs
+                })
+            })
+            .collect();
+        let seqs: HashSet<_> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+        assert_eq!(seqs.len(), 4000);
+        assert_eq!(seqs.iter().min(), Some(&10));
+        assert_eq!(allocator.last_allocated(), Some(4009));
+    }
+
+    #[test]
+    fn test_visibility_waits_for_earlier_windows() {
+        let allocator = SequenceAllocator::new(5);
+        assert_eq!(allocator.last_visible(), Some(4));
+        let first = allocator.allocate_range(3);
+        let second = allocator.allocate_range(2);
+        assert_eq!((first, second), ((5, 7), (8, 9)));
+
+        assert_eq!(allocator.publish(second.0, second.1).unwrap(), Some(4));
+        assert_eq!(allocator.publish(first.0, first.1).unwrap(), Some(9));
+        assert!(allocator.publish(first.0, first.1).is_err());
+        assert!(allocator.publish(10, 10).is_err());
+    }
+}
@@ -1,23 +1,142 @@
 use anyhow::{Context, Result};
 use bytes::BufMut;
-use std::fs::{self, File};
-use std::hash::Hasher;
-use std::io::{BufReader, BufWriter, Read, Write};
+use parking_lot::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::thread;
+use tokio::sync::OnceCell;
 use transaction::Transaction;
 
-const DEFAULT_WAL_FILE_PREFIX: &str = "wal";
+use crate::checksum::ChecksumType;
+use crate::compression::CompressionType;
+use crate::direct::{self, AlignedBufferPool};
+use crate::encryption::{CipherType, Encryption, KeyProvider, SegmentCipher, TAG_SIZE};
+use crate::error::WalError;
+use crate::manifest::{self, ManifestRecord, ManifestState, SealedSegment, WalManifest};
+use crate::reader::{SegmentEvent, SegmentReader};
+use crate::record::{self, WalRecord};
+use crate::retention::{Retention, RetentionOptions, RetentionReport};
+use crate::segment::{self, RecyclePool, SEGMENT_HEADER_SIZE, SegmentHeader};
+use crate::sequence::SequenceAllocator;
+use crate::writer::{AsyncWalWriter, SegmentFile, WriterBackend};
+
+pub const DEFAULT_WAL_FILE_PREFIX: &str = "wal";
 const DEFAULT_MIN_BATCH_SIZE: u64 = 3000;
+const DEFAULT_PREALLOCATE_SIZE: u64 = 0;
+const DEFAULT_RECYCLE_LOG_FILE_NUM: usize = 0;
+pub const DEFAULT_QUARANTINE_FOLDER: &str = "quarantine";
+pub(crate) const BATCH_HEADER_SIZE: usize = 4 + 1 + 4 + 4;
+// log number, compression and uncompressed length, authenticated alongside encrypted payloads
+pub(crate) const BATCH_AAD_SIZE: usize = 4 + 1 + 4;
+
+#[derive(Clone)]
+pub struct WalOptions {
+    /// bytes reserved with `fallocate` when a segment is created, 0 disables preallocation
+    pub preallocate_size: u64,
+    /// number of sealed, checkpointed segments kept around for reuse instead of being deleted
+    pub recycle_log_file_num: usize,
+    /// codec applied to each batch written by `put_batch`
+    pub compression: CompressionType,
+    /// checksum stamped on each batch of new segments
+    pub checksum: ChecksumType,
+    /// encrypts every batch of new segments when set, also needed to recover encrypted segments
+    pub encryption: Option<Encryption>,
+    /// upper bound on the threads parsing segments in parallel during recovery
+    pub recovery_threads: usize,
+    /// how `put_batch_async` writes and syncs segments
+    pub writer_backend: WriterBackend,
+    /// opens new segments with `O_DIRECT`, writing aligned blocks that bypass the page cache.
+    /// Filesystems that reject it (tmpfs) get buffered writes
+    pub direct_io: bool,
+    /// keeps released segments around for a while, or archives them, instead of recycling or
+    /// deleting them right away
+    pub retention: RetentionOptions,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        WalOptions {
+            preallocate_size: DEFAULT_PREALLOCATE_SIZE,
+            recycle_log_file_num: DEFAULT_RECYCLE_LOG_FILE_NUM,
+            compression: CompressionType::None,
+            checksum: ChecksumType::Crc32,
+            encryption: None,
+            recovery_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            writer_backend: WriterBackend::Auto,
+            direct_io: false,
+            retention: RetentionOptions::default(),
+        }
+    }
+}
 
 pub struct WriteAheadLog {
     initial: bool,
     folder: String,
+    options: WalOptions,
+    next_log_number: AtomicU32,
+    recycled: RecyclePool,
+    retention: Retention,
+    manifest: WalManifest,
+    // started by the first async call, callers sticking to the sync API never spawn a ring
+    io: OnceCell<AsyncWalWriter>,
+    buffers: Arc<AlignedBufferPool>,
+    sequences: Arc<SequenceAllocator>,
 }
 
 pub struct RecoveredWindow {
     seq_beginning: u64,
     seq_end: u64,
     transactions: Vec<Transaction>,
+    // every committed record in log order, `transactions` are the decoded `Put` values
+    records: Vec<WalRecord>,
+}
+
+impl RecoveredWindow {
+    pub fn seq_range(&self) -> (u64, u64) {
+        (self.seq_beginning, self.seq_end)
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// Committed records in log order, deletes and checkpoint markers included.
+    pub fn records(&self) -> &[WalRecord] {
+        &self.records
+    }
+}
+
+// segment header, committed records and the transactions decoded from their `Put` values
+type RecoveredSegment = (Option<SegmentHeader>, Vec<WalRecord>, Vec<Transaction>);
+
+/// What recovering one folder produced, before the windows are logged and handed out.
+pub(crate) struct FolderRecovery {
+    pub wal: WriteAheadLog,
+    pub windows: Vec<RecoveredWindow>,
+    /// ranges of the live segments in sequence order, empty ones included
+    pub seq_ranges: Vec<(u64, u64)>,
+    pub next_seq_num: u64,
+}
+
+/// A byte range of a repaired segment that could not be salvaged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostRange {
+    pub start: u64,
+    pub end: u64,
+    pub reason: String,
+}
+
+/// What `WriteAheadLog::repair` salvaged from a segment and where the original was moved.
+#[derive(Debug)]
+pub struct RepairReport {
+    pub quarantined: PathBuf,
+    pub salvaged_batches: usize,
+    pub salvaged_records: usize,
+    pub lost: Vec<LostRange>,
 }
 
 pub struct WalFile<'a> {
@@ -25,87 +144,399 @@ pub struct WalFile<'a> {
     pub seq_end: Option<u64>,
     pub filename: &'a str,
     pub writer: BufWriter<File>,
+    pub log_number: u32,
+    pub compression: CompressionType,
+    pub checksum: ChecksumType,
+    cipher: Option<SegmentCipher>,
+    offset: u64,
+    // crc32c of everything written so far, recorded in the manifest when the segment is sealed
+    segment_checksum: u32,
+    preallocated: bool,
+    next_batch_id: u64,
+    // opened with O_DIRECT: every write is a whole number of aligned blocks written with pwrite,
+    // bypassing `writer`
+    direct: bool,
+    buffers: Arc<AlignedBufferPool>,
+    // second handle to the file for async writes, opened with the first of them
+    shared: Option<SegmentFile>,
+    // set while a batch is written and left set if that fails or is cancelled: how much of it
+    // reached the file is unknown, and writing at the same offset again would reuse its nonce
+    poisoned: bool,
 }
 
 impl<'a> WalFile<'a> {
-    pub fn new(folder: &'a str, seq_start: u64, seq_end: u64) -> anyhow::Result<Self> {
+    pub fn new(folder: &str, seq_start: u64, seq_end: u64, log_number: u32) -> Result<Self> {
+        Self::open(
+            folder,
+            seq_start,
+            seq_end,
+            None,
+            SegmentHeader::new(log_number),
+            None,
+            Arc::new(AlignedBufferPool::default()),
+            false,
+        )
+    }
+
+    /// Creates the segment file, or takes over `recycled_path` when given. A recycled file is not
+    /// truncated: the new header and records overwrite the old ones in place and whatever is left
+    /// past them carries a stale log number. With `direct_io` the file is opened with `O_DIRECT`
+    /// unless the filesystem rejects it.
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        folder: &str,
+        seq_start: u64,
+        seq_end: u64,
+        recycled_path: Option<&Path>,
+        header: SegmentHeader,
+        cipher: Option<SegmentCipher>,
+        buffers: Arc<AlignedBufferPool>,
+        direct_io: bool,
+    ) -> Result<Self> {
         let filename = format!("wal-{:020}-{:020}.log", seq_start, seq_end);
         let filepath = Path::new(folder).join(&filename);
-        let file = File::create(&filepath)?;
-
-        Ok(WalFile {
+        if let Some(recycled_path) = recycled_path {
+            fs::rename(recycled_path, &filepath)?;
+        }
+        let direct_file = if direct_io {
+            let file = direct::open_direct(&filepath, recycled_path.is_none())?;
+            if file.is_none() {
+                eprintln!(
+                    "Warning: O_DIRECT not supported for {:?}, using buffered writes",
+                    filepath
+                );
+            }
+            file
+        } else {
+            None
+        };
+        let direct = direct_file.is_some();
+        let file = match (direct_file, recycled_path) {
+            (Some(file), _) => file,
+            (None, Some(_)) => OpenOptions::new().write(true).open(&filepath)?,
+            (None, None) => File::create(&filepath)?,
+        };
+        let header_buf = header.encode();
+        let mut wal_file = WalFile {
             seq_start,
             seq_end: Some(seq_end),
             filename: Box::leak(filename.into_boxed_str()), // Convert to &'static str
             writer: BufWriter::new(file),
+            log_number: header.log_number,
+            compression: CompressionType::None,
+            checksum: header.checksum,
+            cipher,
+            offset: 0,
+            segment_checksum: 0,
+            preallocated: recycled_path.is_some(),
+            next_batch_id: 0,
+            direct,
+            buffers,
+            shared: None,
+            poisoned: false,
+        };
+        if direct {
+            wal_file.write_direct(&header_buf)?;
+        } else {
+            wal_file.writer.write_all(&header_buf)?;
+            wal_file.offset = SEGMENT_HEADER_SIZE as u64;
+            wal_file.segment_checksum = crc32c::crc32c(&header_buf);
+        }
+        Ok(wal_file)
+    }
+
+    /// Writes `data` at the current offset, zero padded to the next block boundary so the write
+    /// and the next record both stay aligned. The reader skips the padding.
+    fn write_direct(&mut self, data: &[u8]) -> Result<()> {
+        let mut buf = self.buffers.take(data.len());
+        buf.extend_from_slice(data);
+        buf.pad_to_alignment();
+        let result = self.writer.get_ref().write_all_at(&buf, self.offset);
+        if result.is_ok() {
+            self.offset += buf.len() as u64;
+            self.segment_checksum = crc32c::crc32c_append(self.segment_checksum, &buf);
+        }
+        self.buffers.put(buf);
+        Ok(result?)
+    }
+
+    /// Appends `entries` as `Put` records of one batch.
+    pub fn append_batch(&mut self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+        self.append_payload(&record::encode_puts(entries))
+    }
+
+    pub fn append_records(&mut self, records: &[WalRecord]) -> Result<()> {
+        self.append_payload(&record::encode_records(records))
+    }
+
+    /// Id for the next `BatchBegin`/`BatchCommit` bracket written to this segment.
+    pub fn next_batch_id(&mut self) -> u64 {
+        self.next_batch_id += 1;
+        self.next_batch_id
+    }
+
+    /// Fails once an earlier write did, the segment has to be sealed and a new one opened.
+    fn start_write(&mut self) -> Result<()> {
+        if self.poisoned {
+            return Err(WalError::SegmentPoisoned {
+                filename: self.filename.to_string(),
+            }
+            .into());
+        }
+        self.poisoned = true;
+        Ok(())
+    }
+
+    fn append_payload(&mut self, payload: &[u8]) -> Result<()> {
+        self.start_write()?;
+        let batch_buf = encode_batch(
+            self.log_number,
+            self.compression,
+            self.checksum,
+            self.cipher.as_ref(),
+            self.offset,
+            payload,
+        )?;
+        if self.direct {
+            self.write_direct(&batch_buf)?;
+        } else {
+            self.writer.write_all(&batch_buf)?;
+            self.offset += batch_buf.len() as u64;
+            self.segment_checksum = crc32c::crc32c_append(self.segment_checksum, &batch_buf);
+            self.writer.flush()?;
+        }
+        // a preallocated file already owns its blocks and its size does not change on append,
+        // so syncing the data is enough
+        if self.preallocated {
+            self.writer.get_mut().sync_data()?;
+        } else {
+            self.writer.get_mut().sync_all()?;
+        }
+        self.poisoned = false;
+        Ok(())
+    }
+
+    /// `append_batch` without blocking the executor: the write and fsync go through `io`.
+    pub async fn append_batch_async(
+        &mut self,
+        io: &AsyncWalWriter,
+        entries: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<()> {
+        self.append_payload_async(io, &record::encode_puts(entries))
+            .await
+    }
+
+    pub async fn append_records_async(
+        &mut self,
+        io: &AsyncWalWriter,
+        records: &[WalRecord],
+    ) -> Result<()> {
+        self.append_payload_async(io, &record::encode_records(records))
+            .await
+    }
+
+    async fn append_payload_async(&mut self, io: &AsyncWalWriter, payload: &[u8]) -> Result<()> {
+        self.start_write()?;
+        let batch_buf = encode_batch(
+            self.log_number,
+            self.compression,
+            self.checksum,
+            self.cipher.as_ref(),
+            self.offset,
+            payload,
+        )?;
+        let mut buf = self.buffers.take(batch_buf.len());
+        buf.extend_from_slice(&batch_buf);
+        if self.direct {
+            buf.pad_to_alignment();
+        }
+        let len = buf.len() as u64;
+        let segment_checksum = crc32c::crc32c_append(self.segment_checksum, &buf);
+        // the header may still be sitting in the buffer
+        self.writer.flush()?;
+        let file = match self.shared.take() {
+            Some(file) => file,
+            None => io.open_segment(self.writer.get_ref()).await?,
+        };
+        let file = &*self.shared.insert(file);
+        let buf = io.write_at(file, self.offset, buf).await?;
+        self.buffers.put(buf);
+        io.sync(file, self.preallocated).await?;
+        self.offset += len;
+        self.segment_checksum = segment_checksum;
+        // positional writes leave the cursor alone, keep `append_batch` appending after them
+        self.writer.seek(SeekFrom::Start(self.offset))?;
+        self.poisoned = false;
+        Ok(())
+    }
+}
+
+/// Frames a batch as a single record:
+///
+/// `log_number: u32 | compression: u8 | uncompressed_len: u32 | payload_len: u32 | payload | checksum: u32`
+///
+/// where the payload is the (possibly compressed) run of encoded `WalRecord`s and the checksum,
+/// computed with the segment's algorithm, covers everything before it. On encrypted segments the payload is
+/// sealed with the segment cipher, using the header fields before `payload_len` as associated data and
+/// `offset`, the position of the record in the file, as the nonce.
+pub fn encode_batch(
+    log_number: u32,
+    compression: CompressionType,
+    checksum: ChecksumType,
+    cipher: Option<&SegmentCipher>,
+    offset: u64,
+    records: &[u8],
+) -> Result<Vec<u8>> {
+    let payload = compression.compress(records)?;
+    let mut batch_buf: Vec<u8> = Vec::with_capacity(
+        BATCH_HEADER_SIZE + payload.len() + TAG_SIZE + std::mem::size_of::<u32>(),
+    );
+    batch_buf.put_u32(log_number);
+    batch_buf.put_u8(compression as u8);
+    batch_buf.put_u32(records.len() as u32);
+    let payload = match cipher {
+        Some(cipher) => cipher.encrypt(offset, &batch_buf, &payload)?,
+        None => payload,
+    };
+    batch_buf.put_u32(payload.len() as u32);
+    batch_buf.put_slice(&payload);
+    let checksum = checksum.compute(&batch_buf);
+    batch_buf.put_u32(checksum);
+    Ok(batch_buf)
+}
+
+/// Free path for `filename` in the quarantine folder of `folder`, which is created if needed.
+pub(crate) fn quarantine_path(folder: &Path, filename: &str) -> Result<PathBuf> {
+    let quarantine = folder.join(DEFAULT_QUARANTINE_FOLDER);
+    fs::create_dir_all(&quarantine)?;
+    Ok((0..)
+        .map(|i| match i {
+            0 => quarantine.join(filename),
+            i => quarantine.join(format!("{}.{}", filename, i)),
         })
+        .find(|candidate| !candidate.exists())
+        .expect("unbounded candidates"))
+}
+
+/// Rejects overlapping ranges in `seq_ranges`, sorted by their start, and gaps between them as
+/// well when `contiguous` is set.
+pub(crate) fn check_contiguous(seq_ranges: &[(u64, u64)], contiguous: bool) -> Result<()> {
+    for pair in seq_ranges.windows(2) {
+        let ((prev_start, prev_end), (next_start, next_end)) = (pair[0], pair[1]);
+        if next_start <= prev_end {
+            return Err(WalError::SequenceOverlap {
+                prev_start,
+                prev_end,
+                next_start,
+                next_end,
+            }
+            .into());
+        }
+        if contiguous && next_start > prev_end + 1 {
+            return Err(WalError::SequenceGap {
+                prev_start,
+                prev_end,
+                next_start,
+                next_end,
+            }
+            .into());
+        }
     }
+    Ok(())
 }
 
 impl WriteAheadLog {
-    fn recover_from_file(path: &Path) -> Result<Vec<Transaction>> {
-        let file =
-            File::open(path).with_context(|| format!("Failed to open WAL file: {:?}", path))?;
-        let mut reader = BufReader::new(file);
+    /// Reads the committed records of a segment. Records bracketed by `BatchBegin` and
+    /// `BatchCommit` are only kept once the commit is read, and only if none of the batches in
+    /// between was damaged, so an atomic batch torn by a crash is dropped as a whole.
+    fn recover_from_file(
+        path: &Path,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> Result<RecoveredSegment> {
+        let mut reader = SegmentReader::open(path, key_provider)?;
+        let mut committed = Vec::new();
+        if reader.header().is_none() {
+            eprintln!("Warning: WAL file {:?} has no segment header", path);
+            return Ok((None, committed, Vec::new()));
+        }
+        // id, records so far and whether a batch inside the bracket was lost
+        let mut open: Option<(u64, Vec<WalRecord>, bool)> = None;
+        for event in reader.by_ref() {
+            match event? {
+                SegmentEvent::Batch { records, .. } => {
+                    for record in records {
+                        match record {
+                            WalRecord::BatchBegin { batch_id } => {
+                                if let Some((id, _, _)) =
+                                    open.replace((batch_id, Vec::new(), false))
+                                {
+                                    eprintln!(
+                                        "Warning: Discarding uncommitted batch {} of {:?}",
+                                        id, path
+                                    );
+                                }
+                            }
+                            WalRecord::BatchCommit { batch_id } => match open.take() {
+                                Some((id, records, false)) if id == batch_id => {
+                                    committed.extend(records)
+                                }
+                                Some((id, _, _)) if id == batch_id => eprintln!(
+                                    "Warning: Discarding batch {} of {:?}, part of it is damaged",
+                                    id, path
+                                ),
+                                other => {
+                                    eprintln!(
+                                        "Warning: Commit of batch {} without a begin in {:?}",
+                                        batch_id, path
+                                    );
+                                    open = other;
+                                }
+                            },
+                            record => match &mut open {
+                                Some((_, records, _)) => records.push(record),
+                                None => committed.push(record),
+                            },
+                        }
+                    }
+                }
+                SegmentEvent::Corrupt { offset, reason, .. } => {
+                    eprintln!(
+                        "Warning: Skipping batch at offset {} of {:?}: {}",
+                        offset, path, reason
+                    );
+                    if let Some((_, _, damaged)) = &mut open {
+                        *damaged = true;
+                    }
+                }
+                SegmentEvent::End {
+                    offset,
+                    reason: Some(reason),
+                } => {
+                    eprintln!("Warning: {} at position {} of {:?}", reason, offset, path);
+                }
+                SegmentEvent::End { reason: None, .. } => {}
+                SegmentEvent::Stale { offset, log_number } => {
+                    eprintln!(
+                        "Warning: stale record from log {} at position {} of {:?}",
+                        log_number, offset, path
+                    );
+                }
+            }
+        }
+        if let Some((id, _, _)) = open {
+            eprintln!("Warning: Discarding uncommitted batch {} of {:?}", id, path);
+        }
         let mut transactions = Vec::new();
-        let mut buffer = Vec::new();
-        reader.read_to_end(&mut buffer)?;
-        let mut pos = 0;
-        while pos < buffer.len() {
-            if pos + 2 > buffer.len() {
-                break;
-            }
-            let key_len = u16::from_be_bytes([buffer[pos], buffer[pos + 1]]) as usize;
-            pos += 2;
-            if pos + key_len > buffer.len() {
-                eprintln!("Warning: Incomplete key at position {}", pos);
-                break;
-            }
-            pos += key_len;
-            if pos + 2 > buffer.len() {
-                eprintln!("Warning: Incomplete value length at position {}", pos);
-                break;
-            }
-            let value_len = u16::from_be_bytes([buffer[pos], buffer[pos + 1]]) as usize;
-            pos += 2;
-            if pos + value_len > buffer.len() {
-                eprintln!("Warning: Incomplete value at position {}", pos);
-                break;
-            }
-            let value = buffer[pos..pos + value_len].to_vec();
-            pos += value_len;
-            if pos + 4 > buffer.len() {
-                eprintln!("Warning: Incomplete CRC at position {}", pos);
-                break;
-            }
-            let stored_crc = u32::from_be_bytes([
-                buffer[pos],
-                buffer[pos + 1],
-                buffer[pos + 2],
-                buffer[pos + 3],
-            ]);
-            pos += 4;
-            let data_end = pos - 4;
-            let data_start = data_end - (2 + key_len + 2 + value_len);
-            let mut hasher = crc32fast::Hasher::new();
-            hasher.write(&buffer[data_start..data_end]);
-            let computed_crc = hasher.finalize();
-            if stored_crc != computed_crc {
-                eprintln!(
-                    "Warning: CRC mismatch at position {}. Expected: {}, Got: {}",
-                    pos, computed_crc, stored_crc
-                );
+        for record in &committed {
+            let WalRecord::Put { value, .. } = record else {
                 continue;
-            }
-            match Transaction::from_bytes(&value) {
+            };
+            match Transaction::from_bytes(value) {
                 Ok(transaction) => transactions.push(transaction),
-                Err(e) => {
-                    eprintln!("Warning: Failed to deserialize transaction: {}", e);
-                    continue;
-                }
+                Err(e) => eprintln!("Warning: Failed to deserialize transaction: {}", e),
             }
         }
-        Ok(transactions)
+        Ok((reader.header(), committed, transactions))
     }
 
     fn initial_wal(folder: impl AsRef<Path>) -> anyhow::Result<Option<Vec<PathBuf>>> {
@@ -118,35 +549,19 @@ impl WriteAheadLog {
     }
 
     pub fn recover(folder: impl AsRef<Path>) -> Result<(Self, Option<Vec<RecoveredWindow>>, u64)> {
-        let folder = folder.as_ref();
-        let mut max_seq_end = 0u64;
-        let mut recovered_windows = Vec::new();
-        let wal_files = Self::initial_wal(folder)?;
-        if let Some(ref wal_files) = wal_files {
-            for wal_path in wal_files {
-                if let Some((seq_beginning, seq_end)) = Self::extract_seq_range_from_path(wal_path)
-                {
-                    let transactions = Self::recover_from_file(wal_path)?;
-                    if !transactions.is_empty() {
-                        let window = RecoveredWindow {
-                            seq_beginning,
-                            seq_end,
-                            transactions,
-                        };
-                        recovered_windows.push(window);
-                    }
-                    if seq_end > max_seq_end {
-                        max_seq_end = seq_end;
-                    }
-                }
-            }
-        }
-        let next_seq_num = if max_seq_end == 0 { 0 } else { max_seq_end + 1 };
+        Self::recover_with_options(folder, WalOptions::default())
+    }
 
-        let wal = Self {
-            initial: wal_files.is_some() && !recovered_windows.is_empty(),
-            folder: folder.to_string_lossy().to_string(),
-        };
+    pub fn recover_with_options(
+        folder: impl AsRef<Path>,
+        options: WalOptions,
+    ) -> Result<(Self, Option<Vec<RecoveredWindow>>, u64)> {
+        let FolderRecovery {
+            wal,
+            windows: recovered_windows,
+            next_seq_num,
+            ..
+        } = Self::recover_folder(folder.as_ref(), options, true)?;
 
         let windows_result = if recovered_windows.is_empty() {
             println!("No windows recovered from WAL");
@@ -168,7 +583,220 @@ impl WriteAheadLog {
         Ok((wal, windows_result, next_seq_num))
     }
 
-    fn find_wal_files(folder: &Path) -> Result<Option<Vec<PathBuf>>> {
+    /// Recovers the segments of `folder`. A lane of a multi-lane WAL only holds some of the
+    /// windows, so `contiguous` is off for lanes and the gaps other lanes fill are left to the
+    /// caller to check once every lane is read.
+    pub(crate) fn recover_folder(
+        folder: &Path,
+        options: WalOptions,
+        contiguous: bool,
+    ) -> Result<FolderRecovery> {
+        let mut max_seq_end = 0u64;
+        let mut max_log_number = 0u32;
+        let mut recovered_windows = Vec::new();
+        let mut live_ranges = Vec::new();
+        let wal_files = Self::initial_wal(folder)?;
+        if let Some(ref wal_files) = wal_files {
+            Self::check_seq_ranges(wal_files, contiguous)?;
+        }
+        let manifest_state = WalManifest::load(folder)?.unwrap_or_default();
+        let retention = Retention::new(options.retention);
+        let (wal_files, sealed) = Self::reconcile_manifest(wal_files, &manifest_state, &retention)?;
+        let manifest = WalManifest::rewrite(
+            folder,
+            &sealed,
+            manifest_state.max_released(),
+            manifest_state.max_log_number,
+        )?;
+        if let Some(ref wal_files) = wal_files {
+            let seq_ranges = Self::check_seq_ranges(wal_files, contiguous)?;
+            live_ranges.clone_from(&seq_ranges);
+            let key_provider = options.encryption.as_ref().map(|e| e.key_provider.as_ref());
+            let recovered = Self::recover_files(wal_files, key_provider, options.recovery_threads)?;
+            for ((seq_beginning, seq_end), (header, records, transactions)) in
+                seq_ranges.into_iter().zip(recovered)
+            {
+                if let Some(header) = header {
+                    max_log_number = max_log_number.max(header.log_number);
+                }
+                if !records.is_empty() {
+                    let window = RecoveredWindow {
+                        seq_beginning,
+                        seq_end,
+                        transactions,
+                        records,
+                    };
+                    recovered_windows.push(window);
+                }
+                if seq_end > max_seq_end {
+                    max_seq_end = seq_end;
+                }
+            }
+        }
+        // released segments are gone from the directory but their sequence numbers stay used
+        let max_seq_end = max_seq_end.max(manifest_state.max_seq_end.unwrap_or(0));
+        let next_seq_num = if max_seq_end == 0 { 0 } else { max_seq_end + 1 };
+
+        let recycled = RecyclePool::new(options.recycle_log_file_num);
+        // a recycled file is overwritten in place, whatever it still holds must carry a lower
+        // log number than the segment taking it over
+        let max_log_number = max_log_number
+            .max(manifest_state.max_log_number)
+            .max(recycled.load(folder)?);
+        retention.enforce(folder)?;
+
+        let wal = Self {
+            initial: wal_files.is_some() && !recovered_windows.is_empty(),
+            folder: folder.to_string_lossy().to_string(),
+            options,
+            // log number 0 is reserved, it is what unwritten preallocated space reads as
+            next_log_number: AtomicU32::new(max_log_number + 1),
+            recycled,
+            retention,
+            manifest,
+            io: OnceCell::new(),
+            buffers: Arc::new(AlignedBufferPool::default()),
+            sequences: Arc::new(SequenceAllocator::new(next_seq_num)),
+        };
+
+        Ok(FolderRecovery {
+            wal,
+            windows: recovered_windows,
+            seq_ranges: live_ranges,
+            next_seq_num,
+        })
+    }
+
+    /// Checks the directory against the manifest: every live sealed segment has to be present and
+    /// unchanged, and a segment the manifest does not know about must not overlap a sealed or
+    /// released range. Such a segment was still being written when the process stopped, possibly
+    /// below a later window that was sealed first, and is sealed now since nothing will append to
+    /// it again.
+    /// Returns the segments to recover and the sealed set to rewrite the manifest with.
+    fn reconcile_manifest(
+        wal_files: Option<Vec<PathBuf>>,
+        state: &ManifestState,
+        retention: &Retention,
+    ) -> Result<(Option<Vec<PathBuf>>, Vec<SealedSegment>)> {
+        let mut expected = state.sealed.clone();
+        let mut sealed = Vec::with_capacity(expected.len());
+        let mut live = Vec::new();
+        for wal_path in wal_files.into_iter().flatten() {
+            let Some((seq_start, seq_end)) = Self::extract_seq_range_from_path(&wal_path) else {
+                continue;
+            };
+            if state.released.contains(&(seq_start, seq_end)) {
+                // the release was logged but the process stopped before the file was removed
+                if !retention.retain(&wal_path)? {
+                    fs::remove_file(&wal_path)
+                        .context(format!("failed to delete WAL file: {:?}", wal_path))?;
+                }
+                continue;
+            }
+            match expected.remove(&seq_start) {
+                Some(segment) if segment.seq_end == seq_end => {
+                    let checksum = manifest::segment_checksum(&wal_path, segment.size)?;
+                    if checksum != segment.checksum {
+                        return Err(WalError::SegmentChecksumMismatch {
+                            path: wal_path,
+                            expected: segment.checksum,
+                            actual: checksum,
+                        }
+                        .into());
+                    }
+                    sealed.push(segment);
+                }
+                Some(segment) => {
+                    return Err(WalError::SequenceOverlap {
+                        prev_start: segment.seq_start,
+                        prev_end: segment.seq_end,
+                        next_start: seq_start,
+                        next_end: seq_end,
+                    }
+                    .into());
+                }
+                None => {
+                    let overlaps = |start: u64, end: u64| start <= seq_end && seq_start <= end;
+                    if state
+                        .sealed
+                        .values()
+                        .any(|s| overlaps(s.seq_start, s.seq_end))
+                        || state
+                            .released
+                            .iter()
+                            .any(|&(start, end)| overlaps(start, end))
+                    {
+                        return Err(WalError::SegmentUnknown { path: wal_path }.into());
+                    }
+                    let size = fs::metadata(&wal_path)?.len();
+                    let log_number = segment::read_header(&wal_path)?.map_or(0, |h| h.log_number);
+                    sealed.push(SealedSegment {
+                        seq_start,
+                        seq_end,
+                        log_number,
+                        size,
+                        checksum: manifest::segment_checksum(&wal_path, size)?,
+                    });
+                }
+            }
+            live.push(wal_path);
+        }
+        if let Some(missing) = expected.values().next() {
+            return Err(WalError::SegmentMissing {
+                seq_start: missing.seq_start,
+                seq_end: missing.seq_end,
+            }
+            .into());
+        }
+        let live = if live.is_empty() { None } else { Some(live) };
+        Ok((live, sealed))
+    }
+
+    /// Segments are sorted by their first sequence number, so each one has to pick up exactly
+    /// where the previous one ended. Anything else means a segment went missing or two windows
+    /// claimed the same sequence numbers. Without `contiguous` only overlaps are rejected.
+    fn check_seq_ranges(wal_files: &[PathBuf], contiguous: bool) -> Result<Vec<(u64, u64)>> {
+        let seq_ranges: Vec<(u64, u64)> = wal_files
+            .iter()
+            .filter_map(|path| Self::extract_seq_range_from_path(path))
+            .collect();
+        check_contiguous(&seq_ranges, contiguous)?;
+        Ok(seq_ranges)
+    }
+
+    /// Parses segments on at most `threads` workers. Segments do not depend on each other, so
+    /// the results only have to be put back in `wal_files` order.
+    fn recover_files(
+        wal_files: &[PathBuf],
+        key_provider: Option<&dyn KeyProvider>,
+        threads: usize,
+    ) -> Result<Vec<RecoveredSegment>> {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(wal_files.len()));
+        let threads = threads.clamp(1, wal_files.len().max(1));
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(wal_path) = wal_files.get(i) else {
+                            break;
+                        };
+                        let recovered = Self::recover_from_file(wal_path, key_provider);
+                        results.lock().push((i, recovered));
+                    }
+                });
+            }
+        });
+        let mut results = results.into_inner();
+        results.sort_by_key(|(i, _)| *i);
+        results
+            .into_iter()
+            .map(|(_, recovered)| recovered)
+            .collect()
+    }
+
+    pub fn find_wal_files(folder: &Path) -> Result<Option<Vec<PathBuf>>> {
         let mut wal_files: Vec<PathBuf> = fs::read_dir(folder)?
             .filter_map(|entry| entry.ok())
             .map(|entry| entry.path())
@@ -196,7 +824,15 @@ impl WriteAheadLog {
         Ok(Some(wal_files))
     }
 
-    fn extract_seq_range_from_path(path: &PathBuf) -> Option<(u64, u64)> {
+    /// Log number in the header of the segment at `path`, 0 when it cannot be read.
+    fn log_number_of(path: &Path) -> u32 {
+        segment::read_header(path)
+            .ok()
+            .flatten()
+            .map_or(0, |header| header.log_number)
+    }
+
+    pub fn extract_seq_range_from_path(path: &Path) -> Option<(u64, u64)> {
         let filename = path.file_name()?.to_str()?;
         let without_prefix = filename.strip_prefix("wal-")?;
         let without_suffix = without_prefix.strip_suffix(".log")?;
@@ -206,31 +842,122 @@ impl WriteAheadLog {
         Some((start, end))
     }
 
+    /// Opens the segment for `[seq_start, seq_end]`, reusing a recycled file when one is
+    /// available and otherwise creating (and optionally preallocating) a new one.
+    pub fn new_wal_file(&self, seq_start: u64, seq_end: u64) -> Result<WalFile<'_>> {
+        self.segment_creator(seq_start, seq_end)?()
+    }
+
+    /// `new_wal_file` without blocking the executor: creating, renaming and preallocating the
+    /// file runs on the blocking thread pool.
+    pub async fn new_wal_file_async(&self, seq_start: u64, seq_end: u64) -> Result<WalFile<'_>> {
+        let create = self.segment_creator(seq_start, seq_end)?;
+        self.io().await?.run_blocking(create).await
+    }
+
+    /// Takes the next log number and a recycled file, if any, for the segment of
+    /// `[seq_start, seq_end]` and returns what creates it.
+    fn segment_creator(
+        &self,
+        seq_start: u64,
+        seq_end: u64,
+    ) -> Result<impl FnOnce() -> Result<WalFile<'static>> + Send + 'static> {
+        let log_number = self.next_log_number.fetch_add(1, Ordering::SeqCst);
+        let (mut header, cipher) = match &self.options.encryption {
+            Some(encryption) => {
+                let key_id = encryption.key_provider.current_key_id();
+                let key = encryption.key_provider.key(key_id)?;
+                let salt = SegmentCipher::random_salt();
+                let cipher = SegmentCipher::new(encryption.cipher, &key, salt)?;
+                let header = SegmentHeader::encrypted(log_number, encryption.cipher, key_id, salt);
+                (header, Some(cipher))
+            }
+            None => (SegmentHeader::new(log_number), None),
+        };
+        header.checksum = self.options.checksum;
+        let recycled_path = self.recycled.take();
+        let folder = self.folder.clone();
+        let buffers = self.buffers.clone();
+        let WalOptions {
+            preallocate_size,
+            compression,
+            direct_io,
+            ..
+        } = self.options;
+        Ok(move || {
+            let mut wal_file = WalFile::open(
+                &folder,
+                seq_start,
+                seq_end,
+                recycled_path.as_deref(),
+                header,
+                cipher,
+                buffers,
+                direct_io,
+            )?;
+            if recycled_path.is_none() && preallocate_size > 0 {
+                segment::preallocate(wal_file.writer.get_ref(), preallocate_size)?;
+                wal_file.preallocated = true;
+            }
+            wal_file.compression = compression;
+            Ok(wal_file)
+        })
+    }
+
     pub fn put_batch(&self, file: &mut WalFile, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
-        let mut batch_buf = Vec::new();
-        for (key, value) in entries {
-            let key_len = key.len() as u16;
-            let value_len = value.len() as u16;
-            let mut entry_buf: Vec<u8> = Vec::with_capacity(
-                key.len()
-                    + value.len()
-                    + std::mem::size_of::<u16>() * 2
-                    + std::mem::size_of::<u32>(),
-            );
-            entry_buf.put_u16(key_len);
-            entry_buf.put_slice(key);
-            entry_buf.put_u16(value_len);
-            entry_buf.put_slice(value);
-            let mut hasher = crc32fast::Hasher::new();
-            hasher.write(&entry_buf);
-            let crc = hasher.finalize();
-            entry_buf.put_u32(crc);
-            batch_buf.extend_from_slice(&entry_buf);
-        }
-        file.writer.write_all(&batch_buf)?;
+        file.append_batch(entries)
+    }
+
+    pub fn put_records(&self, file: &mut WalFile, records: &[WalRecord]) -> Result<()> {
+        file.append_records(records)
+    }
+
+    pub async fn put_records_async(
+        &self,
+        file: &mut WalFile<'_>,
+        records: &[WalRecord],
+    ) -> Result<()> {
+        file.append_records_async(self.io().await?, records).await
+    }
+
+    pub async fn put_batch_async(
+        &self,
+        file: &mut WalFile<'_>,
+        entries: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<()> {
+        file.append_batch_async(self.io().await?, entries).await
+    }
+
+    /// Sequence numbers for new transactions, continuing after everything recovered.
+    pub fn sequences(&self) -> Arc<SequenceAllocator> {
+        self.sequences.clone()
+    }
+
+    /// The writer backend `put_batch_async` ended up with, `None` until the first async call.
+    pub fn writer_backend(&self) -> Option<WriterBackend> {
+        self.io.get().map(AsyncWalWriter::backend)
+    }
+
+    async fn io(&self) -> Result<&AsyncWalWriter> {
+        self.io
+            .get_or_try_init(|| async { AsyncWalWriter::new(self.options.writer_backend) })
+            .await
+    }
+
+    /// Marks `file` as complete in the manifest. Its range and checksum are verified on every
+    /// recovery from here on.
+    pub fn seal_wal_file(&self, file: &mut WalFile) -> Result<SealedSegment> {
         file.writer.flush()?;
         file.writer.get_mut().sync_all()?;
-        Ok(())
+        let segment = SealedSegment {
+            seq_start: file.seq_start,
+            seq_end: file.seq_end.unwrap_or(file.seq_start),
+            log_number: file.log_number,
+            size: file.offset,
+            checksum: file.segment_checksum,
+        };
+        self.manifest.append(ManifestRecord::Sealed(segment))?;
+        Ok(segment)
     }
 
     pub fn delete_wal_file_by_seq(&self, seq_start: u64, seq_end: u64) -> Result<bool> {
@@ -239,7 +966,802 @@ impl WriteAheadLog {
         if !wal_path.exists() {
             return Ok(false);
         }
+        self.manifest.append(ManifestRecord::Released {
+            seq_start,
+            seq_end,
+            log_number: Self::log_number_of(&wal_path),
+        })?;
         fs::remove_file(&wal_path).context(format!("failed to delete WAL file: {:?}", wal_path))?;
         Ok(true)
     }
+
+    /// Retires a checkpointed segment. With retention configured it is kept (or archived) per the
+    /// retention options, otherwise it is parked in the recycle pool if there is room and deleted
+    /// if not.
+    pub fn release_wal_file_by_seq(&self, seq_start: u64, seq_end: u64) -> Result<bool> {
+        let filename = format!("wal-{:020}-{:020}.log", seq_start, seq_end);
+        let folder = Path::new(&self.folder);
+        let wal_path = folder.join(&filename);
+        if !wal_path.exists() {
+            return Ok(false);
+        }
+        self.manifest.append(ManifestRecord::Released {
+            seq_start,
+            seq_end,
+            log_number: Self::log_number_of(&wal_path),
+        })?;
+        if self.retention.retain(&wal_path)? {
+            self.retention.enforce(folder)?;
+        } else if !self
+            .recycled
+            .recycle(folder, &wal_path)
+            .context(format!("failed to recycle WAL file: {:?}", wal_path))?
+        {
+            fs::remove_file(&wal_path)
+                .context(format!("failed to delete WAL file: {:?}", wal_path))?;
+        }
+        Ok(true)
+    }
+
+    /// Deletes or archives retained segments past their TTL or beyond the size limit. Runs on
+    /// every release, call it periodically as well when segments may expire in between.
+    pub fn enforce_retention(&self) -> Result<RetentionReport> {
+        self.retention.enforce(Path::new(&self.folder))
+    }
+
+    /// Released segments still kept around, oldest first. They can be read like live ones.
+    pub fn retained_wal_files(&self) -> Result<Vec<PathBuf>> {
+        self.retention.retained(Path::new(&self.folder))
+    }
+
+    /// Salvages every batch of the segment at `path` that still validates into a new segment at
+    /// the same path and keeps the original in the `quarantine` folder next to it. After a
+    /// damaged batch reading resynchronizes at the next offset where a length and checksum pair
+    /// validates, the bytes skipped on the way are reported as lost. If the manifest has the
+    /// segment sealed, its size and checksum are updated. Must not run while the WAL is open.
+    pub fn repair(path: &Path, key_provider: Option<&dyn KeyProvider>) -> Result<RepairReport> {
+        let mut reader = SegmentReader::open(path, key_provider)?;
+        let Some(header) = reader.header() else {
+            anyhow::bail!(
+                "WAL file {:?} has no segment header, nothing to salvage",
+                path
+            );
+        };
+        let written_len = reader.written_len();
+        let mut batches = Vec::new();
+        let mut lost = Vec::new();
+        while let Some(event) = reader.next() {
+            let (offset, reason, clean_end) = match event? {
+                SegmentEvent::Batch {
+                    compression,
+                    records,
+                    ..
+                } => {
+                    batches.push((compression, records));
+                    continue;
+                }
+                SegmentEvent::Corrupt { offset, reason, .. } => (offset, reason, false),
+                SegmentEvent::End { offset, reason } => match reason {
+                    Some(reason) => (offset, reason, false),
+                    None => (offset, "unwritten space".to_string(), true),
+                },
+                SegmentEvent::Stale { offset, log_number } => {
+                    let reason = format!("stale record from log {}", log_number);
+                    (offset, reason, true)
+                }
+            };
+            // the damage may be in the length itself, so it cannot be trusted to find the next
+            // batch
+            match reader.resync(offset + 1) {
+                Some(end) => lost.push(LostRange {
+                    start: offset,
+                    end,
+                    reason,
+                }),
+                None => {
+                    // zeroes and the stale tail of a recycled file are where the log normally
+                    // ends, anything else left over was written and is lost
+                    if !clean_end && written_len > offset {
+                        lost.push(LostRange {
+                            start: offset,
+                            end: written_len,
+                            reason,
+                        });
+                    }
+                    break;
+                }
+            }
+        }
+
+        // salvaged batches move to new offsets, which the original used for other batches, so an
+        // encrypted segment needs a new salt
+        let mut new_header = header;
+        let cipher = match header.cipher {
+            CipherType::None => None,
+            cipher_type => {
+                let key_provider = key_provider.ok_or_else(|| WalError::MissingKeyProvider {
+                    path: path.to_path_buf(),
+                })?;
+                let key = key_provider.key(header.key_id)?;
+                new_header.salt = SegmentCipher::random_salt();
+                Some(SegmentCipher::new(cipher_type, &key, new_header.salt)?)
+            }
+        };
+        let mut buf = new_header.encode().to_vec();
+        let mut salvaged_records = 0;
+        for (compression, records) in &batches {
+            let batch = encode_batch(
+                header.log_number,
+                *compression,
+                header.checksum,
+                cipher.as_ref(),
+                buf.len() as u64,
+                &record::encode_records(records),
+            )?;
+            buf.extend_from_slice(&batch);
+            salvaged_records += records.len();
+        }
+
+        let folder = path.parent().unwrap_or(Path::new("."));
+        let filename = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context(format!("invalid WAL file name: {:?}", path))?;
+        let tmp_path = folder.join(format!("{}.repair", filename));
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&buf)?;
+        tmp.sync_all()?;
+        let quarantine = folder.join(DEFAULT_QUARANTINE_FOLDER);
+        let quarantined = quarantine_path(folder, filename)?;
+        // link first so the original survives a crash before the salvaged copy replaces it
+        fs::hard_link(path, &quarantined)
+            .context(format!("failed to quarantine WAL file: {:?}", path))?;
+        File::open(&quarantine)?.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        File::open(folder)?.sync_all()?;
+
+        if let (Some((seq_start, seq_end)), Some(state)) = (
+            Self::extract_seq_range_from_path(path),
+            WalManifest::load(folder)?,
+        ) {
+            let mut sealed: Vec<SealedSegment> = state.sealed.values().copied().collect();
+            if let Some(segment) = sealed
+                .iter_mut()
+                .find(|s| s.seq_start == seq_start && s.seq_end == seq_end)
+            {
+                segment.size = buf.len() as u64;
+                segment.checksum = crc32c::crc32c(&buf);
+                WalManifest::rewrite(folder, &sealed, state.max_released(), state.max_log_number)?;
+            }
+        }
+
+        Ok(RepairReport {
+            quarantined,
+            salvaged_batches: batches.len(),
+            salvaged_records,
+            lost,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(tx: &Transaction) -> (Vec<u8>, Vec<u8>) {
+        (tx.timestamp.to_be_bytes().to_vec(), tx.to_bytes().unwrap())
+    }
+
+    #[test]
+    fn test_recycled_segment_ignores_stale_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = WalOptions {
+            preallocate_size: 64 * 1024,
+            recycle_log_file_num: 1,
+            ..Default::default()
+        };
+        let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+
+        let old: Vec<_> = (0..3)
+            .map(|i| {
+                entry(&Transaction::new_with_timestamp(
+                    Default::default(),
+                    Default::default(),
+                    i,
+                ))
+            })
+            .collect();
+        let mut file = wal.new_wal_file(0, 2).unwrap();
+        wal.put_batch(&mut file, &old).unwrap();
+        assert!(wal.release_wal_file_by_seq(0, 2).unwrap());
+
+        let new = vec![entry(&Transaction::new_with_timestamp(
+            Default::default(),
+            Default::default(),
+            7,
+        ))];
+        let mut file = wal.new_wal_file(3, 3).unwrap();
+        wal.put_batch(&mut file, &new).unwrap();
+
+        let (wal, windows, next_seq_num) = WriteAheadLog::recover(dir.path()).unwrap();
+        let windows = windows.unwrap();
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].transactions.len(), 1);
+        assert_eq!(windows[0].transactions[0].timestamp, 7);
+        assert_eq!(next_seq_num, 4);
+        let sequences = wal.sequences();
+        assert_eq!(sequences.last_visible(), Some(3));
+        assert_eq!(sequences.allocate(), 4);
+    }
+
+    #[test]
+    fn test_log_numbers_continue_past_recycled_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = WalOptions {
+            preallocate_size: 64 * 1024,
+            recycle_log_file_num: 1,
+            ..Default::default()
+        };
+        let transaction = |timestamp| {
+            entry(&Transaction::new_with_timestamp(
+                Default::default(),
+                Default::default(),
+                timestamp,
+            ))
+        };
+        {
+            let (wal, _, _) =
+                WriteAheadLog::recover_with_options(dir.path(), options.clone()).unwrap();
+            let mut file = wal.new_wal_file(0, 2).unwrap();
+            for timestamp in [100, 101, 102] {
+                wal.put_batch(&mut file, &[transaction(timestamp)]).unwrap();
+            }
+            assert!(wal.release_wal_file_by_seq(0, 2).unwrap());
+        }
+        // only the recycled file is left, the next segment takes it over after a restart
+        assert!(WriteAheadLog::find_wal_files(dir.path()).unwrap().is_none());
+        {
+            let (wal, windows, _) =
+                WriteAheadLog::recover_with_options(dir.path(), options.clone()).unwrap();
+            assert!(windows.is_none());
+            let mut file = wal.new_wal_file(3, 3).unwrap();
+            assert!(file.log_number > 1);
+            wal.put_batch(&mut file, &[transaction(7)]).unwrap();
+        }
+
+        let (_, windows, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+        let windows = windows.unwrap();
+        let timestamps: Vec<_> = windows
+            .iter()
+            .flat_map(|w| w.transactions.iter().map(|tx| tx.timestamp))
+            .collect();
+        assert_eq!(timestamps, vec![7]);
+    }
+
+    #[test]
+    fn test_compressed_batches_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        for (seq, compression) in [CompressionType::Lz4, CompressionType::Zstd]
+            .into_iter()
+            .enumerate()
+        {
+            let options = WalOptions {
+                compression,
+                ..Default::default()
+            };
+            let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+            let batch: Vec<_> = (0..10)
+                .map(|i| {
+                    entry(&Transaction::new_with_timestamp(
+                        Default::default(),
+                        Default::default(),
+                        i,
+                    ))
+                })
+                .collect();
+            let seq = seq as u64 * 10;
+            let mut file = wal.new_wal_file(seq, seq + 9).unwrap();
+            wal.put_batch(&mut file, &batch).unwrap();
+            let on_disk = fs::metadata(dir.path().join(file.filename)).unwrap().len();
+            assert!(on_disk < batch[0].1.len() as u64);
+        }
+
+        let (_, windows, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let windows = windows.unwrap();
+        assert_eq!(windows.len(), 2);
+        assert!(windows.iter().all(|w| w.transactions.len() == 10));
+    }
+
+    struct StaticKeys(u8);
+
+    impl KeyProvider for StaticKeys {
+        fn current_key_id(&self) -> u32 {
+            1
+        }
+
+        fn key(&self, _key_id: u32) -> Result<[u8; crate::encryption::KEY_SIZE]> {
+            Ok([self.0; crate::encryption::KEY_SIZE])
+        }
+    }
+
+    #[test]
+    fn test_encrypted_segment_requires_matching_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = |key: u8| WalOptions {
+            encryption: Some(Encryption {
+                cipher: CipherType::ChaCha20Poly1305,
+                key_provider: std::sync::Arc::new(StaticKeys(key)),
+            }),
+            ..Default::default()
+        };
+        let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options(1)).unwrap();
+        let batch = vec![entry(&Transaction::new_with_timestamp(
+            Default::default(),
+            Default::default(),
+            3,
+        ))];
+        let mut file = wal.new_wal_file(0, 0).unwrap();
+        wal.put_batch(&mut file, &batch).unwrap();
+        wal.put_batch(&mut file, &batch).unwrap();
+
+        let (_, windows, _) = WriteAheadLog::recover_with_options(dir.path(), options(1)).unwrap();
+        assert_eq!(windows.unwrap()[0].transactions.len(), 2);
+
+        let err = WriteAheadLog::recover_with_options(dir.path(), options(2))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WalError>(),
+            Some(WalError::Authentication { .. })
+        ));
+        let err = WriteAheadLog::recover(dir.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WalError>(),
+            Some(WalError::MissingKeyProvider { .. })
+        ));
+    }
+
+    #[test]
+    fn test_segments_get_their_own_keys_and_no_rewrite_after_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = WalOptions {
+            encryption: Some(Encryption {
+                cipher: CipherType::Aes256Gcm,
+                key_provider: std::sync::Arc::new(StaticKeys(1)),
+            }),
+            ..Default::default()
+        };
+        let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options.clone()).unwrap();
+        let batch = vec![entry(&Transaction::new_with_timestamp(
+            Default::default(),
+            Default::default(),
+            3,
+        ))];
+        // the same batch at the same offset of two segments under the same key
+        let mut first = wal.new_wal_file(0, 0).unwrap();
+        wal.put_batch(&mut first, &batch).unwrap();
+        let mut second = wal.new_wal_file(1, 1).unwrap();
+        wal.put_batch(&mut second, &batch).unwrap();
+        let first_bytes = fs::read(dir.path().join(first.filename)).unwrap();
+        let second_bytes = fs::read(dir.path().join(second.filename)).unwrap();
+        let first_header = SegmentHeader::decode(&first_bytes).unwrap();
+        let second_header = SegmentHeader::decode(&second_bytes).unwrap();
+        assert_ne!(first_header.salt, second_header.salt);
+        let payload = SEGMENT_HEADER_SIZE + BATCH_HEADER_SIZE;
+        assert_ne!(first_bytes[payload..], second_bytes[payload..]);
+
+        // a write that fails leaves the segment unusable
+        let path = dir.path().join(second.filename);
+        second.writer = BufWriter::new(File::open(&path).unwrap());
+        assert!(wal.put_batch(&mut second, &batch).is_err());
+        second.writer = BufWriter::new(OpenOptions::new().append(true).open(&path).unwrap());
+        let err = wal.put_batch(&mut second, &batch).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WalError>(),
+            Some(WalError::SegmentPoisoned { .. })
+        ));
+        assert_eq!(fs::read(&path).unwrap(), second_bytes);
+        let mut third = wal.new_wal_file(2, 2).unwrap();
+        wal.put_batch(&mut third, &batch).unwrap();
+
+        let (_, windows, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+        assert_eq!(windows.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_mixed_checksum_segments_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let checksums = [
+            ChecksumType::Crc32,
+            ChecksumType::Crc32c,
+            ChecksumType::Xxh3,
+        ];
+        for (seq, checksum) in checksums.into_iter().enumerate() {
+            let options = WalOptions {
+                checksum,
+                ..Default::default()
+            };
+            let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+            let batch = vec![entry(&Transaction::new_with_timestamp(
+                Default::default(),
+                Default::default(),
+                seq as u64,
+            ))];
+            let mut file = wal.new_wal_file(seq as u64, seq as u64).unwrap();
+            wal.put_batch(&mut file, &batch).unwrap();
+        }
+
+        let (_, windows, next_seq_num) = WriteAheadLog::recover(dir.path()).unwrap();
+        assert_eq!(windows.unwrap().len(), 3);
+        assert_eq!(next_seq_num, 3);
+    }
+
+    #[test]
+    fn test_recover_rejects_gaps_and_overlaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        for (seq_start, seq_end) in [(0, 9), (10, 19), (20, 29)] {
+            let batch = vec![entry(&Transaction::new_with_timestamp(
+                Default::default(),
+                Default::default(),
+                seq_start,
+            ))];
+            let mut file = wal.new_wal_file(seq_start, seq_end).unwrap();
+            wal.put_batch(&mut file, &batch).unwrap();
+            wal.seal_wal_file(&mut file).unwrap();
+        }
+        let (wal, windows, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let windows = windows.unwrap();
+        assert_eq!(
+            windows.iter().map(|w| w.seq_beginning).collect::<Vec<_>>(),
+            vec![0, 10, 20]
+        );
+
+        wal.new_wal_file(25, 35).unwrap();
+        let err = WriteAheadLog::recover(dir.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WalError>(),
+            Some(WalError::SequenceOverlap { .. })
+        ));
+        fs::remove_file(dir.path().join(format!("wal-{:020}-{:020}.log", 25, 35))).unwrap();
+
+        let sealed = |seq_start: u64, seq_end: u64| {
+            dir.path()
+                .join(format!("wal-{:020}-{:020}.log", seq_start, seq_end))
+        };
+        fs::rename(sealed(10, 19), dir.path().join("lost")).unwrap();
+        let err = WriteAheadLog::recover(dir.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WalError>(),
+            Some(WalError::SequenceGap { .. })
+        ));
+        fs::rename(dir.path().join("lost"), sealed(10, 19)).unwrap();
+
+        // the oldest segment going missing leaves no gap, only the manifest notices
+        fs::rename(sealed(0, 9), dir.path().join("lost")).unwrap();
+        let err = WriteAheadLog::recover(dir.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WalError>(),
+            Some(WalError::SegmentMissing {
+                seq_start: 0,
+                seq_end: 9
+            })
+        ));
+        fs::rename(dir.path().join("lost"), sealed(0, 9)).unwrap();
+
+        let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        assert!(wal.release_wal_file_by_seq(0, 9).unwrap());
+        fs::write(sealed(5, 9), b"").unwrap();
+        let err = WriteAheadLog::recover(dir.path()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<WalError>(),
+            Some(WalError::SegmentUnknown { .. })
+        ));
+    }
+
+    #[test]
+    fn test_recover_seals_segments_sealed_out_of_order() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+            let mut earlier = wal.new_wal_file(0, 9).unwrap();
+            let mut later = wal.new_wal_file(10, 19).unwrap();
+            for (file, timestamp) in [(&mut earlier, 0), (&mut later, 10)] {
+                let tx = Transaction::new_with_timestamp(
+                    Default::default(),
+                    Default::default(),
+                    timestamp,
+                );
+                wal.put_batch(file, &[entry(&tx)]).unwrap();
+            }
+            // the later window is sealed, the process stops before the earlier one is
+            wal.seal_wal_file(&mut later).unwrap();
+        }
+        let (_, windows, next_seq_num) = WriteAheadLog::recover(dir.path()).unwrap();
+        let ranges: Vec<_> = windows.unwrap().iter().map(|w| w.seq_range()).collect();
+        assert_eq!(ranges, vec![(0, 9), (10, 19)]);
+        assert_eq!(next_seq_num, 20);
+
+        let state = WalManifest::load(dir.path()).unwrap().unwrap();
+        assert_eq!(
+            state.sealed.keys().copied().collect::<Vec<_>>(),
+            vec![0, 10]
+        );
+        assert!(WriteAheadLog::recover(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn test_repair_salvages_around_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let mut file = wal.new_wal_file(0, 2).unwrap();
+        for i in 0..3 {
+            let tx = Transaction::new_with_timestamp(Default::default(), Default::default(), i);
+            wal.put_batch(&mut file, &[entry(&tx)]).unwrap();
+        }
+        wal.seal_wal_file(&mut file).unwrap();
+        drop(file);
+
+        // damage the payload length of the middle batch so its framing can no longer be trusted
+        let path = dir.path().join(format!("wal-{:020}-{:020}.log", 0, 2));
+        let mut bytes = fs::read(&path).unwrap();
+        let batch_size = |offset: usize| {
+            let payload_len =
+                u32::from_be_bytes(bytes[offset + 9..offset + 13].try_into().unwrap());
+            BATCH_HEADER_SIZE + payload_len as usize + 4
+        };
+        let second = SEGMENT_HEADER_SIZE + batch_size(SEGMENT_HEADER_SIZE);
+        let third = second + batch_size(second);
+        bytes[second + 10] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        drop(wal);
+        assert!(WriteAheadLog::recover(dir.path()).is_err());
+
+        let report = WriteAheadLog::repair(&path, None).unwrap();
+        assert_eq!(report.salvaged_batches, 2);
+        assert_eq!(
+            report
+                .lost
+                .iter()
+                .map(|lost| (lost.start, lost.end))
+                .collect::<Vec<_>>(),
+            vec![(second as u64, third as u64)]
+        );
+        assert_eq!(fs::read(&report.quarantined).unwrap(), bytes);
+
+        let (_, windows, next_seq_num) = WriteAheadLog::recover(dir.path()).unwrap();
+        let timestamps: Vec<_> = windows.unwrap()[0]
+            .transactions
+            .iter()
+            .map(|tx| tx.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![0, 2]);
+        assert_eq!(next_seq_num, 3);
+    }
+
+    #[tokio::test]
+    async fn test_async_writer_backends_recover() {
+        for backend in [WriterBackend::Auto, WriterBackend::ThreadPool] {
+            let dir = tempfile::tempdir().unwrap();
+            let options = WalOptions {
+                writer_backend: backend,
+                ..Default::default()
+            };
+            let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+            let mut file = wal.new_wal_file(0, 1).unwrap();
+            // nothing is started until the first async call
+            assert_eq!(wal.writer_backend(), None);
+            for i in 0..2 {
+                let tx = Transaction::new_with_timestamp(Default::default(), Default::default(), i);
+                wal.put_batch_async(&mut file, &[entry(&tx)]).await.unwrap();
+            }
+            assert!(wal.writer_backend().is_some());
+            wal.seal_wal_file(&mut file).unwrap();
+            drop(file);
+            drop(wal);
+
+            let (_, windows, _) = WriteAheadLog::recover(dir.path()).unwrap();
+            let timestamps: Vec<_> = windows.unwrap()[0]
+                .transactions
+                .iter()
+                .map(|tx| tx.timestamp)
+                .collect();
+            assert_eq!(timestamps, vec![0, 1], "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_segments_share_log_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let mut first = wal.new_wal_file(0, 0).unwrap();
+        let mut second = wal.new_wal_file_async(1, 1).await.unwrap();
+        let third = wal.new_wal_file(2, 2).unwrap();
+        assert!(first.log_number < second.log_number && second.log_number < third.log_number);
+
+        let tx = Transaction::new_with_timestamp(Default::default(), Default::default(), 7);
+        wal.put_batch(&mut first, &[entry(&tx)]).unwrap();
+        wal.put_batch_async(&mut second, &[entry(&tx)])
+            .await
+            .unwrap();
+        drop((first, second, third));
+        drop(wal);
+
+        let (_, windows, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let ranges: Vec<_> = windows.unwrap().iter().map(|w| w.seq_range()).collect();
+        assert_eq!(ranges, vec![(0, 0), (1, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_direct_io_segments_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = WalOptions {
+            direct_io: true,
+            ..Default::default()
+        };
+        let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+        let mut file = wal.new_wal_file(0, 2).unwrap();
+        let txs: Vec<_> = (0..3)
+            .map(|i| Transaction::new_with_timestamp(Default::default(), Default::default(), i))
+            .collect();
+        wal.put_batch(&mut file, &[entry(&txs[0])]).unwrap();
+        wal.put_batch_async(&mut file, &[entry(&txs[1])])
+            .await
+            .unwrap();
+        wal.put_batch(&mut file, &[entry(&txs[2])]).unwrap();
+        let sealed = wal.seal_wal_file(&mut file).unwrap();
+        if file.direct {
+            // the header and every batch are padded out to whole blocks
+            assert_eq!(sealed.size % direct::DIRECT_IO_ALIGNMENT as u64, 0);
+        }
+        drop(file);
+        drop(wal);
+
+        let (_, windows, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let timestamps: Vec<_> = windows.unwrap()[0]
+            .transactions
+            .iter()
+            .map(|tx| tx.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_recovery_discards_uncommitted_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let put = |key: &[u8]| WalRecord::Put {
+            key: key.to_vec(),
+            value: key.to_vec(),
+        };
+        let mut file = wal.new_wal_file(0, 3).unwrap();
+        // an atomic batch spanning two physical batches
+        let committed = file.next_batch_id();
+        wal.put_records(
+            &mut file,
+            &[
+                WalRecord::BatchBegin {
+                    batch_id: committed,
+                },
+                put(b"a"),
+            ],
+        )
+        .unwrap();
+        wal.put_records(
+            &mut file,
+            &[
+                put(b"b"),
+                WalRecord::BatchCommit {
+                    batch_id: committed,
+                },
+            ],
+        )
+        .unwrap();
+        wal.put_records(&mut file, &[WalRecord::Delete { key: b"x".to_vec() }])
+            .unwrap();
+        // the process stops before this one commits
+        let torn = file.next_batch_id();
+        wal.put_records(
+            &mut file,
+            &[WalRecord::BatchBegin { batch_id: torn }, put(b"c")],
+        )
+        .unwrap();
+        drop(file);
+        drop(wal);
+
+        let (_, windows, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let windows = windows.unwrap();
+        assert_eq!(
+            windows[0].records(),
+            &[
+                put(b"a"),
+                put(b"b"),
+                WalRecord::Delete { key: b"x".to_vec() }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retention_keeps_released_segments_until_limits() {
+        let batch = |seq_start: u64| -> Vec<_> {
+            (seq_start..seq_start + 3)
+                .map(|i| {
+                    entry(&Transaction::new_with_timestamp(
+                        Default::default(),
+                        Default::default(),
+                        i,
+                    ))
+                })
+                .collect()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = WalOptions {
+            retention: RetentionOptions {
+                ttl: Some(std::time::Duration::from_secs(3600)),
+                archive: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+        for seq_start in [0, 3] {
+            let mut file = wal.new_wal_file(seq_start, seq_start + 2).unwrap();
+            wal.put_batch(&mut file, &batch(seq_start)).unwrap();
+            assert!(
+                wal.release_wal_file_by_seq(seq_start, seq_start + 2)
+                    .unwrap()
+            );
+        }
+        let retained = wal.retained_wal_files().unwrap();
+        assert_eq!(retained.len(), 2);
+        let (_, windows, next_seq_num) = WriteAheadLog::recover(dir.path()).unwrap();
+        assert!(windows.is_none());
+        assert_eq!(next_seq_num, 6);
+
+        // released two hours ago, past the TTL
+        let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(7200);
+        File::options()
+            .write(true)
+            .open(&retained[0])
+            .unwrap()
+            .set_modified(two_hours_ago)
+            .unwrap();
+        let report = wal.enforce_retention().unwrap();
+        let archived = dir
+            .path()
+            .join(crate::retention::DEFAULT_ARCHIVE_FOLDER)
+            .join(format!("wal-{:020}-{:020}.log", 0, 2));
+        assert_eq!(report.archived, vec![archived.clone()]);
+        assert!(report.deleted.is_empty());
+        assert!(archived.exists());
+        assert_eq!(wal.retained_wal_files().unwrap().len(), 1);
+
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let mut file = wal.new_wal_file(0, 2).unwrap();
+        wal.put_batch(&mut file, &batch(0)).unwrap();
+        let segment_size = file.offset;
+        drop(wal);
+        let options = WalOptions {
+            retention: RetentionOptions {
+                max_size: Some(segment_size),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (wal, _, _) = WriteAheadLog::recover_with_options(dir.path(), options).unwrap();
+        let mut file = wal.new_wal_file(3, 5).unwrap();
+        wal.put_batch(&mut file, &batch(3)).unwrap();
+        assert!(wal.release_wal_file_by_seq(0, 2).unwrap());
+        assert!(wal.release_wal_file_by_seq(3, 5).unwrap());
+        let retained = wal.retained_wal_files().unwrap();
+        assert_eq!(retained.len(), 1);
+        assert!(retained[0].ends_with(format!("retained-wal-{:020}-{:020}.log", 3, 5)));
+        assert!(
+            !dir.path()
+                .join(crate::retention::DEFAULT_ARCHIVE_FOLDER)
+                .exists()
+        );
+    }
 }
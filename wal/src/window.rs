@@ -1,124 +1,253 @@
 use crossbeam_skiplist::SkipMap;
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use transaction::PendingTransaction;
 
-use anyhow::Result;
-use bytes::BufMut;
-use std::hash::Hasher;
-use std::io::Write;
-
-use crate::wal::WalFile;
+use crate::backpressure::WriteController;
+use crate::wal::WriteAheadLog;
 
 const DEFAULT_MIN_BATCH_SIZE: usize = 300;
-const DEFAULT_WAL_FOLDER: &'static str = "/test";
+// what an admitted transaction holds on to until its window is acknowledged
+const PENDING_TX_BYTES: u64 = std::mem::size_of::<PendingTransaction>() as u64;
 
-type PendingTxSkipMap = Arc<SkipMap<u64, PendingTransaction>>;
+pub type PendingTxSkipMap = Arc<SkipMap<u64, PendingTransaction>>;
 
-struct WindowFormation {
+/// Gathers admitted transactions into a window, writes the window to a WAL segment of its own and
+/// acknowledges every transaction in it once written.
+///
+/// The window allocates its sequence numbers from the WAL when it opens and numbers transactions
+/// in the order they are submitted. The whole range is published once the window is durable.
+pub struct WindowFormation {
     max_batch_size: usize,
     seq_beginning: u64,
-    seq_end: Option<u64>,
+    seq_end: u64,
     pending: PendingTxSkipMap,
     closed: AtomicBool,
-    wal_writer: WalWriter,
-}
-
-struct WalWriter {
-    initial: bool,
-    folder: String,
-}
-
-impl WalWriter {
-    pub fn put_batch(&self, file: &mut WalFile, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
-        let mut batch_buf = Vec::new();
-        for (key, value) in entries {
-            let key_len = key.len() as u16;
-            let value_len = value.len() as u16;
-            let mut entry_buf: Vec<u8> = Vec::with_capacity(
-                key.len()
-                    + value.len()
-                    + std::mem::size_of::<u16>() * 2
-                    + std::mem::size_of::<u32>(),
-            );
-            entry_buf.put_u16(key_len);
-            entry_buf.put_slice(key);
-            entry_buf.put_u16(value_len);
-            entry_buf.put_slice(value);
-            let mut hasher = crc32fast::Hasher::new();
-            hasher.write(&entry_buf);
-            let crc = hasher.finalize();
-            entry_buf.put_u32(crc);
-            batch_buf.extend_from_slice(&entry_buf);
-        }
-        file.writer.write_all(&batch_buf)?;
-        file.writer.flush()?;
-        file.writer.get_mut().sync_all()?;
-        Ok(())
-    }
+    controller: Arc<WriteController>,
 }
 
 pub enum WindowState {
+    /// the transaction was added and the window takes more
     Open,
+    /// the transaction was added and filled the window, which is ready to be written
     Closed,
+    /// the window was already full, the transaction is handed back for the next one
+    Full(Box<PendingTransaction>),
 }
 
 impl WindowFormation {
-    fn new(
-        seq_beginning: u64,
+    pub fn new(
+        wal: &WriteAheadLog,
         pending: PendingTxSkipMap, // Use the alias here too
+        controller: Arc<WriteController>,
     ) -> Self {
+        let max_batch_size = DEFAULT_MIN_BATCH_SIZE;
+        let (seq_beginning, seq_end) = wal.sequences().allocate_range(max_batch_size as u64);
+        controller.window_opened();
         Self {
             seq_beginning,
-            seq_end: None,
+            seq_end,
             pending,
-            max_batch_size: DEFAULT_MIN_BATCH_SIZE,
+            max_batch_size,
             closed: AtomicBool::new(false),
-            wal_writer: WalWriter {
-                initial: false,
-                folder: "".to_string(),
-            },
+            controller,
+        }
+    }
+
+    /// Admits `tx` through the write controller before adding it, so producers slow down or
+    /// wait while the WAL, memtables or L0 fall behind. A full window hands `tx` back without
+    /// admitting it.
+    pub async fn submit(&mut self, tx: PendingTransaction) -> WindowState {
+        if self.closed.load(Ordering::SeqCst) {
+            return WindowState::Full(Box::new(tx));
         }
+        self.controller.admit(PENDING_TX_BYTES).await;
+        self.add_transaction(tx)
     }
 
-    fn add_transaction(&mut self, tx: PendingTransaction) -> WindowState {
+    fn add_transaction(&mut self, mut tx: PendingTransaction) -> WindowState {
+        tx.seq_num = self.seq_beginning + self.pending.len() as u64;
+        self.pending.insert(tx.seq_num, tx);
         if self.pending.len() >= self.max_batch_size {
-            self.seq_end = Some(tx.seq_num);
+            self.closed.store(true, Ordering::SeqCst);
             return WindowState::Closed;
         }
-        self.pending.insert(tx.seq_num, tx);
         WindowState::Open
     }
 
     // todo: optimize write and ack_batch
-    async fn write(self) -> anyhow::Result<()> {
+    /// Writes the pending transactions to a segment of their own in `wal`, seals it and
+    /// publishes the window's range once it is durable. The segment is created off the executor and takes its
+    /// log number from the WAL, like any other segment.
+    pub async fn write(&self, wal: &WriteAheadLog) -> anyhow::Result<()> {
         if self.pending.is_empty() {
+            // nothing to make durable, but later windows must not wait on this range
+            wal.sequences().publish(self.seq_beginning, self.seq_end)?;
             return Ok(());
         }
-        let mut wf = WalFile::new(DEFAULT_WAL_FOLDER, 0, 0)?;
+        let mut wf = wal
+            .new_wal_file_async(self.seq_beginning, self.seq_end)
+            .await?;
         let mut batch = vec![];
-        for entry in self.pending.range(0..(self.max_batch_size as u64)) {
-            let key = entry.key().to_le_bytes().to_vec();
-            let values = entry.key().to_le_bytes().to_vec();
-            batch.push((key, values))
+        for entry in self.pending.iter() {
+            let key = entry.key().to_be_bytes().to_vec();
+            let value = entry.value().tx.to_bytes()?;
+            batch.push((key, value))
         }
-        self.wal_writer.put_batch(&mut wf, &batch)?;
+        wal.put_batch_async(&mut wf, &batch).await?;
+        wal.seal_wal_file(&mut wf)?;
+        wal.sequences().publish(self.seq_beginning, self.seq_end)?;
         Ok(())
     }
 
-    async fn ack_batch(self) -> anyhow::Result<()> {
-        let ceiling = self.max_batch_size as u64;
-        let keys_to_remove: Vec<_> = self
-            .pending
-            .range(0..ceiling)
-            .map(|entry| *entry.key())
-            .collect();
-        for seq_num in keys_to_remove {
-            if let Some(pending_tx) = self.pending.remove(&seq_num) {
-                pending_tx.value().ack().await?;
-                drop(pending_tx)
-            }
+    /// Acknowledges every transaction of the written window and hands their admission back to
+    /// the write controller.
+    pub async fn ack_batch(self) -> anyhow::Result<()> {
+        while let Some(pending_tx) = self.pending.pop_front() {
+            pending_tx.value().ack().await?;
+            drop(pending_tx);
+            self.controller.release(PENDING_TX_BYTES);
         }
+        self.controller.window_closed();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backpressure::{BackpressureOptions, StallReason, WriteState};
+    use crate::manifest::WalManifest;
+    use std::time::Duration;
+    use transaction::Transaction;
+
+    #[tokio::test]
+    async fn test_window_is_written_acknowledged_and_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let controller = Arc::new(WriteController::new(BackpressureOptions {
+            soft_pending_bytes: 2 * PENDING_TX_BYTES,
+            hard_pending_bytes: 2 * PENDING_TX_BYTES,
+            ..Default::default()
+        }));
+        let mut window = WindowFormation::new(&wal, Arc::new(SkipMap::new()), controller.clone());
+        let mut acks = Vec::new();
+        for timestamp in 0..2 {
+            let (response_tx, response_rx) = kanal::bounded_async(1);
+            let tx =
+                Transaction::new_with_timestamp(Default::default(), Default::default(), timestamp);
+            let state = window
+                .submit(PendingTransaction::new(tx, response_tx))
+                .await;
+            assert!(matches!(state, WindowState::Open));
+            acks.push(response_rx);
+        }
+        assert_eq!(
+            controller.state(),
+            WriteState::Stopped(StallReason::WalFsync)
+        );
+
+        assert_eq!(wal.sequences().last_visible(), None);
+        window.write(&wal).await.unwrap();
+        let last = DEFAULT_MIN_BATCH_SIZE as u64 - 1;
+        assert_eq!(wal.sequences().last_visible(), Some(last));
+        let state = WalManifest::load(dir.path()).unwrap().unwrap();
+        let sealed: Vec<_> = state
+            .sealed
+            .values()
+            .map(|s| (s.seq_start, s.seq_end))
+            .collect();
+        assert_eq!(sealed, vec![(0, last)]);
+        window.ack_batch().await.unwrap();
+        for ack in acks {
+            ack.recv().await.unwrap();
+        }
+        assert_eq!(controller.state(), WriteState::Normal);
+        drop(wal);
+
+        let (_, windows, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let windows = windows.unwrap();
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].seq_range(), (0, last));
+        let timestamps: Vec<_> = windows[0]
+            .transactions()
+            .iter()
+            .map(|tx| tx.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_windows_become_visible_in_allocation_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, next_seq_num) = WriteAheadLog::recover(dir.path()).unwrap();
+        let controller = Arc::new(WriteController::new(BackpressureOptions::default()));
+        let mut windows: Vec<_> = (0..2)
+            .map(|_| WindowFormation::new(&wal, Arc::new(SkipMap::new()), controller.clone()))
+            .collect();
+        let (response_tx, _response_rx) = kanal::unbounded_async();
+        for window in &mut windows {
+            let tx = Transaction::new_with_timestamp(Default::default(), Default::default(), 1);
+            window
+                .submit(PendingTransaction::new(tx, response_tx.clone()))
+                .await;
+        }
+        let seq_nums: Vec<_> = windows
+            .iter()
+            .map(|w| w.pending.front().unwrap().value().seq_num)
+            .collect();
+        let size = DEFAULT_MIN_BATCH_SIZE as u64;
+        assert_eq!(seq_nums, vec![next_seq_num, next_seq_num + size]);
+
+        // the later window is durable first, readers still wait for the earlier one
+        windows[1].write(&wal).await.unwrap();
+        assert_eq!(wal.sequences().last_visible(), None);
+        windows[0].write(&wal).await.unwrap();
+        assert_eq!(wal.sequences().last_visible(), Some(2 * size - 1));
+    }
+
+    #[tokio::test]
+    async fn test_full_window_keeps_the_closing_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let (wal, _, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        // a single transaction still admitted keeps the controller delayed
+        let controller = Arc::new(WriteController::new(BackpressureOptions {
+            soft_pending_bytes: PENDING_TX_BYTES,
+            slowdown_delay: Duration::ZERO,
+            ..Default::default()
+        }));
+        let mut window = WindowFormation::new(&wal, Arc::new(SkipMap::new()), controller.clone());
+        let (response_tx, response_rx) = kanal::unbounded_async();
+        let pending = |timestamp| {
+            let tx =
+                Transaction::new_with_timestamp(Default::default(), Default::default(), timestamp);
+            PendingTransaction::new(tx, response_tx.clone())
+        };
+        let last = DEFAULT_MIN_BATCH_SIZE as u64 - 1;
+        for seq_num in 0..last {
+            assert!(matches!(
+                window.submit(pending(seq_num)).await,
+                WindowState::Open
+            ));
+        }
+        assert!(matches!(
+            window.submit(pending(last)).await,
+            WindowState::Closed
+        ));
+        let WindowState::Full(rejected) = window.submit(pending(last + 1)).await else {
+            panic!("a full window took another transaction");
+        };
+        assert_eq!(rejected.tx.timestamp, last + 1);
+
+        window.write(&wal).await.unwrap();
+        window.ack_batch().await.unwrap();
+        assert_eq!(response_rx.len(), DEFAULT_MIN_BATCH_SIZE);
+        assert_eq!(controller.state(), WriteState::Normal);
+        drop(wal);
+
+        let (_, windows, _) = WriteAheadLog::recover(dir.path()).unwrap();
+        let windows = windows.unwrap();
+        assert_eq!(windows[0].seq_range(), (0, last));
+        assert_eq!(windows[0].transactions().len(), DEFAULT_MIN_BATCH_SIZE);
+    }
+}
@@ -0,0 +1,300 @@
+use anyhow::{Context, Result, anyhow};
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+#[cfg(target_os = "linux")]
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::oneshot;
+
+use crate::direct::AlignedBuf;
+
+/// Which backend performs segment writes and fsyncs for async callers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriterBackend {
+    /// io_uring when the kernel allows it, the thread pool otherwise
+    #[default]
+    Auto,
+    IoUring,
+    ThreadPool,
+}
+
+/// Writes and syncs segments without blocking the calling executor. With io_uring the requests
+/// are handed to a dedicated thread running a `tokio-uring` runtime, otherwise the blocking
+/// syscalls run on tokio's blocking thread pool.
+pub struct AsyncWalWriter {
+    backend: Backend,
+    next_segment_id: AtomicU64,
+}
+
+enum Backend {
+    #[cfg(target_os = "linux")]
+    IoUring(kanal::AsyncSender<IoRequest>),
+    ThreadPool,
+}
+
+enum IoRequest {
+    /// hands the ring its descriptor of a segment, kept until `Close`
+    Open {
+        id: u64,
+        file: File,
+    },
+    Write {
+        id: u64,
+        offset: u64,
+        buf: AlignedBuf,
+        done: oneshot::Sender<(io::Result<()>, AlignedBuf)>,
+    },
+    Sync {
+        id: u64,
+        data_only: bool,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+    Close {
+        id: u64,
+    },
+}
+
+/// The handle async writes and syncs of one segment go through. It owns a second descriptor of
+/// the segment, duplicated once when the first async write comes in, which may outlive a dropped
+/// future.
+pub struct SegmentFile {
+    inner: SegmentInner,
+}
+
+enum SegmentInner {
+    #[cfg(target_os = "linux")]
+    IoUring {
+        id: u64,
+        sender: kanal::AsyncSender<IoRequest>,
+    },
+    ThreadPool(Arc<File>),
+}
+
+impl Drop for SegmentFile {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let SegmentInner::IoUring { id, sender } = &self.inner {
+            // the channel is unbounded, this never blocks
+            let _ = sender.as_sync().try_send(IoRequest::Close { id: *id });
+        }
+    }
+}
+
+impl AsyncWalWriter {
+    /// Starts the requested backend. `Auto` falls back to the thread pool when io_uring cannot
+    /// be set up (old kernels, seccomp filters in containers), asking for `IoUring` explicitly
+    /// fails instead.
+    pub fn new(backend: WriterBackend) -> Result<Self> {
+        let backend = match backend {
+            WriterBackend::ThreadPool => Backend::ThreadPool,
+            WriterBackend::IoUring => Self::start_io_uring()?,
+            WriterBackend::Auto => Self::start_io_uring().unwrap_or_else(|e| {
+                eprintln!("Warning: io_uring unavailable, using thread pool: {:#}", e);
+                Backend::ThreadPool
+            }),
+        };
+        Ok(AsyncWalWriter {
+            backend,
+            next_segment_id: AtomicU64::new(0),
+        })
+    }
+
+    /// The backend actually in use, never `Auto`.
+    pub fn backend(&self) -> WriterBackend {
+        match self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::IoUring(_) => WriterBackend::IoUring,
+            Backend::ThreadPool => WriterBackend::ThreadPool,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn start_io_uring() -> Result<Backend> {
+        // tokio-uring panics when the ring cannot be created, so probe for support first
+        io_uring::IoUring::new(8).context("failed to set up io_uring")?;
+        let (sender, receiver) = kanal::unbounded_async::<IoRequest>();
+        std::thread::Builder::new()
+            .name("wal-io-uring".to_string())
+            .spawn(move || {
+                tokio_uring::start(async move {
+                    // requests are taken in order, so a segment is open before its first write
+                    // and in-flight requests keep their descriptor alive past `Close`
+                    let mut files = HashMap::new();
+                    while let Ok(request) = receiver.recv().await {
+                        match request {
+                            IoRequest::Open { id, file } => {
+                                files.insert(id, Rc::new(tokio_uring::fs::File::from_std(file)));
+                            }
+                            IoRequest::Close { id } => {
+                                files.remove(&id);
+                            }
+                            IoRequest::Write { id, .. } | IoRequest::Sync { id, .. } => {
+                                let file = files.get(&id).cloned();
+                                tokio_uring::spawn(Self::submit(file, request));
+                            }
+                        }
+                    }
+                })
+            })?;
+        Ok(Backend::IoUring(sender))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn start_io_uring() -> Result<Backend> {
+        Err(anyhow!("io_uring is only available on linux"))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn submit(file: Option<Rc<tokio_uring::fs::File>>, request: IoRequest) {
+        let closed = || io::Error::other("segment is not open on the io_uring thread");
+        match request {
+            IoRequest::Write {
+                offset, buf, done, ..
+            } => {
+                let result = match file {
+                    Some(file) => Self::uring_write_all_at(&file, offset, buf).await,
+                    None => (Err(closed()), buf),
+                };
+                let _ = done.send(result);
+            }
+            IoRequest::Sync {
+                data_only, done, ..
+            } => {
+                let result = match file {
+                    Some(file) if data_only => file.sync_data().await,
+                    Some(file) => file.sync_all().await,
+                    None => Err(closed()),
+                };
+                let _ = done.send(result);
+            }
+            IoRequest::Open { .. } | IoRequest::Close { .. } => {}
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn uring_write_all_at(
+        file: &tokio_uring::fs::File,
+        offset: u64,
+        mut buf: AlignedBuf,
+    ) -> (io::Result<()>, AlignedBuf) {
+        use tokio_uring::buf::IoBuf;
+        let mut written = 0;
+        while written < buf.len() {
+            let (result, slice) = file
+                .write_at(buf.slice(written..), offset + written as u64)
+                .await;
+            buf = slice.into_inner();
+            match result {
+                Ok(0) => return (Err(io::ErrorKind::WriteZero.into()), buf),
+                Ok(n) => written += n,
+                Err(e) => return (Err(e), buf),
+            }
+        }
+        (Ok(()), buf)
+    }
+
+    /// Duplicates the descriptor of `file` for the async writes and syncs of its segment. Done
+    /// once per segment, requests through the handle do not open anything.
+    pub async fn open_segment(&self, file: &File) -> Result<SegmentFile> {
+        let file = file.try_clone()?;
+        let inner = match &self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::IoUring(sender) => {
+                let id = self.next_segment_id.fetch_add(1, Ordering::Relaxed);
+                sender
+                    .send(IoRequest::Open { id, file })
+                    .await
+                    .map_err(|_| anyhow!("io_uring thread stopped"))?;
+                SegmentInner::IoUring {
+                    id,
+                    sender: sender.clone(),
+                }
+            }
+            Backend::ThreadPool => SegmentInner::ThreadPool(Arc::new(file)),
+        };
+        Ok(SegmentFile { inner })
+    }
+
+    /// Writes all of `buf` at `offset` of `file` and hands the buffer back for reuse.
+    pub async fn write_at(
+        &self,
+        file: &SegmentFile,
+        offset: u64,
+        buf: AlignedBuf,
+    ) -> Result<AlignedBuf> {
+        let (result, buf) = match &file.inner {
+            #[cfg(target_os = "linux")]
+            SegmentInner::IoUring { id, sender } => {
+                let (done, result) = oneshot::channel();
+                let request = IoRequest::Write {
+                    id: *id,
+                    offset,
+                    buf,
+                    done,
+                };
+                sender
+                    .send(request)
+                    .await
+                    .map_err(|_| anyhow!("io_uring thread stopped"))?;
+                result
+                    .await
+                    .map_err(|_| anyhow!("io_uring thread dropped the request"))?
+            }
+            SegmentInner::ThreadPool(file) => {
+                let file = file.clone();
+                tokio::task::spawn_blocking(move || (file.write_all_at(&buf, offset), buf)).await?
+            }
+        };
+        result?;
+        Ok(buf)
+    }
+
+    /// Runs `f`, blocking file system work such as creating a segment, on the blocking thread
+    /// pool. io_uring has no say here, the ring only takes writes and syncs.
+    pub async fn run_blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(f).await?
+    }
+
+    /// Flushes `file` to disk, only its data (not metadata such as the size) when `data_only`.
+    pub async fn sync(&self, file: &SegmentFile, data_only: bool) -> Result<()> {
+        match &file.inner {
+            #[cfg(target_os = "linux")]
+            SegmentInner::IoUring { id, sender } => {
+                let (done, result) = oneshot::channel();
+                let request = IoRequest::Sync {
+                    id: *id,
+                    data_only,
+                    done,
+                };
+                sender
+                    .send(request)
+                    .await
+                    .map_err(|_| anyhow!("io_uring thread stopped"))?;
+                result
+                    .await
+                    .map_err(|_| anyhow!("io_uring thread dropped the request"))??;
+                Ok(())
+            }
+            SegmentInner::ThreadPool(file) => {
+                let file = file.clone();
+                tokio::task::spawn_blocking(move || {
+                    if data_only {
+                        file.sync_data()
+                    } else {
+                        file.sync_all()
+                    }
+                })
+                .await??;
+                Ok(())
+            }
+        }
+    }
+}